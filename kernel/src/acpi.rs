@@ -0,0 +1,219 @@
+//! Just enough ACPI to find the system description tables, e.g. the MADT ("APIC") and HPET.
+
+use core::{mem::size_of, ptr, slice};
+
+use spin::Lazy;
+
+use crate::{boot::RSDP_REQUEST, hhdm::Hhdm, types::PhysAddr};
+
+pub mod madt;
+
+/// The root table, validated once on first use.
+static ROOT: Lazy<Result<RootTable, AcpiError>> = Lazy::new(|| unsafe { RootTable::find() });
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    /// The bootloader didn't find an RSDP.
+    NoRsdp,
+    /// The RSDP doesn't start with `"RSD PTR "`.
+    BadRsdpSignature,
+    /// A table's bytes don't sum to zero. Holds the table's signature.
+    BadChecksum([u8; 4]),
+    /// A length is too short to hold the structure's own header, or runs past the HHDM.
+    BadLength,
+}
+
+/// The header every system description table starts with.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// A system description table, read through the HHDM.
+#[derive(Debug, Clone, Copy)]
+pub struct Table {
+    pub phys: PhysAddr,
+    bytes: &'static [u8],
+}
+
+impl Table {
+    /// Maps the table at `phys`, using its header to find its length.
+    ///
+    /// # Safety
+    /// `phys` must be the address of an ACPI table.
+    unsafe fn at(hhdm: &Hhdm, phys: PhysAddr) -> Result<Table, AcpiError> {
+        let header = hhdm
+            .try_to_virtual::<SdtHeader>(phys)
+            .ok_or(AcpiError::BadLength)?;
+        let length = ptr::addr_of!((*header.as_ptr()).length).read_unaligned() as usize;
+
+        let end = phys
+            .checked_add(length as u64)
+            .ok_or(AcpiError::BadLength)?;
+        if length < size_of::<SdtHeader>() || hhdm.try_to_virtual::<u8>(end - 1).is_none() {
+            return Err(AcpiError::BadLength);
+        }
+        Ok(Table {
+            phys,
+            bytes: hhdm.slice(phys, length),
+        })
+    }
+
+    pub fn header(&self) -> SdtHeader {
+        unsafe { self.bytes.as_ptr().cast::<SdtHeader>().read_unaligned() }
+    }
+
+    pub fn signature(&self) -> [u8; 4] {
+        self.header().signature
+    }
+
+    /// The whole table, header included.
+    pub fn bytes(&self) -> &'static [u8] {
+        self.bytes
+    }
+
+    /// The table's contents after the header.
+    pub fn data(&self) -> &'static [u8] {
+        &self.bytes[size_of::<SdtHeader>()..]
+    }
+
+    /// Returns whether the table's bytes sum to zero, as its checksum field is chosen to make
+    /// them.
+    pub fn checksum_ok(&self) -> bool {
+        checksum(self.bytes) == 0
+    }
+}
+
+/// The RSDT or XSDT, whichever the RSDP points to.
+#[derive(Debug)]
+struct RootTable {
+    table: Table,
+    /// 4 for the RSDT, 8 for the XSDT.
+    entry_size: usize,
+}
+
+impl RootTable {
+    unsafe fn find() -> Result<RootTable, AcpiError> {
+        let response = RSDP_REQUEST.get_response().get().ok_or(AcpiError::NoRsdp)?;
+        let rsdp = response.address.as_ptr().ok_or(AcpiError::NoRsdp)?;
+
+        // The first 20 bytes are the ACPI 1.0 RSDP. Revision 2 and later extend it to `length`
+        // bytes, adding the XSDT address and a checksum over the whole structure.
+        let v1 = slice::from_raw_parts(rsdp, 20);
+        if &v1[..8] != b"RSD PTR " {
+            return Err(AcpiError::BadRsdpSignature);
+        }
+        if checksum(v1) != 0 {
+            return Err(AcpiError::BadChecksum(*b"RSD "));
+        }
+
+        let hhdm = Hhdm::with_limine();
+        let revision = v1[15];
+        let (phys, entry_size) = if revision >= 2 {
+            let length = u32::from_le_bytes(rsdp.add(20).cast::<[u8; 4]>().read());
+            if length < 36 {
+                return Err(AcpiError::BadLength);
+            }
+            let v2 = slice::from_raw_parts(rsdp, length as usize);
+            if checksum(v2) != 0 {
+                return Err(AcpiError::BadChecksum(*b"RSD "));
+            }
+            let xsdt = u64::from_le_bytes(rsdp.add(24).cast::<[u8; 8]>().read());
+            (PhysAddr(xsdt), 8)
+        } else {
+            let rsdt = u32::from_le_bytes(rsdp.add(16).cast::<[u8; 4]>().read());
+            (PhysAddr(rsdt.into()), 4)
+        };
+
+        let table = Table::at(&hhdm, phys)?;
+        if !table.checksum_ok() {
+            return Err(AcpiError::BadChecksum(table.signature()));
+        }
+        Ok(RootTable { table, entry_size })
+    }
+
+    fn entries(&self) -> impl Iterator<Item = PhysAddr> + '_ {
+        self.table
+            .data()
+            .chunks_exact(self.entry_size)
+            .map(|entry| {
+                let mut bytes = [0; 8];
+                bytes[..entry.len()].copy_from_slice(entry);
+                PhysAddr(u64::from_le_bytes(bytes))
+            })
+    }
+}
+
+/// Returns every table the root table lists, in order. Their checksums aren't checked.
+pub fn tables() -> Result<impl Iterator<Item = Table>, AcpiError> {
+    let root = ROOT.as_ref().map_err(|err| *err)?;
+    let hhdm = Hhdm::with_limine();
+    Ok(root
+        .entries()
+        .filter_map(move |phys| unsafe { Table::at(&hhdm, phys) }.ok()))
+}
+
+/// Returns the first table with the given signature, e.g. `*b"APIC"` for the MADT.
+pub fn find_table(signature: [u8; 4]) -> Result<Option<Table>, AcpiError> {
+    for table in tables()? {
+        if table.signature() == signature {
+            if !table.checksum_ok() {
+                return Err(AcpiError::BadChecksum(signature));
+            }
+            return Ok(Some(table));
+        }
+    }
+    Ok(None)
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn finds_the_madt() {
+        let madt = find_table(madt::SIGNATURE)
+            .expect("acpi tables are broken")
+            .expect("no madt");
+        assert_eq!(madt.signature(), *b"APIC");
+        assert!(madt.checksum_ok());
+
+        let length = madt.header().length;
+        assert_eq!(length as usize, madt.bytes().len());
+    }
+
+    #[test_case]
+    fn madt_lists_an_enabled_cpu() {
+        let madt = find_table(madt::SIGNATURE).unwrap().unwrap();
+        assert!(madt::local_apics(&madt).any(|apic| apic.enabled));
+    }
+
+    #[test_case]
+    fn madt_lists_the_isa_io_apic() {
+        let madt = find_table(madt::SIGNATURE).unwrap().unwrap();
+        let io_apic = madt::io_apics(&madt)
+            .find(|io_apic| io_apic.gsi_base == 0)
+            .expect("no io apic at gsi 0");
+        assert_ne!(io_apic.address, PhysAddr(0));
+    }
+
+    #[test_case]
+    fn checksum_wraps() {
+        assert_eq!(checksum(&[]), 0);
+        assert_eq!(checksum(&[0x80, 0x80]), 0);
+        assert_eq!(checksum(&[0xff, 0x02]), 1);
+    }
+}
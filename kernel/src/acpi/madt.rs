@@ -0,0 +1,85 @@
+//! The multiple APIC description table, which lists the system's processors and interrupt
+//! controllers.
+
+use super::Table;
+use crate::types::PhysAddr;
+
+/// The MADT's signature.
+pub const SIGNATURE: [u8; 4] = *b"APIC";
+
+const PROCESSOR_LOCAL_APIC: u8 = 0;
+const IO_APIC: u8 = 1;
+const PROCESSOR_LOCAL_X2APIC: u8 = 9;
+
+const FLAG_ENABLED: u32 = 1 << 0;
+
+/// A processor, as the MADT describes its local APIC.
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicEntry {
+    pub apic_id: u32,
+    /// Whether the processor is usable. Disabled processors must not be started.
+    pub enabled: bool,
+}
+
+/// An IO APIC, as the MADT describes it.
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicEntry {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// The global system interrupt the IO APIC's first input is wired to.
+    pub gsi_base: u32,
+}
+
+/// Returns every processor the MADT lists, whether through an xAPIC or an x2APIC entry.
+pub fn local_apics(madt: &Table) -> impl Iterator<Item = LocalApicEntry> {
+    entries(madt).filter_map(|(typ, entry)| match (typ, entry) {
+        (PROCESSOR_LOCAL_APIC, [_, _, _uid, apic_id, flags @ ..]) if flags.len() >= 4 => {
+            Some(LocalApicEntry {
+                apic_id: (*apic_id).into(),
+                enabled: read_u32(flags) & FLAG_ENABLED != 0,
+            })
+        }
+        (PROCESSOR_LOCAL_X2APIC, [_, _, _, _, rest @ ..]) if rest.len() >= 8 => {
+            Some(LocalApicEntry {
+                apic_id: read_u32(rest),
+                enabled: read_u32(&rest[4..]) & FLAG_ENABLED != 0,
+            })
+        }
+        _ => None,
+    })
+}
+
+/// Returns every IO APIC the MADT lists.
+pub fn io_apics(madt: &Table) -> impl Iterator<Item = IoApicEntry> {
+    entries(madt).filter_map(|(typ, entry)| match (typ, entry) {
+        (IO_APIC, [_, _, id, _, rest @ ..]) if rest.len() >= 8 => Some(IoApicEntry {
+            id: *id,
+            address: PhysAddr(read_u32(rest).into()),
+            gsi_base: read_u32(&rest[4..]),
+        }),
+        _ => None,
+    })
+}
+
+/// Returns the type of every entry in the MADT along with its bytes, header included.
+fn entries(madt: &Table) -> impl Iterator<Item = (u8, &'static [u8])> {
+    // The local APIC address and flags come before the variable-length entries.
+    let mut entries = madt.data().get(8..).unwrap_or(&[]);
+
+    core::iter::from_fn(move || {
+        let [typ, len, ..] = *entries else {
+            return None;
+        };
+        let len = usize::from(len);
+        if len < 2 || len > entries.len() {
+            return None;
+        }
+        let (entry, rest) = entries.split_at(len);
+        entries = rest;
+        Some((typ, entry))
+    })
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
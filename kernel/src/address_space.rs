@@ -1,15 +1,29 @@
-use core::{iter::Step, mem, num::NonZeroUsize, ops::Range, ptr::NonNull};
+use alloc::vec::Vec;
+use core::{
+    iter::Step,
+    mem,
+    num::NonZeroUsize,
+    ops::Range,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicPtr, Ordering},
+};
 
 use bytemuck::TransparentWrapper;
 
 use self::x86_64::PageMapper;
 use crate::{
-    address_space::x86_64::{MapError, PageFlags},
+    address_space::x86_64::{MapError, MappingBatch, Mappings, PageFlags, UnmapError},
     boot::KERNEL_ADDRESS_REQUEST,
+    hhdm::Hhdm,
+    interrupts::x86_64::PageFaultErrorCode,
     pmm::{self, PhysAllocError, PhysicalMemoryAllocator},
     spinlock::Spinlock,
-    types::{Frame, Page, VirtAddr},
-    vmm::{self, VirtAllocError, VirtualRegionAllocator},
+    types::{Frame, Page, PhysAddr, VirtAddr},
+    vmm::{
+        self, VirtAllocError, VirtualRegionAllocator, VirtualRegionDeallocator,
+        VirtualRegionReserver,
+    },
+    x86_64::{cr3, percpu},
 };
 
 mod x86_64;
@@ -18,6 +32,13 @@ mod x86_64;
 pub enum AllocError {
     PhysAllocError(PhysAllocError),
     VirtAllocError(VirtAllocError),
+    /// The virtual region handed out for a mapping did not cover every frame being mapped.
+    RegionSizeMismatch {
+        pages: usize,
+        frames: usize,
+    },
+    /// A page of a region asked for by address was already mapped.
+    PageAlreadyMapped,
 }
 
 impl From<PhysAllocError> for AllocError {
@@ -32,21 +53,12 @@ impl From<VirtAllocError> for AllocError {
     }
 }
 
-#[derive(Debug)]
-pub enum MapFramesError {
-    PhysAllocError(PhysAllocError),
-    VirtAllocError(VirtAllocError),
-}
-
-impl From<PhysAllocError> for MapFramesError {
-    fn from(value: PhysAllocError) -> Self {
-        Self::PhysAllocError(value)
-    }
-}
-
-impl From<VirtAllocError> for MapFramesError {
-    fn from(value: VirtAllocError) -> Self {
-        Self::VirtAllocError(value)
+impl From<MapError> for AllocError {
+    fn from(value: MapError) -> Self {
+        match value {
+            MapError::PhysAllocError(err) => Self::PhysAllocError(err),
+            MapError::PageAlreadyMapped => Self::PageAlreadyMapped,
+        }
     }
 }
 
@@ -55,11 +67,34 @@ pub struct MapOptions {
     pub user: bool,
     pub writable: bool,
     pub disable_cache: bool,
+    pub no_execute: bool,
+    /// Map the pages read-only, giving each a private writable copy when it is first written.
+    pub copy_on_write: bool,
+}
+
+/// Who owns the frames behind a mapping that is being torn down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameOwner {
+    /// The frames were mapped with [`AddrSpace::map_frames`] and stay allocated.
+    Caller,
+    /// The frames were allocated by [`AddrSpace::allocate`] and are freed along with the mapping.
+    AddrSpace,
 }
 
 #[derive(Debug)]
 pub struct KernelAddrSpaceNotInitializedError;
 
+/// A page fault that the address space could not resolve.
+#[derive(Debug)]
+pub struct UnhandledPageFault;
+
+/// How much of an address space's virtual range has been allocated, in pages.
+#[derive(Debug, Clone, Copy)]
+pub struct VirtualUsage {
+    pub used: usize,
+    pub remaining: usize,
+}
+
 #[repr(transparent)]
 #[derive(Debug, TransparentWrapper)]
 pub struct AddrSpace {
@@ -71,9 +106,91 @@ impl AddrSpace {
         TransparentWrapper::wrap_ref(&AddrSpaceInner::Kernel)
     }
 
-    pub unsafe fn handle_page_fault(&self, _addr: VirtAddr) {
+    /// Creates an empty user address space.
+    ///
+    /// The kernel half of the page tables is shared with the kernel address space by copying its
+    /// top-level entries, so kernel mappings made later only show up here if they land under an
+    /// l4 entry that already existed.
+    pub fn new_user() -> Result<AddrSpace, AllocError> {
+        let state = with_kernel_address_space(AddrSpaceState::new_user)?;
+        Ok(AddrSpace {
+            inner: AddrSpaceInner::User(Spinlock::new(state)),
+        })
+    }
+
+    /// Switches this CPU to this address space.
+    ///
+    /// The address space must outlive its time as the active one.
+    pub unsafe fn activate(&self) {
+        self.with_state(|state| state.mapper.activate());
+        percpu::current().active_addr_space.set(self);
+    }
+
+    /// Runs `f` with the address space this CPU last activated, or the kernel's if it hasn't
+    /// activated one.
+    ///
+    /// Each CPU has its own CR3, so this is per CPU too. It must not run before the CPU's per-CPU
+    /// block is set up, which is also before the CPU can take a page fault through the kernel's
+    /// IDT.
+    pub fn with_active<F, T>(f: F) -> T
+    where
+        F: FnOnce(&AddrSpace) -> T,
+    {
+        let active = percpu::current().active_addr_space.get();
+        // Safety: `activate` requires the address space to live as long as it is active.
+        match unsafe { active.as_ref() } {
+            Some(addr_space) => f(addr_space),
+            None => f(AddrSpace::kernel()),
+        }
+    }
+
+    /// Tries to resolve a fault at `addr`, which currently only succeeds for writes to
+    /// copy-on-write pages of user address spaces.
+    pub unsafe fn handle_page_fault(
+        &self,
+        addr: VirtAddr,
+        error: PageFaultErrorCode,
+    ) -> Result<(), UnhandledPageFault> {
+        match &self.inner {
+            AddrSpaceInner::Kernel => Err(UnhandledPageFault),
+            AddrSpaceInner::User(state) => {
+                if !error.contains(
+                    PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE,
+                ) {
+                    return Err(UnhandledPageFault);
+                }
+                let page = Page::containing(addr);
+                // A write fault taken while the lock is held, for instance by kernel code writing
+                // to a copy-on-write page inside `with_state`, would spin here forever.
+                let Some(mut state) = state.try_lock() else {
+                    panic!(
+                        "copy-on-write fault at {:?} while its address space is locked",
+                        addr
+                    );
+                };
+                state.copy_on_write(page)
+            }
+        }
+    }
+
+    /// Returns the frame `page` is mapped to, if any.
+    pub fn translate_page(&self, page: Page) -> Option<Frame> {
+        self.with_state(|state| state.mapper.translate_page(page))
+    }
+
+    /// Returns the physical address `addr` is mapped to, or `None` if its page is not mapped.
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        let frame = self.translate_page(Page::containing(addr))?;
+        Some(frame.0 + addr.offset_in_page() as u64)
+    }
+
+    fn with_state<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&mut AddrSpaceState) -> T,
+    {
         match &self.inner {
-            AddrSpaceInner::Kernel => unreachable!("page fault in kernel code"),
+            AddrSpaceInner::Kernel => with_kernel_address_space(f),
+            AddrSpaceInner::User(state) => state.lock(f),
         }
     }
 
@@ -82,48 +199,140 @@ impl AddrSpace {
         frames: Range<Frame>,
         map_options: MapOptions,
     ) -> Result<NonNull<u8>, AllocError> {
-        match &self.inner {
-            AddrSpaceInner::Kernel => KernelAddrSpace.map_frames(frames, map_options),
-        }
+        self.with_state(|state| state.map_frames(frames, map_options))
     }
 
-    pub fn allocate(&self, pages: NonZeroUsize) -> Result<NonNull<u8>, AllocError> {
-        match &self.inner {
-            AddrSpaceInner::Kernel => KernelAddrSpace.allocate(pages),
-        }
+    /// Maps `frames` at `start` rather than wherever the virtual region allocator picks.
+    ///
+    /// The region is reserved in the allocator first, so this fails if it may already be in use.
+    pub fn map_frames_at(
+        &self,
+        start: Page,
+        frames: Range<Frame>,
+        map_options: MapOptions,
+    ) -> Result<(), AllocError> {
+        self.with_state(|state| state.map_frames_at(start, frames, map_options))
     }
-}
 
-#[derive(Debug, Clone)]
-enum AddrSpaceInner {
-    Kernel,
-}
+    /// Maps `size` bytes of device memory starting at `phys`, with caching disabled.
+    ///
+    /// The physical base is rounded down and the end rounded up to page boundaries, so the
+    /// returned pointer is offset into the first mapped page to address `phys` exactly.
+    pub fn map_mmio(
+        &self,
+        phys: PhysAddr,
+        size: usize,
+        map_options: MapOptions,
+    ) -> Result<NonNull<u8>, AllocError> {
+        let offset = phys.offset_in_page();
+        let start = Frame::containing(phys);
+        let pages = (offset as usize + size).div_ceil(4096);
+        let end = Step::forward(start, pages);
 
-#[derive(Debug, Default, Clone, Copy)]
-struct KernelAddrSpace;
+        let map_options = MapOptions {
+            disable_cache: true,
+            no_execute: true,
+            ..map_options
+        };
+        let base = self.map_frames(start..end, map_options)?;
+        Ok(unsafe { NonNull::new_unchecked(base.as_ptr().add(offset as usize)) })
+    }
 
-impl KernelAddrSpace {
-    pub fn allocate(&mut self, pages: NonZeroUsize) -> Result<NonNull<u8>, AllocError> {
-        with_kernel_address_space(|inner| inner.allocate(pages))
+    pub fn allocate(&self, pages: NonZeroUsize) -> Result<NonNull<u8>, AllocError> {
+        self.with_state(|state| state.allocate(pages))
     }
 
-    pub fn map_frames(
+    /// Like [`AddrSpace::allocate`], but leaves the page just below the allocation unmapped so
+    /// that running off its start faults instead of corrupting a neighbour.
+    ///
+    /// The virtual region reserved is one page larger than requested:
+    ///
+    /// ```text
+    /// region.start          returned pointer                 region.end
+    /// |  guard (unmapped)   |  `pages` mapped, writable pages  |
+    /// ```
+    ///
+    /// The guard page's address is reserved so nothing else gets mapped there, but no frame
+    /// backs it. This suits stacks, which grow down towards the guard.
+    pub fn allocate_with_guard(&self, pages: NonZeroUsize) -> Result<NonNull<u8>, AllocError> {
+        self.with_state(|state| state.allocate_with_guard(pages))
+    }
+
+    /// Changes the protection of `pages` pages starting at the page containing `ptr`, keeping
+    /// the frames they map.
+    ///
+    /// Nothing changes unless every page in the range is mapped.
+    pub unsafe fn protect(
         &self,
-        frames: Range<Frame>,
+        ptr: NonNull<u8>,
+        pages: NonZeroUsize,
         map_options: MapOptions,
-    ) -> Result<NonNull<u8>, AllocError> {
-        with_kernel_address_space(|inner| inner.map_frames(frames, map_options))
+    ) -> Result<(), UnmapError> {
+        let start = Page::containing(VirtAddr(ptr.as_ptr() as usize));
+        let region = start..Step::forward(start, pages.get());
+        self.with_state(|state| state.protect(region, map_options))
     }
+
+    /// Unmaps `pages` pages starting at the page containing `ptr`, and returns their virtual
+    /// region to the allocator.
+    ///
+    /// Nothing is unmapped unless every page in the range is mapped. The frames behind the
+    /// mapping are freed only if `owner` is [`FrameOwner::AddrSpace`].
+    pub unsafe fn unmap(
+        &self,
+        ptr: NonNull<u8>,
+        pages: NonZeroUsize,
+        owner: FrameOwner,
+    ) -> Result<(), UnmapError> {
+        let start = Page::containing(VirtAddr(ptr.as_ptr() as usize));
+        let region = start..Step::forward(start, pages.get());
+        self.with_state(|state| state.unmap(region, owner))
+    }
+
+    /// Panics if any page is mapped both writable and executable.
+    pub fn assert_w_xor_x(&self) {
+        self.with_state(|state| state.mapper.assert_w_xor_x());
+    }
+
+    pub fn virtual_usage(&self) -> VirtualUsage {
+        self.with_state(|state| VirtualUsage {
+            used: state.vmm.used(),
+            remaining: state.vmm.remaining(),
+        })
+    }
+
+    /// Logs the mappings overlapping `range` at debug level.
+    pub fn dump_mappings(&self, range: Range<VirtAddr>) {
+        self.with_state(|state| state.mapper.dump_mappings(range));
+    }
+
+    /// Runs `f` over every mapping in the address space, coalescing adjacent pages that map
+    /// contiguous frames with the same flags.
+    ///
+    /// The iterator reads the page tables as it goes, so the address space stays locked until `f`
+    /// returns, keeping the tables from being freed under it.
+    pub fn with_mappings<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(Mappings) -> T,
+    {
+        self.with_state(|state| f(state.mapper.mappings()))
+    }
+}
+
+#[derive(Debug)]
+enum AddrSpaceInner {
+    Kernel,
+    User(Spinlock<AddrSpaceState>),
 }
 
 fn with_kernel_address_space<F, T>(f: F) -> T
 where
-    F: FnOnce(&mut KernelAddrSpaceInner) -> T,
+    F: FnOnce(&mut AddrSpaceState) -> T,
 {
-    KERNEL.lock(|slot| f(slot.get_or_insert_with(KernelAddrSpaceInner::with_limine)))
+    KERNEL.lock(|slot| f(slot.get_or_insert_with(AddrSpaceState::with_limine)))
 }
 
-static KERNEL: Spinlock<Option<KernelAddrSpaceInner>> = Spinlock::new(None);
+static KERNEL: Spinlock<Option<AddrSpaceState>> = Spinlock::new(None);
 
 struct FrameDropGuard<'a, P>
 where
@@ -142,14 +351,17 @@ where
     }
 }
 
+/// The page tables of an address space, with the allocators used to populate them.
 #[derive(Debug)]
-struct KernelAddrSpaceInner {
+struct AddrSpaceState {
     vmm: vmm::BumpAllocator,
     pmm: pmm::Global,
     mapper: PageMapper,
+    /// Whether every mapping is made accessible to user mode.
+    user: bool,
 }
 
-impl KernelAddrSpaceInner {
+impl AddrSpaceState {
     pub fn with_limine() -> Self {
         let kernel_address = KERNEL_ADDRESS_REQUEST.get_response().get().unwrap();
 
@@ -162,31 +374,67 @@ impl KernelAddrSpaceInner {
             vmm: vmm::BumpAllocator::new(Page(start)..Page(end)),
             pmm: pmm::Global,
             mapper: unsafe { PageMapper::active() },
+            user: false,
         }
     }
 
+    /// Creates a user address space covering the lower half, minus the null page.
+    fn new_user(kernel: &mut AddrSpaceState) -> Result<Self, AllocError> {
+        let start = VirtAddr(0x1000);
+        let end = VirtAddr(1 << 47);
+
+        Ok(Self {
+            vmm: vmm::BumpAllocator::new(Page(start)..Page(end)),
+            pmm: pmm::Global,
+            mapper: unsafe { PageMapper::with_kernel_half(&kernel.mapper, &kernel.pmm)? },
+            user: true,
+        })
+    }
+
     pub fn allocate(&mut self, pages: NonZeroUsize) -> Result<NonNull<u8>, AllocError> {
+        let region = self.vmm.allocate_region(pages)?;
+        self.back_region(region.clone()).map_err(|err| {
+            unsafe { self.vmm.deallocate_region(region) };
+            err
+        })
+    }
+
+    pub fn allocate_with_guard(&mut self, pages: NonZeroUsize) -> Result<NonNull<u8>, AllocError> {
+        let with_guard = pages.checked_add(1).expect("guarded allocation too large");
+        let region = self.vmm.allocate_region(with_guard)?;
+        self.back_region(Step::forward(region.start, 1)..region.end)
+            .map_err(|err| {
+                unsafe { self.vmm.deallocate_region(region) };
+                err
+            })
+    }
+
+    /// Backs every page of `pages` with a fresh frame, unmapping them all again on failure.
+    fn back_region(&mut self, pages: Range<Page>) -> Result<NonNull<u8>, AllocError> {
         struct DeallocRegion<'a> {
             region: Range<Page>,
             pmm: &'a pmm::Global,
-            mapper: &'a mut PageMapper,
+            batch: MappingBatch<'a>,
         }
 
         impl<'a> Drop for DeallocRegion<'a> {
             fn drop(&mut self) {
                 for page in self.region.clone() {
                     unsafe {
-                        let frame = self.mapper.unmap_page(page).expect("failed to unmap page");
+                        let frame = self.batch.unmap_page(page).expect("failed to unmap page");
                         self.pmm.deallocate_frame(frame);
                     }
                 }
             }
         }
 
-        let pages = self.vmm.allocate_region(pages)?;
+        let flags = self.page_flags(&MapOptions {
+            writable: true,
+            ..Default::default()
+        });
 
         let mut region_guard = DeallocRegion {
-            mapper: &mut self.mapper,
+            batch: MappingBatch::new(&mut self.mapper),
             pmm: &self.pmm,
             region: pages.start..pages.start,
         };
@@ -199,20 +447,16 @@ impl KernelAddrSpaceInner {
                 pmm: &self.pmm,
             };
 
-            let result = unsafe {
-                region_guard.mapper.map_page(
-                    page,
-                    frame,
-                    PageFlags::PRESENT | PageFlags::WRITABLE,
-                    &self.pmm,
-                )
-            };
+            let result = unsafe { region_guard.batch.map_page(page, frame, flags, &self.pmm) };
 
             match result {
                 Ok(_) => {
                     mem::forget(frame_drop_guard);
                     region_guard.region.end = Step::forward(page, 1);
-                    assert_eq!(region_guard.mapper.translate_page(page), Some(frame));
+                    crate::kassert_eq!(
+                        region_guard.batch.mapper().translate_page(page),
+                        Some(frame)
+                    );
                 }
                 Err(MapError::PhysAllocError(err)) => {
                     return Err(AllocError::PhysAllocError(err));
@@ -225,11 +469,163 @@ impl KernelAddrSpaceInner {
             }
         }
 
-        mem::forget(region_guard);
+        // Keep the mappings, but still let the batch flush the TLB on drop.
+        region_guard.region = pages.start..pages.start;
+        drop(region_guard);
         let ptr = pages.start.0.as_ptr().cast();
         Ok(unsafe { NonNull::new_unchecked(ptr) })
     }
 
+    fn page_flags(&self, map_options: &MapOptions) -> PageFlags {
+        let mut flags = PageFlags::PRESENT;
+        if map_options.writable {
+            flags |= PageFlags::WRITABLE;
+        }
+        if map_options.user || self.user {
+            flags |= PageFlags::USER;
+        }
+        // Kernel mappings are shared by every address space, so they needn't be flushed when
+        // switching between them.
+        if !self.user && !map_options.user {
+            flags |= PageFlags::GLOBAL;
+        }
+        if map_options.disable_cache {
+            flags |= PageFlags::DISABLE_CACHE;
+        }
+        if map_options.no_execute {
+            flags |= PageFlags::NO_EXECUTE;
+        }
+        if map_options.copy_on_write {
+            flags = (flags - PageFlags::WRITABLE) | PageFlags::COW;
+        }
+        flags
+    }
+
+    unsafe fn protect(
+        &mut self,
+        region: Range<Page>,
+        map_options: MapOptions,
+    ) -> Result<(), UnmapError> {
+        if region
+            .clone()
+            .any(|page| self.mapper.translate_page(page).is_none())
+        {
+            return Err(UnmapError::PageNotMapped);
+        }
+
+        let flags = self.page_flags(&map_options);
+        let mut batch = MappingBatch::new(&mut self.mapper);
+        for page in region {
+            batch.protect(page, flags)?;
+        }
+        Ok(())
+    }
+
+    unsafe fn unmap(&mut self, region: Range<Page>, owner: FrameOwner) -> Result<(), UnmapError> {
+        if region
+            .clone()
+            .any(|page| self.mapper.translate_page(page).is_none())
+        {
+            return Err(UnmapError::PageNotMapped);
+        }
+
+        let mut batch = MappingBatch::new(&mut self.mapper);
+        for page in region.clone() {
+            let frame = batch.unmap_page_reclaim(page, &self.pmm)?;
+            if owner == FrameOwner::AddrSpace {
+                self.pmm.deallocate_frame(frame);
+            }
+        }
+        drop(batch);
+
+        self.vmm.deallocate_region(region);
+        Ok(())
+    }
+
+    /// Gives a copy-on-write page its own frame holding a copy of the shared one, and makes it
+    /// writable.
+    ///
+    /// The shared frame is left mapped wherever else it is; without reference counts there is no
+    /// telling whether this was its last user, so it is never freed.
+    unsafe fn copy_on_write(&mut self, page: Page) -> Result<(), UnhandledPageFault> {
+        let flags = self.mapper.page_flags(page).ok_or(UnhandledPageFault)?;
+        if !flags.contains(PageFlags::COW) {
+            return Err(UnhandledPageFault);
+        }
+        let shared = self.mapper.translate_page(page).ok_or(UnhandledPageFault)?;
+
+        let (frame, copy) = self
+            .pmm
+            .allocate_frame_mapped()
+            .map_err(|_| UnhandledPageFault)?;
+        let original = Hhdm::with_limine().slice::<u8>(shared.0, 4096);
+        ptr::copy_nonoverlapping(original.as_ptr(), copy.as_ptr(), original.len());
+
+        let flags = (flags - PageFlags::COW) | PageFlags::WRITABLE;
+        self.mapper
+            .unmap_page(page)
+            .expect("copy-on-write page vanished");
+        // The table the old entry lived in is still there, so this can't need a new one.
+        self.mapper
+            .map_page(page, frame, flags, &self.pmm)
+            .expect("failed to remap copy-on-write page");
+        Ok(())
+    }
+
+    fn map_frames_at(
+        &mut self,
+        start: Page,
+        frames: Range<Frame>,
+        map_options: MapOptions,
+    ) -> Result<(), AllocError> {
+        let pmm = self.pmm;
+        self.map_frames_at_with(start, frames, map_options, &pmm)
+    }
+
+    /// Like [`map_frames_at`](Self::map_frames_at), but allocates page tables from `pmm`.
+    fn map_frames_at_with(
+        &mut self,
+        start: Page,
+        frames: Range<Frame>,
+        map_options: MapOptions,
+        pmm: &impl PhysicalMemoryAllocator,
+    ) -> Result<(), AllocError> {
+        let n = Step::steps_between(&frames.start, &frames.end)
+            .expect("invalid physical memory region");
+        let end = Step::forward_checked(start, n).ok_or(VirtAllocError::RegionUnavailable)?;
+        let pages = start..end;
+
+        self.vmm.reserve_region(pages.clone())?;
+
+        let flags = self.page_flags(&map_options);
+        self.map_region(pages.clone(), frames, flags, pmm)
+            .map_err(|err| {
+                unsafe { self.vmm.deallocate_region(pages) };
+                err.into()
+            })
+    }
+
+    /// Maps each page of `pages` to the corresponding frame of `frames`, allocating page tables
+    /// from `pmm`. If any page fails to map, the ones mapped before it are unmapped again.
+    fn map_region(
+        &mut self,
+        pages: Range<Page>,
+        frames: Range<Frame>,
+        flags: PageFlags,
+        pmm: &impl PhysicalMemoryAllocator,
+    ) -> Result<(), MapError> {
+        let mut batch = MappingBatch::new(&mut self.mapper);
+        for (page, frame) in pages.clone().zip(frames) {
+            if let Err(err) = unsafe { batch.map_page(page, frame, flags, pmm) } {
+                for mapped in pages.start..page {
+                    unsafe { batch.unmap_page(mapped) }.expect("failed to unmap page");
+                }
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
     pub fn map_frames(
         &mut self,
         frames: Range<Frame>,
@@ -242,24 +638,500 @@ impl KernelAddrSpaceInner {
         };
         let pages = self.vmm.allocate_region(pages)?;
 
-        let mut flags = PageFlags::PRESENT;
-        if map_options.writable {
-            flags |= PageFlags::WRITABLE;
+        // Zipping below would silently map only a prefix of `frames` if these ever disagreed.
+        let page_count = Step::steps_between(&pages.start, &pages.end);
+        if page_count != Some(n) {
+            unsafe { self.vmm.deallocate_region(pages) };
+            return Err(AllocError::RegionSizeMismatch {
+                pages: page_count.unwrap_or(0),
+                frames: n,
+            });
         }
-        if map_options.user {
-            flags |= PageFlags::USER;
+
+        // The frames belong to the caller, so on failure only the pages and the region are
+        // given back.
+        let flags = self.page_flags(&map_options);
+        let pmm = self.pmm;
+        if let Err(err) = self.map_region(pages.clone(), frames, flags, &pmm) {
+            unsafe { self.vmm.deallocate_region(pages) };
+            return match err {
+                MapError::PageAlreadyMapped => panic!("page already mapped"),
+                MapError::PhysAllocError(err) => Err(err.into()),
+            };
         }
-        if map_options.disable_cache {
-            flags |= PageFlags::DISABLE_CACHE;
+
+        Ok(unsafe { NonNull::new_unchecked(pages.start.0.as_ptr().cast()) })
+    }
+}
+
+/// Tears down a user address space's half of the page tables.
+///
+/// The frames behind its mappings are left alone: nothing records which of them the address
+/// space allocated itself, so freeing them is up to whoever mapped them.
+impl Drop for AddrSpaceState {
+    fn drop(&mut self) {
+        // The kernel's tables are never dropped, and are shared with every user address space.
+        if !self.user {
+            return;
         }
-        for (page, frame) in pages.clone().zip(frames) {
-            match unsafe { self.mapper.map_page(page, frame, flags, &self.pmm) } {
-                Ok(_) => {}
-                Err(MapError::PageAlreadyMapped) => panic!("page already mapped"),
-                Err(MapError::PhysAllocError(err)) => return Err(err.into()),
+        debug_assert_ne!(
+            cr3::read(),
+            self.mapper.l4_frame(),
+            "dropping the active address space"
+        );
+
+        let pages: Vec<Page> = self
+            .mapper
+            .mappings()
+            .filter(|(pages, _, _)| !pages.start.0.is_higher_half())
+            .flat_map(|(pages, _, _)| pages)
+            .collect();
+        let mut batch = MappingBatch::new(&mut self.mapper);
+        for page in pages {
+            unsafe { batch.unmap_page_reclaim(page, &self.pmm) }
+                .expect("failed to unmap user page");
+        }
+        drop(batch);
+        unsafe { self.mapper.free_l4(&self.pmm) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+
+    use super::*;
+
+    /// A user address space's lower half starts out empty, and its frames are never touched
+    /// since it is never activated, so any frame numbers will do.
+    fn lower_half_mappings(space: &AddrSpace) -> Vec<(Range<Page>, Frame, PageFlags)> {
+        space.with_mappings(|mappings| {
+            mappings
+                .filter(|(pages, _, _)| !pages.start.0.is_higher_half())
+                .collect()
+        })
+    }
+
+    fn frames(start: u64, count: u64) -> Range<Frame> {
+        Frame(PhysAddr(start))..Frame(PhysAddr(start + count * 4096))
+    }
+
+    #[test_case]
+    fn mappings_coalesce_contiguous_runs() {
+        let space = AddrSpace::new_user().unwrap();
+        let writable = || MapOptions {
+            writable: true,
+            ..Default::default()
+        };
+
+        let page = |addr| Page(VirtAddr(addr));
+        space
+            .map_frames_at(page(0x40_0000), frames(0x100_0000, 3), writable())
+            .unwrap();
+        // Continues both the pages and the frames of the first region with the same flags.
+        space
+            .map_frames_at(page(0x40_3000), frames(0x100_3000, 2), writable())
+            .unwrap();
+        space
+            .map_frames_at(
+                page(0x50_0000),
+                frames(0x200_0000, 1),
+                MapOptions::default(),
+            )
+            .unwrap();
+
+        let mappings = lower_half_mappings(&space);
+        assert_eq!(mappings.len(), 2, "{:x?}", mappings);
+
+        let (pages, frame, flags) = &mappings[0];
+        assert_eq!(*pages, page(0x40_0000)..page(0x40_5000));
+        assert_eq!(*frame, Frame(PhysAddr(0x100_0000)));
+        assert!(flags.contains(PageFlags::WRITABLE | PageFlags::USER));
+
+        let (pages, frame, flags) = &mappings[1];
+        assert_eq!(*pages, page(0x50_0000)..page(0x50_1000));
+        assert_eq!(*frame, Frame(PhysAddr(0x200_0000)));
+        assert!(!flags.contains(PageFlags::WRITABLE));
+    }
+
+    /// Hands out frames from the global allocator until `left` runs out, then fails.
+    struct FailAfter {
+        left: Cell<usize>,
+    }
+
+    unsafe impl PhysicalMemoryAllocator for FailAfter {
+        fn allocate_frame(&self) -> Result<Frame, PhysAllocError> {
+            let left = self.left.get().checked_sub(1).ok_or(PhysAllocError)?;
+            self.left.set(left);
+            pmm::Global.allocate_frame()
+        }
+
+        unsafe fn deallocate_frame(&self, frame: Frame) {
+            pmm::Global.deallocate_frame(frame);
+        }
+    }
+
+    #[test_case]
+    fn map_region_rolls_back_on_failure() {
+        let space = AddrSpace::new_user().unwrap();
+        let page = |addr| Page(VirtAddr(addr));
+        // The first page needs an l3, an l2 and an l1 table, and the third page crosses into the
+        // next l1, so the allocator runs dry partway through.
+        let start = page(0x7f_e000);
+        let pages = start..page(0x80_2000);
+        let failing = FailAfter { left: Cell::new(3) };
+
+        space.with_state(|state| {
+            let result = state.map_frames_at_with(
+                start,
+                frames(0x100_0000, 4),
+                MapOptions::default(),
+                &failing,
+            );
+            assert!(matches!(result, Err(AllocError::PhysAllocError(_))));
+            assert_eq!(failing.left.get(), 0);
+            for page in pages.clone() {
+                assert_eq!(state.mapper.translate_page(page), None);
+            }
+
+            // The region was handed back, so the same range can be taken again.
+            state
+                .map_frames_at(start, frames(0x100_0000, 4), MapOptions::default())
+                .unwrap();
+            for (page, frame) in pages.clone().zip(frames(0x100_0000, 4)) {
+                assert_eq!(state.mapper.translate_page(page), Some(frame));
             }
+        });
+    }
+
+    #[test_case]
+    fn map_mmio_points_at_the_requested_byte() {
+        let two = NonZeroUsize::new(2).unwrap();
+        let frames = pmm::Global.allocate_frames(two).unwrap();
+        let phys = frames.start.0 + 0x123;
+        let hhdm = Hhdm::with_limine();
+        unsafe { hhdm.to_virtual::<u8>(phys).as_ptr().write_volatile(0x5a) };
+
+        // Starts partway into the first frame and ends partway into the second.
+        let space = AddrSpace::kernel();
+        let ptr = space.map_mmio(phys, 0xf00, MapOptions::default()).unwrap();
+        assert_eq!(ptr.as_ptr() as usize % 4096, 0x123);
+        assert_eq!(unsafe { ptr.as_ptr().read_volatile() }, 0x5a);
+
+        let addr = VirtAddr(ptr.as_ptr() as usize);
+        assert_eq!(space.translate(addr), Some(phys));
+        assert_eq!(space.translate(addr + 0xeff), Some(phys + 0xeff));
+
+        unsafe {
+            space.unmap(ptr, two, FrameOwner::Caller).unwrap();
+            pmm::Global.deallocate_frames(frames);
         }
+    }
 
-        Ok(unsafe { NonNull::new_unchecked(pages.start.0.as_ptr().cast()) })
+    #[test_case]
+    fn w_xor_x_flags_a_planted_mapping() {
+        let space = AddrSpace::new_user().unwrap();
+        let page = |addr| Page(VirtAddr(addr));
+        space
+            .map_frames_at(
+                page(0x70_0000),
+                frames(0x100_0000, 1),
+                MapOptions {
+                    writable: true,
+                    no_execute: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        space
+            .map_frames_at(
+                page(0x70_1000),
+                frames(0x100_1000, 1),
+                MapOptions::default(),
+            )
+            .unwrap();
+        space
+            .map_frames_at(
+                page(0x70_4000),
+                frames(0x200_0000, 2),
+                MapOptions {
+                    writable: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let violations: Vec<_> = space.with_state(|state| {
+            state
+                .mapper
+                .w_xor_x_violations()
+                .filter(|(pages, _, _)| !pages.start.0.is_higher_half())
+                .collect()
+        });
+        assert_eq!(violations.len(), 1, "{:x?}", violations);
+        let (pages, frame, _) = &violations[0];
+        assert_eq!(*pages, page(0x70_4000)..page(0x70_6000));
+        assert_eq!(*frame, Frame(PhysAddr(0x200_0000)));
+    }
+
+    /// Runs once the heap and every CPU's stacks are mapped, since the tests run after init.
+    #[test_case]
+    fn kernel_address_space_is_w_xor_x() {
+        AddrSpace::kernel().assert_w_xor_x();
+    }
+
+    #[test_case]
+    fn user_address_spaces_map_and_free_their_tables() {
+        let space = AddrSpace::new_user().unwrap();
+        let (frame, _) = pmm::Global.allocate_frame_mapped().unwrap();
+        let page = Page(VirtAddr(0x80_0000));
+        space
+            .map_frames_at(page, frame..Step::forward(frame, 1), MapOptions::default())
+            .unwrap();
+        assert_eq!(space.translate_page(page), Some(frame));
+        assert_eq!(space.translate(page.0 + 0x123), Some(frame.0 + 0x123));
+
+        let l4 = space.with_state(|state| state.mapper.l4_frame());
+        crate::interrupts::without(|| {
+            drop(space);
+            // The allocator hands out the most recently freed frame first, and the l4 goes last.
+            let next = pmm::Global.allocate_frame().unwrap();
+            assert_eq!(next, l4);
+            unsafe { pmm::Global.deallocate_frame(next) };
+        });
+        unsafe { pmm::Global.deallocate_frame(frame) };
+    }
+
+    #[test_case]
+    fn unmap_leaves_nothing_behind() {
+        let space = AddrSpace::kernel();
+        let (frame, _) = pmm::Global.allocate_frame_mapped().unwrap();
+        let one = NonZeroUsize::new(1).unwrap();
+
+        let ptr = space
+            .map_frames(frame..Step::forward(frame, 1), MapOptions::default())
+            .unwrap();
+        let page = Page::containing(VirtAddr(ptr.as_ptr() as usize));
+        assert_eq!(space.translate_page(page), Some(frame));
+
+        unsafe { space.unmap(ptr, one, FrameOwner::Caller) }.unwrap();
+        assert_eq!(space.translate_page(page), None);
+        // A second unmap finds nothing there.
+        let again = unsafe { space.unmap(ptr, one, FrameOwner::Caller) };
+        assert!(matches!(again, Err(UnmapError::PageNotMapped)));
+
+        unsafe { pmm::Global.deallocate_frame(frame) };
+    }
+
+    #[test_case]
+    fn protect_makes_pages_read_only() {
+        let space = AddrSpace::kernel();
+        let two = NonZeroUsize::new(2).unwrap();
+        let ptr = space.allocate(two).unwrap();
+        let start = Page::containing(VirtAddr(ptr.as_ptr() as usize));
+        let pages = start..Step::forward(start, 2);
+        let flags = |page| {
+            space
+                .with_state(|state| state.mapper.page_flags(page))
+                .unwrap()
+        };
+        let frames: Vec<_> = pages
+            .clone()
+            .map(|page| space.translate_page(page))
+            .collect();
+
+        for page in pages.clone() {
+            assert!(flags(page).contains(PageFlags::WRITABLE));
+        }
+
+        let read_only = MapOptions {
+            no_execute: true,
+            ..Default::default()
+        };
+        unsafe { space.protect(ptr, two, read_only) }.unwrap();
+        for (page, frame) in pages.zip(frames) {
+            let flags = flags(page);
+            assert!(flags.contains(PageFlags::PRESENT), "{:?}", flags);
+            assert!(!flags.contains(PageFlags::WRITABLE), "{:?}", flags);
+            assert_eq!(space.translate_page(page), frame);
+        }
+
+        unsafe { space.unmap(ptr, two, FrameOwner::AddrSpace) }.unwrap();
+    }
+
+    #[test_case]
+    fn map_frames_at_reads_back_through_the_mapping() {
+        let space = AddrSpace::new_user().unwrap();
+        let (frame, ptr) = pmm::Global.allocate_frame_mapped().unwrap();
+        for i in 0..4096 {
+            unsafe { ptr.as_ptr().add(i).write_volatile(i as u8 ^ 0x3c) };
+        }
+
+        let page = Page(VirtAddr(0x90_0000));
+        let frames = || frame..Step::forward(frame, 1);
+        space
+            .map_frames_at(page, frames(), MapOptions::default())
+            .unwrap();
+        assert_eq!(space.translate_page(page), Some(frame));
+
+        let mut copy = [0u8; 4096];
+        crate::interrupts::without(|| unsafe {
+            let kernel = cr3::read();
+            space.with_state(|state| state.mapper.activate());
+            ptr::copy_nonoverlapping(page.0.as_ptr().cast::<u8>(), copy.as_mut_ptr(), copy.len());
+            cr3::write(kernel);
+        });
+        for (i, byte) in copy.iter().enumerate() {
+            assert_eq!(*byte, i as u8 ^ 0x3c);
+        }
+
+        // The region is taken now, reported through the same error type as `map_frames`.
+        let again = space.map_frames_at(page, frames(), MapOptions::default());
+        assert!(matches!(
+            again,
+            Err(AllocError::VirtAllocError(
+                VirtAllocError::RegionUnavailable
+            ))
+        ));
+
+        drop(space);
+        unsafe { pmm::Global.deallocate_frame(frame) };
+    }
+
+    #[test_case]
+    fn translate_keeps_the_offset_in_the_page() {
+        let space = AddrSpace::new_user().unwrap();
+        let page = Page(VirtAddr(0xa0_0000));
+        space
+            .map_frames_at(page, frames(0x100_0000, 2), MapOptions::default())
+            .unwrap();
+
+        for offset in [0, 1, 0x7ff, 0xfff, 0x1000, 0x1abc, 0x1fff] {
+            assert_eq!(
+                space.translate(page.0 + offset),
+                Some(PhysAddr(0x100_0000 + offset as u64)),
+                "offset {:#x}",
+                offset
+            );
+        }
+        assert_eq!(space.translate(page.0 + 0x2000), None);
+    }
+
+    #[test_case]
+    fn accesses_set_the_accessed_and_dirty_bits() {
+        let space = AddrSpace::kernel();
+        let one = NonZeroUsize::new(1).unwrap();
+        let ptr = space.allocate(one).unwrap();
+        let page = Page::containing(VirtAddr(ptr.as_ptr() as usize));
+        let flags = || {
+            space
+                .with_state(|state| state.mapper.page_flags(page))
+                .unwrap()
+        };
+
+        space.with_state(|state| unsafe { state.mapper.clear_accessed(page) }.unwrap());
+        assert!(!flags().contains(PageFlags::ACCESSED));
+        assert!(!flags().contains(PageFlags::DIRTY));
+
+        unsafe { ptr.as_ptr().read_volatile() };
+        assert!(flags().contains(PageFlags::ACCESSED));
+        assert!(!flags().contains(PageFlags::DIRTY));
+
+        unsafe { ptr.as_ptr().write_volatile(1) };
+        assert!(flags().contains(PageFlags::DIRTY));
+
+        unsafe { space.unmap(ptr, one, FrameOwner::AddrSpace) }.unwrap();
+    }
+
+    #[test_case]
+    fn activate_round_trips_through_cr3() {
+        let space = AddrSpace::new_user().unwrap();
+        let user_l4 = space.with_state(|state| state.mapper.l4_frame());
+        let kernel_l4 = AddrSpace::kernel().with_state(|state| state.mapper.l4_frame());
+        assert_eq!(cr3::read(), kernel_l4);
+
+        crate::interrupts::without(|| unsafe {
+            space.activate();
+            let active = cr3::read();
+            let is_active = AddrSpace::with_active(|active| ptr::eq(active, &space));
+            AddrSpace::kernel().activate();
+
+            assert_eq!(active, user_l4);
+            assert!(is_active);
+        });
+        assert_eq!(cr3::read(), kernel_l4);
+        assert!(AddrSpace::with_active(|active| matches!(
+            active.inner,
+            AddrSpaceInner::Kernel
+        )));
+    }
+
+    #[test_case]
+    fn active_address_space_is_per_cpu() {
+        let space = AddrSpace::new_user().unwrap();
+        let block = percpu::current();
+
+        crate::interrupts::without(|| unsafe {
+            space.activate();
+            let recorded = block.active_addr_space.get();
+            AddrSpace::kernel().activate();
+
+            assert!(ptr::eq(recorded, &space));
+        });
+        let after = unsafe { &*block.active_addr_space.get() };
+        assert!(matches!(after.inner, AddrSpaceInner::Kernel));
+    }
+
+    #[test_case]
+    fn copy_on_write_gives_the_writer_its_own_copy() {
+        let space = AddrSpace::new_user().unwrap();
+        let (shared, ptr) = pmm::Global.allocate_frame_mapped().unwrap();
+        let pattern = |i: usize| i as u8 ^ 0x69;
+        for i in 0..4096 {
+            unsafe { ptr.as_ptr().add(i).write_volatile(pattern(i)) };
+        }
+
+        let page = Page(VirtAddr(0xb0_0000));
+        let cow = MapOptions {
+            copy_on_write: true,
+            ..Default::default()
+        };
+        space
+            .map_frames_at(page, shared..Step::forward(shared, 1), cow)
+            .unwrap();
+        let flags = || {
+            space
+                .with_state(|state| state.mapper.page_flags(page))
+                .unwrap()
+        };
+        assert!(flags().contains(PageFlags::COW));
+        assert!(!flags().contains(PageFlags::WRITABLE));
+
+        let mut copy = [0u8; 4096];
+        crate::interrupts::without(|| unsafe {
+            space.activate();
+            let addr = page.0.as_ptr().cast::<u8>();
+            // Faults, and the page fault handler gives the page its own frame before the write
+            // is retried.
+            addr.add(1).write_volatile(0xff);
+            ptr::copy_nonoverlapping(addr, copy.as_mut_ptr(), copy.len());
+            AddrSpace::kernel().activate();
+        });
+
+        let private = space.translate_page(page).unwrap();
+        assert_ne!(private, shared);
+        assert!(flags().contains(PageFlags::WRITABLE));
+        assert!(!flags().contains(PageFlags::COW));
+        for (i, byte) in copy.iter().enumerate() {
+            assert_eq!(*byte, if i == 1 { 0xff } else { pattern(i) });
+        }
+        // The shared frame never sees the write.
+        assert_eq!(unsafe { ptr.as_ptr().add(1).read_volatile() }, pattern(1));
+
+        drop(space);
+        unsafe {
+            pmm::Global.deallocate_frame(shared);
+            pmm::Global.deallocate_frame(private);
+        }
     }
 }
@@ -1,4 +1,12 @@
-use core::{arch::asm, cell::Cell, fmt::Debug, ptr};
+use core::{
+    arch::asm,
+    cell::Cell,
+    fmt::Debug,
+    iter::Step,
+    ops::Range,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
 
 use bitflags::bitflags;
 use bytemuck::Zeroable;
@@ -6,8 +14,13 @@ use bytemuck::Zeroable;
 use crate::{
     hhdm::{Hhdm, HigherHalf},
     pmm::{PhysAllocError, PhysicalMemoryAllocator},
+    trace::{self, Tag},
     types::{Frame, Page, PhysAddr, VirtAddr},
-    x86_64::cr3,
+    x86_64::{
+        cr3,
+        cr4::{self, Cr4},
+        efer::{self, Efer},
+    },
 };
 
 #[derive(Debug)]
@@ -19,6 +32,8 @@ pub enum MapError {
 #[derive(Debug)]
 pub enum UnmapError {
     PageNotMapped,
+    /// The page is part of a huge page, which can't be unmapped a page at a time.
+    InsideHugePage,
 }
 
 impl From<PhysAllocError> for MapError {
@@ -43,20 +58,171 @@ impl PageMapper {
         Self { l4, hhdm }
     }
 
+    /// Creates page tables for a new address space whose upper half shares the kernel's page
+    /// tables.
+    pub unsafe fn with_kernel_half(
+        kernel: &PageMapper,
+        phys_alloc: &impl PhysicalMemoryAllocator,
+    ) -> Result<Self, PhysAllocError> {
+        let frame = phys_alloc.allocate_frame()?;
+        let l4: HigherHalf<PageTable> = kernel.hhdm.to_virtual(frame.0);
+        ptr::write(l4.as_ptr(), PageTable::empty());
+
+        let kernel_entries = &kernel.l4.as_ref().entries[256..];
+        for (entry, kernel_entry) in l4.as_ref().entries[256..].iter().zip(kernel_entries) {
+            entry.set(kernel_entry.get());
+        }
+
+        Ok(Self {
+            l4,
+            hhdm: kernel.hhdm.clone(),
+        })
+    }
+
+    /// Makes these page tables the active ones on this CPU.
+    ///
+    /// Every TLB entry except those for [`PageFlags::GLOBAL`] pages is flushed.
+    pub unsafe fn activate(&self) {
+        cr3::write(self.l4_frame());
+    }
+
+    /// The frame holding the l4, which is what CR3 points at while these tables are active.
+    pub fn l4_frame(&self) -> Frame {
+        Frame(self.hhdm.to_physical(self.l4))
+    }
+
+    /// Frees the l4, leaving the mapper dangling.
+    ///
+    /// Everything in the lower half must have been unmapped and its tables reclaimed first, and
+    /// the tables must not be active on any CPU.
+    pub unsafe fn free_l4(&mut self, phys_alloc: &impl PhysicalMemoryAllocator) {
+        debug_assert!(
+            self.l4.as_ref().entries[..256]
+                .iter()
+                .all(|entry| !entry.get().flags().contains(PageFlags::PRESENT)),
+            "freeing an l4 that still has lower half tables"
+        );
+        phys_alloc.deallocate_frame(self.l4_frame());
+    }
+
     pub unsafe fn map_page(
         &mut self,
         page: Page,
         frame: Frame,
         flags: PageFlags,
         phys_alloc: &impl PhysicalMemoryAllocator,
+    ) -> Result<(), MapError> {
+        self.map_page_unflushed(page, frame, flags, phys_alloc)?;
+        tlb_flush(page.0);
+        Ok(())
+    }
+
+    pub unsafe fn unmap_page(&mut self, page: Page) -> Result<Frame, UnmapError> {
+        let frame = self.unmap_page_unflushed(page)?;
+        tlb_flush(page.0);
+        Ok(frame)
+    }
+
+    /// Like [`PageMapper::unmap_page`], but also frees any intermediate page tables left empty.
+    pub unsafe fn unmap_page_reclaim(
+        &mut self,
+        page: Page,
+        phys_alloc: &impl PhysicalMemoryAllocator,
+    ) -> Result<Frame, UnmapError> {
+        let frame = self.unmap_page(page)?;
+        self.reclaim_tables(page.0.addr(), phys_alloc);
+        Ok(frame)
+    }
+
+    /// Maps the 2 MiB region starting at `page` to the 2 MiB of memory starting at `frame`, using
+    /// a single l2 entry.
+    ///
+    /// Both `page` and `frame` must be 2 MiB aligned.
+    pub unsafe fn map_huge_page_2m(
+        &mut self,
+        page: Page,
+        frame: Frame,
+        flags: PageFlags,
+        phys_alloc: &impl PhysicalMemoryAllocator,
+    ) -> Result<(), MapError> {
+        assert_eq!(
+            page.0.align_down(HUGE_PAGE_SIZE),
+            page.0,
+            "unaligned huge page"
+        );
+        assert_eq!(
+            frame.0.align_down(HUGE_PAGE_SIZE as u64),
+            frame.0,
+            "unaligned huge frame"
+        );
+        log::trace!("mapping huge {:x?} to {:x?}", page, frame);
+
+        let vaddr = page.0.addr();
+        let page_table = self.create_table(vaddr, 2, flags, phys_alloc)?;
+
+        let page_table_index = page.0.page_table_index(1);
+        let entry_cell = &page_table.entries[page_table_index];
+        if entry_cell.get().flags().contains(PageFlags::PRESENT) {
+            return Err(MapError::PageAlreadyMapped);
+        }
+
+        entry_cell.set(PageTableEntry::new(flags | PageFlags::HUGE_PAGE, frame));
+        trace::emit(Tag::Map, vaddr as u64, frame.0 .0);
+
+        tlb_flush(page.0);
+        Ok(())
+    }
+
+    /// Maps `page` without invalidating its TLB entry, which is left to the caller.
+    unsafe fn map_page_unflushed(
+        &mut self,
+        page: Page,
+        frame: Frame,
+        flags: PageFlags,
+        phys_alloc: &impl PhysicalMemoryAllocator,
     ) -> Result<(), MapError> {
         log::trace!("mapping {:x?} to {:x?}", page, frame);
 
         let vaddr = page.0.addr();
+        let page_table = self.create_table(vaddr, 1, flags, phys_alloc)?;
+
+        let page_table_index = page.0.page_table_index(0);
+        let entry_cell = &page_table.entries[page_table_index];
+        let entry = PageTableEntry::new(flags, frame);
+        if entry_cell.get().flags().contains(PageFlags::PRESENT) {
+            return Err(MapError::PageAlreadyMapped);
+        }
+
+        entry_cell.set(entry);
+        trace::emit(Tag::Map, page.0.addr() as u64, frame.0 .0);
+        Ok(())
+    }
+
+    /// Walks down to the level `down_to` table covering `vaddr`, allocating any missing tables on
+    /// the way.
+    ///
+    /// The processor only lets ring 3 through an entry if every level above it allows it too, so
+    /// the tables on the way only get [`PageFlags::USER`] if the mapping about to be made with
+    /// `flags` has it. Tables that were kernel-only until now are opened up for a user mapping.
+    ///
+    /// Fails with [`MapError::PageAlreadyMapped`] if a huge page already covers `vaddr`.
+    unsafe fn create_table(
+        &mut self,
+        vaddr: usize,
+        down_to: u8,
+        flags: PageFlags,
+        phys_alloc: &impl PhysicalMemoryAllocator,
+    ) -> Result<&PageTable, MapError> {
+        let table_flags = PageFlags::PRESENT | PageFlags::WRITABLE | (flags & PageFlags::USER);
+        debug_assert!(
+            VirtAddr(vaddr).is_canonical(),
+            "non-canonical address {:#x}",
+            vaddr
+        );
         let mut page_table = self.l4.as_ref();
 
-        for level in (1..4).rev() {
-            let page_table_index = vaddr.wrapping_shr(12 + 9 * level) & 0x1ff;
+        for level in (down_to..4).rev() {
+            let page_table_index = VirtAddr(vaddr).page_table_index(level);
             let entry_cell = &page_table.entries[page_table_index];
             let mut entry = entry_cell.get();
 
@@ -70,13 +236,13 @@ impl PageMapper {
                 let page_table_ptr = self.hhdm.to_virtual(page_table_frame.0);
                 ptr::write(page_table_ptr.as_ptr(), PageTable::empty());
 
-                entry = PageTableEntry::new(
-                    PageFlags::PRESENT | PageFlags::WRITABLE | PageFlags::USER,
-                    page_table_frame,
-                );
+                entry = PageTableEntry::new(table_flags, page_table_frame);
                 entry_cell.set(entry);
             } else if entry.flags().contains(PageFlags::HUGE_PAGE) {
-                todo!("huge page handling");
+                return Err(MapError::PageAlreadyMapped);
+            } else if !entry.flags().contains(table_flags) {
+                entry = PageTableEntry::new(entry.flags() | table_flags, entry.frame());
+                entry_cell.set(entry);
             }
 
             let frame = entry.frame();
@@ -84,20 +250,86 @@ impl PageMapper {
             page_table = child_page_table_ptr.as_ref();
         }
 
-        let page_table_index = vaddr.wrapping_shr(12) & 0x1ff;
-        let entry_cell = &page_table.entries[page_table_index];
-        let entry = PageTableEntry::new(flags, frame);
-        if entry_cell.get().flags().contains(PageFlags::PRESENT) {
-            return Err(MapError::PageAlreadyMapped);
+        Ok(page_table)
+    }
+
+    /// Unmaps `page` without invalidating its TLB entry, which is left to the caller.
+    unsafe fn unmap_page_unflushed(&mut self, page: Page) -> Result<Frame, UnmapError> {
+        log::trace!("unmapping page {:#x?}", page);
+        let (slot, size) = self.walk(page.0.addr()).ok_or(UnmapError::PageNotMapped)?;
+
+        let pte = slot.get();
+        if !pte.flags().contains(PageFlags::PRESENT) {
+            return Err(UnmapError::PageNotMapped);
+        }
+        if size != 4096 {
+            return Err(UnmapError::InsideHugePage);
         }
 
-        entry_cell.set(entry);
+        let frame = pte.frame();
+        let pte = PageTableEntry::new(PageFlags::empty(), frame);
+        slot.set(pte);
+        trace::emit(Tag::Unmap, page.0.addr() as u64, frame.0 .0);
+        Ok(frame)
+    }
+
+    /// Frees the intermediate tables on the path to `vaddr` that no longer map anything, from the
+    /// bottom up.
+    ///
+    /// The l4 is never freed, and neither is anything under its kernel half, since those tables
+    /// are shared with every other address space.
+    unsafe fn reclaim_tables(&mut self, vaddr: usize, phys_alloc: &impl PhysicalMemoryAllocator) {
+        let index = |depth: usize| VirtAddr(vaddr).page_table_index(3 - depth as u8);
+        if index(0) >= 256 {
+            return;
+        }
+
+        // The tables on the path to `vaddr`, with the l4 at index 0.
+        let mut tables = [self.l4.as_ref(); 4];
+        for depth in 1..4 {
+            let entry = tables[depth - 1].entries[index(depth - 1)].get();
+            if !entry.flags().contains(PageFlags::PRESENT)
+                || entry.flags().contains(PageFlags::HUGE_PAGE)
+            {
+                return;
+            }
+            tables[depth] = self.hhdm.to_virtual::<PageTable>(entry.frame().0).as_ref();
+        }
+
+        let mut freed = [None; 3];
+        for depth in (1..4).rev() {
+            let in_use = tables[depth]
+                .entries
+                .iter()
+                .any(|entry| entry.get().flags().contains(PageFlags::PRESENT));
+            if in_use {
+                break;
+            }
+            let parent_entry = &tables[depth - 1].entries[index(depth - 1)];
+            freed[depth - 1] = Some(parent_entry.get().frame());
+            parent_entry.set(PageTableEntry::missing());
+        }
+
+        // The paging-structure caches may still point at the tables until this flush, so they
+        // can only be reused after it.
+        if freed.iter().any(Option::is_some) {
+            tlb_flush(VirtAddr(vaddr));
+        }
+        for frame in freed.into_iter().flatten() {
+            phys_alloc.deallocate_frame(frame);
+        }
+    }
+
+    /// Replaces the flags of a mapped page, keeping the frame it maps to.
+    ///
+    /// The page stays present whether or not `flags` includes [`PageFlags::PRESENT`].
+    pub unsafe fn protect(&mut self, page: Page, flags: PageFlags) -> Result<(), UnmapError> {
+        self.protect_unflushed(page, flags)?;
         tlb_flush(page.0);
         Ok(())
     }
 
-    pub unsafe fn unmap_page(&mut self, page: Page) -> Result<Frame, UnmapError> {
-        log::trace!("unmapping page {:#x?}", page);
+    unsafe fn protect_unflushed(&mut self, page: Page, flags: PageFlags) -> Result<(), UnmapError> {
         let slot = self
             .get_entry(page.0.addr())
             .ok_or(UnmapError::PageNotMapped)?;
@@ -106,41 +338,306 @@ impl PageMapper {
         if !pte.flags().contains(PageFlags::PRESENT) {
             return Err(UnmapError::PageNotMapped);
         }
+        let huge = pte.flags() & PageFlags::HUGE_PAGE;
+        slot.set(PageTableEntry::new(
+            flags | huge | PageFlags::PRESENT,
+            pte.frame(),
+        ));
+        Ok(())
+    }
 
-        let frame = pte.frame();
-        let pte = PageTableEntry::new(PageFlags::empty(), frame);
-        slot.set(pte);
+    /// Returns the flags `page` is mapped with, or `None` if it isn't mapped.
+    pub fn page_flags(&self, page: Page) -> Option<PageFlags> {
+        let flags = self.get_entry(page.0.addr())?.get().flags();
+        flags.contains(PageFlags::PRESENT).then_some(flags)
+    }
+
+    /// Clears the accessed bit of `page`, so that the processor sets it again on the next access.
+    pub unsafe fn clear_accessed(&mut self, page: Page) -> Result<(), UnmapError> {
+        let slot = self
+            .get_entry(page.0.addr())
+            .ok_or(UnmapError::PageNotMapped)?;
+
+        let pte = slot.get();
+        if !pte.flags().contains(PageFlags::PRESENT) {
+            return Err(UnmapError::PageNotMapped);
+        }
+        slot.set(PageTableEntry(pte.0 & !PageFlags::ACCESSED.bits()));
         tlb_flush(page.0);
-        Ok(frame)
+        Ok(())
     }
 
     pub fn translate_page(&self, page: Page) -> Option<Frame> {
-        let entry = self.get_entry(page.0.addr())?.get();
+        let addr = page.0.addr();
+        let (entry, size) = self.walk(addr)?;
+        let entry = entry.get();
 
-        entry
-            .flags()
-            .contains(PageFlags::PRESENT)
-            .then_some(entry.frame())
+        if !entry.flags().contains(PageFlags::PRESENT) {
+            return None;
+        }
+        Some(Frame(entry.leaf_frame(size).0 + (addr % size) as u64))
     }
 
+    /// Walks the page tables, yielding each mapped region as a range of pages backed by
+    /// physically contiguous frames with identical flags.
+    ///
+    /// The tables are read as the walk goes, so changes made while iterating may or may not be
+    /// observed.
+    pub fn mappings(&self) -> Mappings {
+        Mappings {
+            hhdm: self.hhdm.clone(),
+            tables: [Some(self.l4), None, None, None],
+            next: [0; 4],
+            depth: 0,
+            pending: None,
+        }
+    }
+
+    /// Logs every mapped region overlapping `range` at debug level, one line per run of pages
+    /// with contiguous frames and identical flags.
+    pub fn dump_mappings(&self, range: Range<VirtAddr>) {
+        let overlapping = self
+            .mappings()
+            .filter(|(pages, _, _)| pages.start.0 < range.end && range.start < pages.end.0);
+
+        for (pages, frame, flags) in overlapping {
+            let len = pages.end.0.offset_from(pages.start.0);
+            let end = frame.0 + len as u64;
+            log::debug!(
+                "{:#x}..{:#x} -> {:#x}..{:#x} [{:?}]",
+                pages.start.0.addr(),
+                pages.end.0.addr(),
+                frame.0 .0,
+                end.0,
+                flags
+            );
+        }
+    }
+
+    /// Returns every mapping that is both writable and executable.
+    pub fn w_xor_x_violations(&self) -> impl Iterator<Item = (Range<Page>, Frame, PageFlags)> {
+        self.mappings().filter(|(_, _, flags)| {
+            flags.contains(PageFlags::WRITABLE) && !flags.contains(PageFlags::NO_EXECUTE)
+        })
+    }
+
+    /// Panics if any mapping is both writable and executable, logging the first offenders.
+    pub fn assert_w_xor_x(&self) {
+        let mut count = 0;
+        for (pages, frame, flags) in self.w_xor_x_violations() {
+            if count < 8 {
+                log::error!("W^X violation: {:x?} -> {:x?} ({:?})", pages, frame, flags);
+            }
+            count += 1;
+        }
+        assert!(count == 0, "W^X violated by {} mappings", count);
+    }
+
+    /// Returns the entry mapping `addr`. Within a huge page, that is the huge l2 or l3 entry, so
+    /// changing it affects the whole huge page.
     fn get_entry(&self, addr: usize) -> Option<&Cell<PageTableEntry>> {
+        self.walk(addr).map(|(entry, _)| entry)
+    }
+
+    /// Returns the leaf entry for `addr` along with the size in bytes of the region it maps.
+    fn walk(&self, addr: usize) -> Option<(&Cell<PageTableEntry>, usize)> {
+        debug_assert!(
+            VirtAddr(addr).is_canonical(),
+            "non-canonical address {:#x}",
+            addr
+        );
         let mut page_table = unsafe { self.l4.as_ref() };
 
         for i in (1..4).rev() {
-            let index = addr.wrapping_shr(12 + 9 * i) & 0x1ff;
+            let index = VirtAddr(addr).page_table_index(i);
             let pte = page_table.entries[index].get();
 
             if !pte.flags().contains(PageFlags::PRESENT) {
                 return None;
             }
+            if i < 3 && pte.flags().contains(PageFlags::HUGE_PAGE) {
+                return Some((&page_table.entries[index], 4096 << (9 * i)));
+            }
 
             let frame = pte.frame();
             let ptr: HigherHalf<PageTable> = self.hhdm.to_virtual(frame.0);
             page_table = unsafe { ptr.as_ref() };
         }
 
-        let index = addr.wrapping_shr(12) & 0x1ff;
-        Some(&page_table.entries[index])
+        let index = VirtAddr(addr).page_table_index(0);
+        Some((&page_table.entries[index], 4096))
+    }
+}
+
+/// The size of a page mapped directly by an l2 entry.
+pub const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+
+/// Past this many pages, a batch flushes the whole TLB instead of invalidating page by page.
+const BATCH_FLUSH_THRESHOLD: usize = 32;
+
+/// Groups page table changes so that their TLB invalidation happens once, when the batch is
+/// dropped.
+///
+/// Small batches still invalidate each page individually; larger ones reload CR3 instead. Until
+/// the batch is dropped, stale translations for the changed pages may remain cached.
+pub struct MappingBatch<'a> {
+    mapper: &'a mut PageMapper,
+    pending: [Page; BATCH_FLUSH_THRESHOLD],
+    len: usize,
+    overflowed: bool,
+}
+
+impl<'a> MappingBatch<'a> {
+    pub fn new(mapper: &'a mut PageMapper) -> Self {
+        Self {
+            mapper,
+            pending: [Page(VirtAddr::zero()); BATCH_FLUSH_THRESHOLD],
+            len: 0,
+            overflowed: false,
+        }
+    }
+
+    pub fn mapper(&self) -> &PageMapper {
+        self.mapper
+    }
+
+    pub unsafe fn map_page(
+        &mut self,
+        page: Page,
+        frame: Frame,
+        flags: PageFlags,
+        phys_alloc: &impl PhysicalMemoryAllocator,
+    ) -> Result<(), MapError> {
+        self.mapper
+            .map_page_unflushed(page, frame, flags, phys_alloc)?;
+        self.defer_flush(page);
+        Ok(())
+    }
+
+    pub unsafe fn unmap_page(&mut self, page: Page) -> Result<Frame, UnmapError> {
+        let frame = self.mapper.unmap_page_unflushed(page)?;
+        self.defer_flush(page);
+        Ok(frame)
+    }
+
+    /// Like [`MappingBatch::unmap_page`], but also frees any intermediate page tables left empty.
+    pub unsafe fn unmap_page_reclaim(
+        &mut self,
+        page: Page,
+        phys_alloc: &impl PhysicalMemoryAllocator,
+    ) -> Result<Frame, UnmapError> {
+        let frame = self.unmap_page(page)?;
+        self.mapper.reclaim_tables(page.0.addr(), phys_alloc);
+        Ok(frame)
+    }
+
+    pub unsafe fn protect(&mut self, page: Page, flags: PageFlags) -> Result<(), UnmapError> {
+        self.mapper.protect_unflushed(page, flags)?;
+        self.defer_flush(page);
+        Ok(())
+    }
+
+    fn defer_flush(&mut self, page: Page) {
+        match self.pending.get_mut(self.len) {
+            Some(slot) => {
+                *slot = page;
+                self.len += 1;
+            }
+            None => self.overflowed = true,
+        }
+    }
+}
+
+impl<'a> Drop for MappingBatch<'a> {
+    fn drop(&mut self) {
+        if self.overflowed {
+            tlb_nuke();
+        } else {
+            for page in &self.pending[..self.len] {
+                tlb_flush(page.0);
+            }
+        }
+    }
+}
+
+/// Iterator over the mapped regions of a [`PageMapper`].
+#[derive(Debug)]
+pub struct Mappings {
+    hhdm: Hhdm,
+    /// The table being walked at each level, with the l4 at index 0.
+    tables: [Option<HigherHalf<PageTable>>; 4],
+    /// The index of the next entry to visit in each table.
+    next: [usize; 4],
+    depth: usize,
+    pending: Option<(Range<Page>, Frame, PageFlags)>,
+}
+
+impl Mappings {
+    /// Returns the next present leaf entry as a region covering every page it maps.
+    fn next_leaf(&mut self) -> Option<(Range<Page>, Frame, PageFlags)> {
+        loop {
+            let table = self.tables[self.depth]?;
+
+            if self.next[self.depth] == 512 {
+                self.tables[self.depth] = None;
+                self.depth = self.depth.checked_sub(1)?;
+                continue;
+            }
+
+            let index = self.next[self.depth];
+            self.next[self.depth] += 1;
+
+            let entry = unsafe { table.as_ref() }.entries[index].get();
+            if !entry.flags().contains(PageFlags::PRESENT) {
+                continue;
+            }
+
+            if self.depth == 3 || entry.flags().contains(PageFlags::HUGE_PAGE) {
+                let start = Page(self.current_addr());
+                let pages = 1 << (9 * (3 - self.depth));
+                let end = Step::forward(start, pages);
+                // The processor sets these as pages are used, so they would keep otherwise
+                // identical neighbours from coalescing.
+                let flags = entry.flags() - (PageFlags::ACCESSED | PageFlags::DIRTY);
+                return Some((start..end, entry.leaf_frame(pages * 4096), flags));
+            }
+
+            self.depth += 1;
+            self.tables[self.depth] = Some(self.hhdm.to_virtual(entry.frame().0));
+            self.next[self.depth] = 0;
+        }
+    }
+
+    /// The address mapped by the entry most recently visited at the current depth.
+    fn current_addr(&self) -> VirtAddr {
+        let addr = (0..=self.depth).fold(0, |addr, depth| {
+            addr | ((self.next[depth] - 1) << (39 - 9 * depth))
+        });
+        // Sign-extend bit 47 to form a canonical address.
+        VirtAddr(((addr << 16) as isize >> 16) as usize)
+    }
+}
+
+impl Iterator for Mappings {
+    type Item = (Range<Page>, Frame, PageFlags);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (mut pages, frame, flags) = self.pending.take().or_else(|| self.next_leaf())?;
+
+        while let Some((next_pages, next_frame, next_flags)) = self.next_leaf() {
+            let len = Step::steps_between(&pages.start, &pages.end).unwrap();
+            let contiguous = next_pages.start == pages.end
+                && Step::forward_checked(frame, len) == Some(next_frame)
+                && next_flags == flags;
+
+            if !contiguous {
+                self.pending = Some((next_pages, next_frame, next_flags));
+                break;
+            }
+            pages.end = next_pages.end;
+        }
+
+        Some((pages, frame, flags))
     }
 }
 
@@ -156,7 +653,9 @@ impl PageTable {
     }
 }
 
-const FRAME_MASK: u64 = u64::MAX.wrapping_shl(13).wrapping_shr(1);
+/// Bits 12 to 51 of an entry hold the frame's address. Above them are the no-execute bit and bits
+/// the processor ignores or uses for protection keys.
+const FRAME_MASK: u64 = 0x000f_ffff_ffff_f000;
 
 #[repr(transparent)]
 #[derive(Clone, Copy, Zeroable)]
@@ -183,8 +682,20 @@ impl PageTableEntry {
     }
 
     pub fn new(flags: PageFlags, frame: Frame) -> Self {
+        Self::new_with_nx(flags, frame, efer::read().contains(Efer::NO_EXECUTE_ENABLE))
+    }
+
+    /// Like [`PageTableEntry::new`], for a processor that has EFER.NXE set as given.
+    fn new_with_nx(flags: PageFlags, frame: Frame, nx_enabled: bool) -> Self {
+        let flags = strip_unsupported_flags(flags, nx_enabled);
+        debug_assert_eq!(
+            frame.0 .0 & !FRAME_MASK,
+            0,
+            "frame {:#x} can't be held in a page table entry",
+            frame.0 .0
+        );
         let flags = flags.bits() & !FRAME_MASK;
-        Self(flags | frame.0 .0)
+        Self(flags | (frame.0 .0 & FRAME_MASK))
     }
 
     pub fn flags(&self) -> PageFlags {
@@ -200,27 +711,421 @@ impl PageTableEntry {
         Frame(PhysAddr(self.0 & FRAME_MASK))
     }
 
+    /// The start of the memory a leaf entry mapping `size` bytes points to. In 2 MiB and 1 GiB
+    /// entries, bit 12 is the PAT bit rather than part of the address.
+    pub fn leaf_frame(&self, size: usize) -> Frame {
+        Frame(PhysAddr(self.0 & FRAME_MASK & !(size as u64 - 1)))
+    }
+
     // pub fn set_flags(&mut self, flags: PageFlags) {
     //     self.0 &= !PageFlags::all().bits();
     //     self.0 |= (flags & PageFlags::all()).bits();
     // }
 }
 
+/// Removes flags the processor would treat as reserved bits.
+///
+/// Bit 63 is only the no-execute bit while EFER.NXE is set; otherwise it is reserved and any
+/// access through the entry raises a reserved-bit page fault. In that case pages are mapped
+/// executable instead, with a warning the first time it happens.
+fn strip_unsupported_flags(flags: PageFlags, nx_enabled: bool) -> PageFlags {
+    static WARNED: AtomicBool = AtomicBool::new(false);
+
+    // EFER.NXE can only be set on processors that support NX in the first place.
+    if !flags.contains(PageFlags::NO_EXECUTE) || nx_enabled {
+        return flags;
+    }
+
+    if !WARNED.swap(true, Ordering::Relaxed) {
+        log::warn!("no-execute pages requested but EFER.NXE is disabled, mapping them executable");
+    }
+    flags - PageFlags::NO_EXECUTE
+}
+
 bitflags! {
-    #[derive(Debug, Clone, Copy)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub struct PageFlags: u64 {
         const PRESENT = 1;
         const WRITABLE = 1 << 1;
         const USER = 1 << 2;
         const DISABLE_CACHE = 1 << 4;
+        /// Set by the processor when the page is read or written.
+        const ACCESSED = 1 << 5;
+        /// Set by the processor when the page is written.
+        const DIRTY = 1 << 6;
         const HUGE_PAGE = 1 << 7;
+        const GLOBAL = 1 << 8;
+        /// Ignored by the processor. Marks a read-only page that gets a private copy of its frame
+        /// the first time it is written.
+        const COW = 1 << 9;
+        const NO_EXECUTE = 1 << 63;
     }
 }
 
+/// How many TLB flushes, of a single page or the whole TLB, have been issued on any CPU.
+static TLB_FLUSHES: AtomicU64 = AtomicU64::new(0);
+
+/// Returns how many times [`tlb_flush`] and [`tlb_nuke`] have run since boot.
+pub fn tlb_flushes() -> u64 {
+    TLB_FLUSHES.load(Ordering::Relaxed)
+}
+
 pub fn tlb_flush(addr: VirtAddr) {
+    TLB_FLUSHES.fetch_add(1, Ordering::Relaxed);
     unsafe { asm!("invlpg [{}]", in(reg) addr.0) }
 }
 
+/// Flushes the entire TLB.
+///
+/// Reloading CR3 leaves [`PageFlags::GLOBAL`] entries cached; only `invlpg` or toggling CR4.PGE
+/// drops those. So while global pages are enabled, CR4.PGE is toggled instead.
 pub fn tlb_nuke() {
-    unsafe { asm!("mov {tmp}, cr3", "mov cr3, {tmp}", tmp = out(reg) _) };
+    TLB_FLUSHES.fetch_add(1, Ordering::Relaxed);
+    let flags = cr4::read();
+    if flags.contains(Cr4::PAGE_GLOBAL_ENABLE) {
+        unsafe {
+            cr4::write(flags - Cr4::PAGE_GLOBAL_ENABLE);
+            cr4::write(flags);
+        }
+    } else {
+        unsafe { asm!("mov {tmp}, cr3", "mov cr3, {tmp}", tmp = out(reg) _) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use core::cell::RefCell;
+
+    use super::*;
+    use crate::pmm::Global;
+
+    /// Page tables for a fresh address space that is never activated, so the frames its pages
+    /// map to are never touched and any frame numbers will do.
+    struct Scratch {
+        mapper: PageMapper,
+    }
+
+    impl Scratch {
+        fn new() -> Self {
+            let kernel = unsafe { PageMapper::active() };
+            let mapper = unsafe { PageMapper::with_kernel_half(&kernel, &Global) }.unwrap();
+            Self { mapper }
+        }
+
+        /// Returns the l4, l3 and l2 entries on the path to `addr`, which must all be present.
+        fn table_entries(&self, addr: usize) -> [PageTableEntry; 3] {
+            let mut entries = [PageTableEntry::missing(); 3];
+            let mut table = unsafe { self.mapper.l4.as_ref() };
+            for (level, entry) in (1..4).rev().zip(&mut entries) {
+                *entry = table.entries[VirtAddr(addr).page_table_index(level)].get();
+                table = unsafe { self.mapper.hhdm.to_virtual(entry.frame().0).as_ref() };
+            }
+            entries
+        }
+
+        /// Returns the flags of the l4, l3 and l2 entries on the path to `addr`.
+        fn table_flags(&self, addr: usize) -> [PageFlags; 3] {
+            self.table_entries(addr).map(|entry| entry.flags())
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let pages: Vec<Page> = self
+                .mapper
+                .mappings()
+                .filter(|(pages, _, _)| !pages.start.0.is_higher_half())
+                .flat_map(|(pages, _, _)| pages)
+                .collect();
+            for page in pages {
+                _ = unsafe { self.mapper.unmap_page_reclaim(page, &Global) };
+            }
+            unsafe { self.mapper.free_l4(&Global) };
+        }
+    }
+
+    fn page(addr: usize) -> Page {
+        Page(VirtAddr(addr))
+    }
+
+    #[test_case]
+    fn kernel_mappings_keep_tables_kernel_only() {
+        let mut scratch = Scratch::new();
+        let flags = PageFlags::PRESENT | PageFlags::WRITABLE;
+        unsafe {
+            scratch.mapper.map_page(
+                page(0x4000_0000),
+                Frame(PhysAddr(0x10_0000)),
+                flags,
+                &Global,
+            )
+        }
+        .unwrap();
+
+        for flags in scratch.table_flags(0x4000_0000) {
+            assert!(!flags.contains(PageFlags::USER), "{:?}", flags);
+        }
+    }
+
+    #[test_case]
+    fn user_mappings_promote_existing_tables() {
+        let mut scratch = Scratch::new();
+        let kernel = PageFlags::PRESENT | PageFlags::WRITABLE;
+        unsafe {
+            scratch
+                .mapper
+                .map_page(
+                    page(0x4000_0000),
+                    Frame(PhysAddr(0x10_0000)),
+                    kernel,
+                    &Global,
+                )
+                .unwrap();
+            // Shares every table with the kernel page above.
+            scratch
+                .mapper
+                .map_page(
+                    page(0x4000_1000),
+                    Frame(PhysAddr(0x10_1000)),
+                    kernel | PageFlags::USER,
+                    &Global,
+                )
+                .unwrap();
+        }
+
+        for flags in scratch.table_flags(0x4000_0000) {
+            assert!(flags.contains(PageFlags::USER), "{:?}", flags);
+        }
+        // Only the tables are opened up, not the kernel page itself.
+        let flags = scratch.mapper.page_flags(page(0x4000_0000)).unwrap();
+        assert!(!flags.contains(PageFlags::USER));
+    }
+
+    /// The flags an entry made with `flags` should end up with, which lacks no-execute when the
+    /// processor doesn't have it turned on.
+    fn supported(flags: PageFlags) -> PageFlags {
+        if efer::read().contains(Efer::NO_EXECUTE_ENABLE) {
+            flags
+        } else {
+            flags - PageFlags::NO_EXECUTE
+        }
+    }
+
+    #[test_case]
+    fn no_execute_is_dropped_without_nxe() {
+        let frame = Frame(PhysAddr(0x1234_5000));
+        let flags = PageFlags::PRESENT | PageFlags::WRITABLE | PageFlags::NO_EXECUTE;
+
+        let entry = PageTableEntry::new_with_nx(flags, frame, false);
+        assert_eq!(entry.0 & 1 << 63, 0);
+        assert_eq!(entry.flags(), PageFlags::PRESENT | PageFlags::WRITABLE);
+        assert_eq!(entry.frame(), frame);
+
+        let entry = PageTableEntry::new_with_nx(flags, frame, true);
+        assert_eq!(entry.0 & 1 << 63, 1 << 63);
+        assert_eq!(entry.flags(), flags);
+    }
+
+    #[test_case]
+    fn entries_round_trip_frames() {
+        let flags = PageFlags::PRESENT | PageFlags::WRITABLE | PageFlags::NO_EXECUTE;
+        for addr in [0, 0x1000, 0x1234_5000, 0x8000_0000_0000, FRAME_MASK] {
+            let frame = Frame(PhysAddr(addr));
+            let entry = PageTableEntry::new(flags, frame);
+            assert_eq!(entry.frame(), frame);
+            assert_eq!(entry.flags(), supported(flags));
+        }
+    }
+
+    #[test_case]
+    fn frames_ignore_the_high_bits() {
+        // Bits 52 to 62 are ignored by the processor or hold a protection key, and bit 63 is
+        // no-execute. None of them are part of the address.
+        let high = 0x7ff0_0000_0000_0000 | PageFlags::NO_EXECUTE.bits();
+        let entry = PageTableEntry(high | 0x000f_ffff_ffff_f000 | PageFlags::PRESENT.bits());
+        assert_eq!(entry.frame(), Frame(PhysAddr(0x000f_ffff_ffff_f000)));
+        assert!(entry
+            .flags()
+            .contains(PageFlags::PRESENT | PageFlags::NO_EXECUTE));
+    }
+
+    #[test_case]
+    fn huge_leaf_frames_ignore_pat() {
+        // In a 2 MiB entry, bit 12 is the PAT bit rather than part of the address.
+        const PAT: u64 = 1 << 12;
+        let flags = PageFlags::PRESENT | PageFlags::HUGE_PAGE | PageFlags::NO_EXECUTE;
+        let entry = PageTableEntry(flags.bits() | PAT | 0x8000_0000_0000);
+        assert_eq!(
+            entry.leaf_frame(HUGE_PAGE_SIZE),
+            Frame(PhysAddr(0x8000_0000_0000))
+        );
+        assert_eq!(entry.flags(), flags);
+    }
+
+    #[test_case]
+    fn huge_pages_translate_and_refuse_unmapping() {
+        let mut scratch = Scratch::new();
+        let flags = PageFlags::PRESENT | PageFlags::WRITABLE;
+        unsafe {
+            scratch.mapper.map_huge_page_2m(
+                page(0x4000_0000),
+                Frame(PhysAddr(0x20_0000)),
+                flags,
+                &Global,
+            )
+        }
+        .unwrap();
+
+        let inside = page(0x4000_0000 + 0x12_3000);
+        assert_eq!(
+            scratch.mapper.translate_page(inside),
+            Some(Frame(PhysAddr(0x20_0000 + 0x12_3000)))
+        );
+        assert_eq!(scratch.mapper.translate_page(page(0x4020_0000)), None);
+
+        for page in [page(0x4000_0000), inside] {
+            let result = unsafe { scratch.mapper.unmap_page(page) };
+            assert!(matches!(result, Err(UnmapError::InsideHugePage)));
+        }
+        assert!(scratch.mapper.translate_page(inside).is_some());
+
+        // Unmapping a page at a time can't take the huge page down, so clear its entry here.
+        let (entry, size) = scratch.mapper.walk(0x4000_0000).unwrap();
+        assert_eq!(size, HUGE_PAGE_SIZE);
+        entry.set(PageTableEntry::missing());
+        unsafe { scratch.mapper.reclaim_tables(0x4000_0000, &Global) };
+    }
+
+    #[test_case]
+    fn translates_the_bootloader_hhdm() {
+        // Limine maps the HHDM, which always covers the first 4 GiB, with huge pages where it
+        // can.
+        let mapper = unsafe { PageMapper::active() };
+        for phys in [0x1234, 0x20_5678, 0x4000_0abc] {
+            let virt = mapper.hhdm.to_virtual::<u8>(PhysAddr(phys)).as_ptr() as usize;
+            let frame = mapper
+                .translate_page(Page(VirtAddr(virt & !0xfff)))
+                .expect("the hhdm covers the first 4 GiB");
+            assert_eq!(frame.0 + (virt & 0xfff) as u64, PhysAddr(phys));
+        }
+    }
+
+    #[test_case]
+    fn batches_flush_less_and_stay_coherent() {
+        const PAGES: usize = 2 * BATCH_FLUSH_THRESHOLD;
+        const BASE: usize = 0x5000_0000;
+        const NEW: u64 = 1 << 32;
+
+        let frames = |marker: u64| {
+            let mut frames = [Frame(PhysAddr(0)); PAGES];
+            for (i, frame) in frames.iter_mut().enumerate() {
+                let (allocated, ptr) = Global.allocate_frame_mapped().unwrap();
+                unsafe { ptr.as_ptr().cast::<u64>().write_volatile(marker | i as u64) };
+                *frame = allocated;
+            }
+            frames
+        };
+        let old = frames(0);
+        let new = frames(NEW);
+        let pages = || (0..PAGES).map(|i| page(BASE + i * 4096));
+        let read_all = || {
+            let mut values = [0; PAGES];
+            for (value, page) in values.iter_mut().zip(pages()) {
+                *value = unsafe { page.0.as_ptr().cast::<u64>().read_volatile() };
+            }
+            values
+        };
+
+        let mut scratch = Scratch::new();
+        let flags = PageFlags::PRESENT | PageFlags::WRITABLE | PageFlags::NO_EXECUTE;
+        let (unbatched, batched, before, after) = crate::interrupts::without(|| unsafe {
+            let kernel = cr3::read();
+
+            let start = tlb_flushes();
+            for (page, frame) in pages().zip(old) {
+                scratch
+                    .mapper
+                    .map_page(page, frame, flags, &Global)
+                    .unwrap();
+            }
+            let unbatched = tlb_flushes() - start;
+
+            // Reading every page pulls its translation into the TLB, so a missed flush would
+            // show up as a read from the old frame below.
+            scratch.mapper.activate();
+            let before = read_all();
+
+            let start = tlb_flushes();
+            {
+                let mut batch = MappingBatch::new(&mut scratch.mapper);
+                for (page, frame) in pages().zip(new) {
+                    batch.unmap_page(page).unwrap();
+                    batch.map_page(page, frame, flags, &Global).unwrap();
+                }
+            }
+            let batched = tlb_flushes() - start;
+            let after = read_all();
+
+            cr3::write(kernel);
+            (unbatched, batched, before, after)
+        });
+
+        assert!(unbatched >= PAGES as u64);
+        assert!(batched < unbatched, "{} >= {}", batched, unbatched);
+        for (i, (before, after)) in before.into_iter().zip(after).enumerate() {
+            assert_eq!(before, i as u64);
+            assert_eq!(after, NEW | i as u64);
+        }
+
+        drop(scratch);
+        for frame in old.into_iter().chain(new) {
+            unsafe { Global.deallocate_frame(frame) };
+        }
+    }
+
+    /// Hands out frames from [`Global`], remembering every frame given back.
+    #[derive(Default)]
+    struct RecordFrees {
+        freed: RefCell<Vec<Frame>>,
+    }
+
+    unsafe impl PhysicalMemoryAllocator for RecordFrees {
+        fn allocate_frame(&self) -> Result<Frame, PhysAllocError> {
+            Global.allocate_frame()
+        }
+
+        unsafe fn deallocate_frame(&self, frame: Frame) {
+            self.freed.borrow_mut().push(frame);
+            Global.deallocate_frame(frame);
+        }
+    }
+
+    #[test_case]
+    fn reclaim_frees_an_emptied_subtree() {
+        // The first page under an l4 entry that has nothing mapped yet.
+        const ADDR: usize = 0x100_0000_0000;
+
+        let mut scratch = Scratch::new();
+        let l4_index = VirtAddr(ADDR).page_table_index(3);
+        let l4_entry =
+            |scratch: &Scratch| unsafe { scratch.mapper.l4.as_ref() }.entries[l4_index].get();
+        assert!(!l4_entry(&scratch).flags().contains(PageFlags::PRESENT));
+
+        let flags = PageFlags::PRESENT | PageFlags::WRITABLE;
+        let frame = Frame(PhysAddr(0x10_0000));
+        unsafe { scratch.mapper.map_page(page(ADDR), frame, flags, &Global) }.unwrap();
+        let mut tables = scratch.table_entries(ADDR).map(|entry| entry.frame());
+
+        let recorder = RecordFrees::default();
+        let unmapped = unsafe { scratch.mapper.unmap_page_reclaim(page(ADDR), &recorder) };
+        assert_eq!(unmapped.unwrap(), frame);
+
+        // The l3, l2 and l1 tables went back, and nothing else did.
+        let mut freed = recorder.freed.take();
+        freed.sort();
+        tables.sort();
+        assert_eq!(freed, tables);
+        assert!(!l4_entry(&scratch).flags().contains(PageFlags::PRESENT));
+    }
 }
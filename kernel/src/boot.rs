@@ -1,6 +1,192 @@
-use limine::{BootInfoRequest, HhdmRequest, KernelAddressRequest, MemmapRequest};
+use core::{ptr::NonNull, slice};
+
+use limine::{
+    BootInfoRequest, FramebufferRequest, HhdmRequest, KernelAddressRequest, KernelFileRequest,
+    MemmapRequest, ModuleRequest, RsdpRequest,
+};
 
 pub static HHDM_REQUEST: HhdmRequest = HhdmRequest::new(0);
 pub static MEMMAP_REQUEST: MemmapRequest = MemmapRequest::new(0);
 pub static BOOTINFO_REQUEST: BootInfoRequest = BootInfoRequest::new(0);
 pub static KERNEL_ADDRESS_REQUEST: KernelAddressRequest = KernelAddressRequest::new(0);
+pub static KERNEL_FILE_REQUEST: KernelFileRequest = KernelFileRequest::new(0);
+pub static FRAMEBUFFER_REQUEST: FramebufferRequest = FramebufferRequest::new(0);
+pub static RSDP_REQUEST: RsdpRequest = RsdpRequest::new(0);
+pub static MODULE_REQUEST: ModuleRequest = ModuleRequest::new(0);
+
+/// Returns the kernel command line, or an empty string if the bootloader didn't pass one.
+pub fn cmdline() -> &'static str {
+    KERNEL_FILE_REQUEST
+        .get_response()
+        .get()
+        .and_then(|response| response.kernel_file.get())
+        .and_then(|file| file.cmdline.to_str())
+        .and_then(|cmdline| cmdline.to_str().ok())
+        .unwrap_or("")
+}
+
+/// Returns the options on the kernel command line as `(key, value)` pairs, in order.
+pub fn cmdline_options() -> impl Iterator<Item = (&'static str, &'static str)> {
+    parse_options(cmdline())
+}
+
+/// Splits a command line into whitespace-separated `key=value` options. An option without an
+/// `=` is a flag, and has an empty value.
+pub fn parse_options(cmdline: &str) -> impl Iterator<Item = (&str, &str)> {
+    cmdline
+        .split_ascii_whitespace()
+        .map(|option| option.split_once('=').unwrap_or((option, "")))
+}
+
+/// Returns the value of the first option on the command line with the given key.
+pub fn cmdline_option(key: &str) -> Option<&'static str> {
+    cmdline_options().find_map(|(k, value)| (k == key).then_some(value))
+}
+
+/// A file limine loaded alongside the kernel, e.g. an initrd.
+#[derive(Debug, Clone, Copy)]
+pub struct Module {
+    /// The module's contents, mapped in the HHDM.
+    pub address: NonNull<u8>,
+    pub len: usize,
+    /// The path limine loaded the module from.
+    pub path: &'static str,
+    /// The string given for the module in `limine.cfg`.
+    pub cmdline: &'static str,
+}
+
+impl Module {
+    pub fn bytes(&self) -> &'static [u8] {
+        // Module memory is never reclaimed, so it stays valid for the kernel's lifetime.
+        unsafe { slice::from_raw_parts(self.address.as_ptr(), self.len) }
+    }
+}
+
+/// Returns the modules limine loaded, in the order `limine.cfg` lists them.
+pub fn modules() -> impl Iterator<Item = Module> {
+    let files = MODULE_REQUEST
+        .get_response()
+        .get()
+        .map_or(&[][..], |response| response.modules());
+    files.iter().filter_map(|file| {
+        Some(Module {
+            address: NonNull::new(file.base.as_ptr()?)?,
+            len: file.length as usize,
+            path: file.path.to_str()?.to_str().ok()?,
+            cmdline: file
+                .cmdline
+                .to_str()
+                .and_then(|cmdline| cmdline.to_str().ok())
+                .unwrap_or(""),
+        })
+    })
+}
+
+/// The first framebuffer limine set up, with the layout checked for consistency.
+#[derive(Debug, Clone, Copy)]
+pub struct Framebuffer {
+    pub address: FramebufferMemory,
+    pub width: usize,
+    pub height: usize,
+    /// Bytes from the start of one scanline to the start of the next.
+    pub pitch: usize,
+    /// Bits per pixel.
+    pub bpp: u16,
+    /// 1 for RGB, the only memory model limine defines.
+    pub memory_model: u8,
+    pub red: ColorMask,
+    pub green: ColorMask,
+    pub blue: ColorMask,
+}
+
+/// Where a color channel sits within a pixel.
+#[derive(Debug, Clone, Copy)]
+pub struct ColorMask {
+    pub size: u8,
+    pub shift: u8,
+}
+
+/// The framebuffer's pixels, already mapped by limine.
+///
+/// Like MMIO, this memory may be uncached or write-combining and is read by the display behind
+/// the processor's back, so it must only be accessed with volatile reads and writes. That is why
+/// it is only handed out as a raw pointer.
+#[derive(Debug, Clone, Copy)]
+pub struct FramebufferMemory(NonNull<u8>);
+
+impl FramebufferMemory {
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.0.as_ptr()
+    }
+}
+
+/// Returns the first framebuffer, or `None` if limine didn't set one up or reported a layout
+/// that doesn't fit in its own pitch.
+pub fn framebuffer() -> Option<Framebuffer> {
+    let response = FRAMEBUFFER_REQUEST.get_response().get()?;
+    if response.framebuffer_count < 1 {
+        return None;
+    }
+    let framebuffer = response.framebuffers().first()?;
+
+    let width = framebuffer.width as usize;
+    let height = framebuffer.height as usize;
+    let pitch = framebuffer.pitch as usize;
+    if width == 0 || height == 0 || pitch < width * usize::from(framebuffer.bpp).div_ceil(8) {
+        return None;
+    }
+
+    Some(Framebuffer {
+        address: FramebufferMemory(NonNull::new(framebuffer.address.as_ptr()?)?),
+        width,
+        height,
+        pitch,
+        bpp: framebuffer.bpp,
+        memory_model: framebuffer.memory_model,
+        red: ColorMask {
+            size: framebuffer.red_mask_size,
+            shift: framebuffer.red_mask_shift,
+        },
+        green: ColorMask {
+            size: framebuffer.green_mask_size,
+            shift: framebuffer.green_mask_shift,
+        },
+        blue: ColorMask {
+            size: framebuffer.blue_mask_size,
+            shift: framebuffer.blue_mask_shift,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    #[test_case]
+    fn parses_options() {
+        let options: Vec<_> = parse_options("log=trace  quiet tick_hz=100\tinit=/bin/sh").collect();
+        assert_eq!(
+            options,
+            [
+                ("log", "trace"),
+                ("quiet", ""),
+                ("tick_hz", "100"),
+                ("init", "/bin/sh"),
+            ]
+        );
+    }
+
+    #[test_case]
+    fn option_values_may_contain_equals() {
+        let options: Vec<_> = parse_options("root=UUID=1234 empty=").collect();
+        assert_eq!(options, [("root", "UUID=1234"), ("empty", "")]);
+    }
+
+    #[test_case]
+    fn parses_an_empty_command_line() {
+        assert_eq!(parse_options("").count(), 0);
+        assert_eq!(parse_options("   ").count(), 0);
+    }
+}
@@ -1 +1,148 @@
+use core::{
+    cell::Cell,
+    fmt::{self, Write},
+    ops::ControlFlow,
+    panic::Location,
+};
+
+pub use self::symbols::symbolize;
+use crate::{interrupts, serial_port};
+
 pub mod backtrace;
+mod symbols;
+
+/// Like [`assert!`], but reports the failure straight to the serial port before panicking.
+#[macro_export]
+macro_rules! kassert {
+    ($cond:expr $(,)?) => {
+        $crate::kassert!($cond, "{}", stringify!($cond))
+    };
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            $crate::dbg::assert_failed(format_args!($($arg)+));
+        }
+    };
+}
+
+/// Like [`assert_eq!`], but reports both operands straight to the serial port before panicking.
+#[macro_export]
+macro_rules! kassert_eq {
+    ($left:expr, $right:expr $(,)?) => {
+        match (&$left, &$right) {
+            (left, right) => {
+                if !(*left == *right) {
+                    $crate::dbg::assert_failed(format_args!(
+                        "{}",
+                        $crate::dbg::EqFailure {
+                            left_expr: stringify!($left),
+                            right_expr: stringify!($right),
+                            left,
+                            right,
+                        }
+                    ));
+                }
+            }
+        }
+    };
+}
+
+/// The report for a failed [`kassert_eq!`]: both expressions and the values they had.
+#[doc(hidden)]
+pub struct EqFailure<'a> {
+    pub left_expr: &'static str,
+    pub right_expr: &'static str,
+    pub left: &'a dyn fmt::Debug,
+    pub right: &'a dyn fmt::Debug,
+}
+
+impl fmt::Display for EqFailure<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} == {}\n  left: {:?}\n right: {:?}",
+            self.left_expr, self.right_expr, self.left, self.right
+        )
+    }
+}
+
+/// Reports a failed kernel assertion and panics.
+///
+/// The message goes out through the emergency writer first, so the operands are recorded even
+/// if the panic handler itself can't make progress, e.g. because the heap or COM1 is wedged.
+#[cold]
+#[inline(never)]
+#[track_caller]
+pub fn assert_failed(args: fmt::Arguments) -> ! {
+    interrupts::disable();
+
+    let location = Location::caller();
+    let mut writer = serial_port::emergency_writer();
+    _ = writeln!(writer, "assertion failed at {}: {}", location, args);
+
+    panic!("assertion failed at {}", location);
+}
+
+/// Writes a backtrace of the caller, one `#N name+0xoffset` line per frame, innermost first.
+///
+/// The unwinder is tried first. If it can't find any frames, e.g. because the unwind tables are
+/// missing, the frame pointer chain is followed instead.
+pub fn write_backtrace(writer: &mut impl Write) -> fmt::Result {
+    let mut result = Ok(());
+    let index = Cell::new(0);
+    let mut visit = |frame: backtrace::Frame<'_>| {
+        result = write_frame(writer, index.get(), frame.ip());
+        index.set(index.get() + 1);
+        match result {
+            Ok(()) => ControlFlow::Continue(()),
+            Err(_) => ControlFlow::Break(()),
+        }
+    };
+    backtrace::trace(&mut visit);
+    if index.get() == 0 {
+        backtrace::trace_fp(&mut visit);
+    }
+    result
+}
+
+fn write_frame(writer: &mut impl Write, index: usize, ip: usize) -> fmt::Result {
+    // Return addresses point just past the call, which may already be in the next function, so
+    // look up the call instruction itself.
+    match symbolize(ip.wrapping_sub(1)) {
+        Some((name, offset)) => writeln!(
+            writer,
+            "#{} {:#}+{:#x}",
+            index,
+            rustc_demangle::demangle(name),
+            offset + 1
+        ),
+        None => writeln!(writer, "#{} {:#x}", index, ip),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+
+    #[test_case]
+    fn eq_failure_reports_both_operands() {
+        let (left, right) = (0x1000_u64, 0x2000_u64);
+        let failure = EqFailure {
+            left_expr: stringify!(frame.0),
+            right_expr: stringify!(expected),
+            left: &left,
+            right: &right,
+        };
+        assert_eq!(
+            failure.to_string(),
+            "frame.0 == expected\n  left: 4096\n right: 8192"
+        );
+    }
+
+    #[test_case]
+    fn kassert_eq_passes_on_equal_operands() {
+        crate::kassert_eq!(2 + 2, 4);
+        crate::kassert_eq!("ithaca", "ithaca");
+    }
+}
@@ -2,12 +2,22 @@ use core::{arch::asm, ffi::c_void, marker::PhantomData, ops::ControlFlow};
 
 use unwinding::abi::{UnwindContext, UnwindReasonCode, _Unwind_Backtrace, _Unwind_GetIP};
 
+use crate::types::VirtAddr;
+
 #[derive(Debug)]
 pub struct Frame<'a> {
     ip: usize,
     _p: PhantomData<&'a ()>,
 }
 
+impl Frame<'_> {
+    /// The frame's instruction pointer. For every frame but the innermost this is a return
+    /// address.
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+}
+
 pub fn trace<F>(mut f: F)
 where
     F: FnMut(Frame<'_>) -> ControlFlow<()>,
@@ -36,6 +46,54 @@ where
     }
 }
 
+/// Where the kernel image is linked, see `linker.ld`. Limine only slides the kernel upwards from
+/// here, so return addresses below this aren't in kernel code.
+pub const KERNEL_IMAGE_BASE: usize = 0xffff_ffff_8000_0000;
+
+/// Walks the stack by following the chain of saved frame pointers, which works without unwind
+/// tables, e.g. through naked functions and in the earliest boot code.
+///
+/// The kernel is built with `-Cforce-frame-pointers=yes`, so every Rust frame starts with the
+/// caller's `rbp` followed by the return address. The walk stops at a null frame pointer, which
+/// `_start` sets up, or at the first frame pointer or return address that doesn't look like it
+/// belongs to the kernel.
+#[inline(never)]
+pub fn trace_fp<F>(mut f: F)
+where
+    F: FnMut(Frame<'_>) -> ControlFlow<()>,
+{
+    let mut rbp = read_rbp();
+    while is_valid_frame_pointer(rbp) {
+        let (saved_rbp, ip) = unsafe {
+            let frame = rbp as *const usize;
+            (frame.read(), frame.add(1).read())
+        };
+        if ip < KERNEL_IMAGE_BASE {
+            break;
+        }
+
+        let frame = Frame {
+            ip,
+            _p: PhantomData,
+        };
+        if f(frame).is_break() {
+            break;
+        }
+
+        // Callers' frames are further up the stack. Anything else is a corrupted chain, and
+        // following it could loop forever.
+        if saved_rbp <= rbp {
+            break;
+        }
+        rbp = saved_rbp;
+    }
+}
+
+fn is_valid_frame_pointer(rbp: usize) -> bool {
+    rbp % 8 == 0 && VirtAddr(rbp).is_higher_half()
+}
+
+#[inline(always)]
 fn read_rbp() -> usize {
     let value;
     unsafe { asm!("mov {}, rbp", out(reg) value) };
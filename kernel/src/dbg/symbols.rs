@@ -0,0 +1,135 @@
+//! Symbol lookup in the kernel's own ELF file, which limine leaves mapped for us.
+
+use core::{mem::size_of, slice, str};
+
+use bytemuck::{Pod, Zeroable};
+use spin::Lazy;
+
+use super::backtrace::KERNEL_IMAGE_BASE;
+use crate::boot::{KERNEL_ADDRESS_REQUEST, KERNEL_FILE_REQUEST};
+
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+
+static KERNEL_SYMBOLS: Lazy<Option<SymbolTable>> = Lazy::new(|| {
+    let file = KERNEL_FILE_REQUEST
+        .get_response()
+        .get()?
+        .kernel_file
+        .get()?;
+    let base = file.base.as_ptr()?;
+    let bytes = unsafe { slice::from_raw_parts(base, file.length as usize) };
+
+    // With KASLR the kernel doesn't run where it was linked, while the symbol table still has
+    // the link addresses.
+    let virtual_base = KERNEL_ADDRESS_REQUEST.get_response().get()?.virtual_base;
+    let slide = virtual_base.wrapping_sub(KERNEL_IMAGE_BASE as u64);
+
+    SymbolTable::parse(bytes, slide)
+});
+
+/// Returns the name of the function containing `ip` and the offset of `ip` into it.
+///
+/// Names are returned as they appear in the symbol table, i.e. still mangled. This doesn't
+/// allocate, but the first call parses the kernel file.
+pub fn symbolize(ip: usize) -> Option<(&'static str, usize)> {
+    KERNEL_SYMBOLS.as_ref()?.lookup(ip)
+}
+
+#[derive(Debug)]
+struct SymbolTable {
+    symbols: &'static [Symbol],
+    names: &'static [u8],
+    /// How far the kernel was moved from its link address.
+    slide: u64,
+}
+
+impl SymbolTable {
+    fn parse(file: &'static [u8], slide: u64) -> Option<SymbolTable> {
+        let header: &ElfHeader =
+            bytemuck::try_from_bytes(file.get(..size_of::<ElfHeader>())?).ok()?;
+        if header.ident[..4] != *b"\x7fELF"
+            || usize::from(header.shentsize) != size_of::<SectionHeader>()
+        {
+            return None;
+        }
+
+        let start = header.shoff as usize;
+        let end = start + usize::from(header.shnum) * size_of::<SectionHeader>();
+        let sections: &[SectionHeader] = bytemuck::try_cast_slice(file.get(start..end)?).ok()?;
+
+        let symtab = sections.iter().find(|section| section.typ == SHT_SYMTAB)?;
+        let strtab = sections.get(symtab.link as usize)?;
+
+        Some(SymbolTable {
+            symbols: bytemuck::try_cast_slice(section_data(file, symtab)?).ok()?,
+            names: section_data(file, strtab)?,
+            slide,
+        })
+    }
+
+    fn lookup(&self, ip: usize) -> Option<(&'static str, usize)> {
+        let ip = (ip as u64).wrapping_sub(self.slide);
+        let symbol = self.symbols.iter().find(|symbol| {
+            symbol.info & 0xf == STT_FUNC
+                && (symbol.value..symbol.value + symbol.size.max(1)).contains(&ip)
+        })?;
+        Some((self.name(symbol)?, (ip - symbol.value) as usize))
+    }
+
+    fn name(&self, symbol: &Symbol) -> Option<&'static str> {
+        let names = self.names.get(symbol.name as usize..)?;
+        let len = names.iter().position(|&byte| byte == 0)?;
+        str::from_utf8(&names[..len]).ok()
+    }
+}
+
+fn section_data(file: &'static [u8], section: &SectionHeader) -> Option<&'static [u8]> {
+    let start = section.offset as usize;
+    file.get(start..start + section.size as usize)
+}
+
+#[derive(Debug, Clone, Copy, Zeroable, Pod)]
+#[repr(C)]
+struct ElfHeader {
+    ident: [u8; 16],
+    typ: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+#[derive(Debug, Clone, Copy, Zeroable, Pod)]
+#[repr(C)]
+struct SectionHeader {
+    name: u32,
+    typ: u32,
+    flags: u64,
+    addr: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    addralign: u64,
+    entsize: u64,
+}
+
+#[derive(Debug, Clone, Copy, Zeroable, Pod)]
+#[repr(C)]
+struct Symbol {
+    name: u32,
+    info: u8,
+    other: u8,
+    shndx: u16,
+    value: u64,
+    size: u64,
+}
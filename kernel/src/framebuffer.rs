@@ -0,0 +1,249 @@
+use core::{fmt, ptr::NonNull};
+
+use crate::boot::{self, ColorMask};
+
+mod font;
+
+const GLYPH_WIDTH: usize = 8;
+const GLYPH_HEIGHT: usize = 8;
+/// Each glyph row is drawn this many times, which makes the 8x8 font readable at common
+/// resolutions.
+const SCALE_Y: usize = 2;
+const LINE_HEIGHT: usize = GLYPH_HEIGHT * SCALE_Y;
+
+/// The limine memory model for linear RGB framebuffers, the only one it defines.
+const MEMORY_MODEL_RGB: u8 = 1;
+
+/// A text console drawn onto a linear 32-bpp framebuffer.
+#[derive(Debug)]
+pub struct Console {
+    base: NonNull<u8>,
+    /// Bytes from the start of one scanline to the start of the next.
+    pitch: usize,
+    width: usize,
+    height: usize,
+    columns: usize,
+    rows: usize,
+    column: usize,
+    row: usize,
+    foreground: u32,
+    background: u32,
+}
+
+// The framebuffer is only ever touched through the console that owns it.
+unsafe impl Send for Console {}
+
+impl Console {
+    /// Creates a console on the first framebuffer limine handed us, if there is one and it has a
+    /// layout we can draw to.
+    ///
+    /// # Safety
+    /// Nothing else may draw to that framebuffer for as long as the console is alive.
+    pub unsafe fn from_limine() -> Option<Console> {
+        let framebuffer = boot::framebuffer()?;
+
+        // This runs from inside the logger, so it can't log why a framebuffer is rejected.
+        if framebuffer.bpp != 32 || framebuffer.memory_model != MEMORY_MODEL_RGB {
+            return None;
+        }
+
+        let white =
+            channel(framebuffer.red) | channel(framebuffer.green) | channel(framebuffer.blue);
+
+        let mut console = Console::new(
+            NonNull::new(framebuffer.address.as_ptr())?,
+            framebuffer.width,
+            framebuffer.height,
+            framebuffer.pitch,
+        );
+        console.foreground = white;
+        console.clear();
+        Some(console)
+    }
+
+    /// Creates a white on black console. Pixels are 32 bits, and `pitch` is in bytes.
+    ///
+    /// # Safety
+    /// `base` must point to a mapped framebuffer of at least `height` scanlines of `pitch` bytes,
+    /// each with `width` pixels.
+    pub unsafe fn new(base: NonNull<u8>, width: usize, height: usize, pitch: usize) -> Console {
+        Console {
+            base,
+            pitch,
+            width,
+            height,
+            columns: width / GLYPH_WIDTH,
+            rows: height / LINE_HEIGHT,
+            column: 0,
+            row: 0,
+            foreground: 0x00ff_ffff,
+            background: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        for y in 0..self.height {
+            self.fill_scanline(y);
+        }
+        self.column = 0;
+        self.row = 0;
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        match byte {
+            b'\n' => self.newline(),
+            b'\r' => self.column = 0,
+            b'\t' => {
+                for _ in 0..4 - self.column % 4 {
+                    self.write_byte(b' ');
+                }
+            }
+            byte => {
+                if self.columns == 0 || self.rows == 0 {
+                    return;
+                }
+                if self.column == self.columns {
+                    self.newline();
+                }
+                self.draw_glyph(glyph(byte));
+                self.column += 1;
+            }
+        }
+    }
+
+    fn newline(&mut self) {
+        self.column = 0;
+        if self.row + 1 < self.rows {
+            self.row += 1;
+        } else {
+            self.scroll();
+        }
+    }
+
+    /// Moves every line up by one and blanks the bottom line.
+    ///
+    /// The copy is done a pixel at a time with volatile accesses, like every other framebuffer
+    /// access, since the framebuffer is device memory that the compiler mustn't reorder or elide
+    /// accesses to.
+    fn scroll(&mut self) {
+        for y in 0..(self.rows - 1) * LINE_HEIGHT {
+            self.copy_scanline(y + LINE_HEIGHT, y);
+        }
+        for y in (self.rows - 1) * LINE_HEIGHT..self.rows * LINE_HEIGHT {
+            self.fill_scanline(y);
+        }
+    }
+
+    fn draw_glyph(&mut self, glyph: &[u8; GLYPH_HEIGHT]) {
+        let x0 = self.column * GLYPH_WIDTH;
+        let y0 = self.row * LINE_HEIGHT;
+        for (i, bits) in glyph.iter().enumerate() {
+            for repeat in 0..SCALE_Y {
+                let y = y0 + i * SCALE_Y + repeat;
+                for dx in 0..GLYPH_WIDTH {
+                    let color = if bits & (1 << dx) != 0 {
+                        self.foreground
+                    } else {
+                        self.background
+                    };
+                    self.put_pixel(x0 + dx, y, color);
+                }
+            }
+        }
+    }
+
+    fn fill_scanline(&mut self, y: usize) {
+        for x in 0..self.width {
+            self.put_pixel(x, y, self.background);
+        }
+    }
+
+    fn copy_scanline(&mut self, from: usize, to: usize) {
+        debug_assert!(from < self.height && to < self.height);
+        unsafe {
+            let base = self.base.as_ptr();
+            let from = base.add(from * self.pitch).cast::<u32>();
+            let to = base.add(to * self.pitch).cast::<u32>();
+            for x in 0..self.width {
+                to.add(x).write_volatile(from.add(x).read_volatile());
+            }
+        }
+    }
+
+    fn put_pixel(&mut self, x: usize, y: usize, color: u32) {
+        debug_assert!(x < self.width && y < self.height);
+        unsafe {
+            let pixel = self.base.as_ptr().add(y * self.pitch + x * 4).cast::<u32>();
+            pixel.write_volatile(color);
+        }
+    }
+}
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            self.write_byte(if c.is_ascii() { c as u8 } else { b'?' });
+        }
+        Ok(())
+    }
+}
+
+/// Returns the glyph for an ASCII byte, or `?` for control characters the font doesn't cover.
+fn glyph(byte: u8) -> &'static [u8; GLYPH_HEIGHT] {
+    let index = match byte {
+        b' '..=b'~' => byte - b' ',
+        _ => b'?' - b' ',
+    };
+    &font::GLYPHS[index as usize]
+}
+
+/// Returns the pixel value with every bit of a color channel set.
+fn channel(mask: ColorMask) -> u32 {
+    let bits = 1u32
+        .checked_shl(mask.size.into())
+        .map_or(u32::MAX, |bit| bit - 1);
+    bits << mask.shift
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use super::*;
+
+    const WIDTH: usize = 2 * GLYPH_WIDTH;
+    const PITCH: usize = WIDTH * 4;
+    const HEIGHT: usize = 2 * LINE_HEIGHT;
+
+    fn pixel(pixels: &[u32], x: usize, y: usize) -> u32 {
+        pixels[y * WIDTH + x]
+    }
+
+    #[test_case]
+    fn scroll_moves_lines_up() {
+        let mut pixels: Vec<u32> = vec![0; WIDTH * HEIGHT];
+        let mut console = unsafe {
+            Console::new(
+                NonNull::new(pixels.as_mut_ptr().cast()).unwrap(),
+                WIDTH,
+                HEIGHT,
+                PITCH,
+            )
+        };
+
+        // The second row gets a full block, then a newline pushes it up to the first.
+        console.write_byte(b'\n');
+        for y in LINE_HEIGHT..HEIGHT {
+            for x in 0..WIDTH {
+                console.put_pixel(x, y, 0x00ff_ffff);
+            }
+        }
+        console.write_byte(b'\n');
+        drop(console);
+
+        assert_eq!(pixel(&pixels, 0, 0), 0x00ff_ffff);
+        assert_eq!(pixel(&pixels, WIDTH - 1, LINE_HEIGHT - 1), 0x00ff_ffff);
+        assert_eq!(pixel(&pixels, 0, LINE_HEIGHT), 0);
+        assert_eq!(pixel(&pixels, WIDTH - 1, HEIGHT - 1), 0);
+    }
+}
@@ -1,36 +1,128 @@
-use core::ptr::NonNull;
+use core::{mem, ptr::NonNull, slice};
 
+use bytemuck::Pod;
 use limine::HhdmRequest;
+use spin::Lazy;
 
-use crate::types::PhysAddr;
+use crate::{
+    pmm::{self, PhysicalMemoryAllocator},
+    types::{PhysAddr, VirtAddr},
+};
 
 static HHDM_REQUEST: HhdmRequest = HhdmRequest::new(0);
 
+/// How many bytes of physical memory the HHDM covers. Limine maps the first 4 GiB, and beyond
+/// that everything in the memory map.
+static HHDM_SIZE: Lazy<u64> = Lazy::new(|| {
+    let memmap_end = pmm::memmap_end().map_or(0, |end| end.0);
+    memmap_end.max(4 << 30)
+});
+
 #[derive(Debug, Clone)]
 pub struct Hhdm {
-    base: u64,
+    base: VirtAddr,
+    size: u64,
 }
 
 impl Hhdm {
     pub fn with_limine() -> Hhdm {
         Hhdm {
-            base: HHDM_REQUEST
-                .get_response()
-                .get()
-                .expect("failed to retrieve higher half mapping")
-                .offset,
+            base: VirtAddr(
+                HHDM_REQUEST
+                    .get_response()
+                    .get()
+                    .expect("failed to retrieve higher half mapping")
+                    .offset as usize,
+            ),
+            size: *HHDM_SIZE,
         }
     }
 
+    /// Returns where `phys` is mapped in the HHDM.
+    ///
+    /// Nothing is checked: `phys` must lie within the physical memory the HHDM covers, or the
+    /// pointer is dangling. Use [`Hhdm::try_to_virtual`] for addresses that aren't known to be
+    /// good.
     pub fn to_virtual<T>(&self, phys: PhysAddr) -> HigherHalf<T> {
-        let addr = phys.0 + self.base;
-        let ptr = unsafe { NonNull::new_unchecked(addr as usize as *mut T) };
+        let addr = self.base + phys.0 as usize;
+        let ptr = unsafe { NonNull::new_unchecked(addr.as_ptr().cast()) };
         HigherHalf(ptr)
     }
 
+    /// Like [`Hhdm::to_virtual`], but returns `None` unless the whole `T` at `phys` lies within
+    /// the HHDM.
+    pub fn try_to_virtual<T>(&self, phys: PhysAddr) -> Option<HigherHalf<T>> {
+        let end = phys.checked_add(mem::size_of::<T>() as u64)?;
+        (end.0 <= self.size).then(|| self.to_virtual(phys))
+    }
+
+    /// Returns the `len` values of `T` starting at `phys`, read through the HHDM.
+    ///
+    /// Panics if `phys` isn't aligned for `T` or the slice doesn't fit within the HHDM.
+    ///
+    /// # Safety
+    ///
+    /// Nothing may write to the memory while the slice is alive, whether through another mapping
+    /// or by DMA.
+    pub unsafe fn slice<'a, T: Pod>(&self, phys: PhysAddr, len: usize) -> &'a [T] {
+        assert_eq!(
+            phys.0 % mem::align_of::<T>() as u64,
+            0,
+            "unaligned slice at {:#x?}",
+            phys
+        );
+        let in_bounds = mem::size_of::<T>()
+            .checked_mul(len)
+            .and_then(|size| phys.checked_add(size as u64))
+            .is_some_and(|end| end.0 <= self.size);
+        assert!(
+            in_bounds,
+            "{} values at {:#x?} run past the end of the hhdm",
+            len, phys
+        );
+        slice::from_raw_parts(self.to_virtual::<T>(phys).as_ptr(), len)
+    }
+
     pub fn to_physical<T>(&self, addr: HigherHalf<T>) -> PhysAddr {
-        PhysAddr(addr.as_ptr() as usize as u64 - self.base)
+        let addr = VirtAddr(addr.as_ptr() as usize);
+        PhysAddr(addr.offset_from(self.base) as u64)
+    }
+}
+
+/// Checks that the higher half direct map reported by limine actually addresses physical memory.
+///
+/// A wrong offset otherwise only surfaces much later as faults on garbage data, so this panics
+/// early with a clear message instead.
+pub fn self_test(pmm: &impl PhysicalMemoryAllocator) {
+    const MAGIC: u64 = 0x1a7a_ca5e_d00d_f00d;
+
+    let hhdm = Hhdm::with_limine();
+    let frame = pmm
+        .allocate_frame()
+        .expect("hhdm self-test: failed to allocate a frame");
+
+    let ptr: HigherHalf<u64> = hhdm.to_virtual(frame.0);
+    let readback = unsafe {
+        ptr.as_ptr().write_volatile(MAGIC);
+        ptr.as_ptr().read_volatile()
+    };
+    assert_eq!(
+        readback, MAGIC,
+        "hhdm self-test: {:#x?} is not backed by {:#x?}, the hhdm offset is wrong",
+        ptr, frame
+    );
+
+    for phys in [PhysAddr(0), PhysAddr(0x1000), frame.0, frame.0 + 0xabc] {
+        let roundtrip = hhdm.to_physical(hhdm.to_virtual::<u8>(phys));
+        assert_eq!(
+            roundtrip, phys,
+            "hhdm self-test: {:#x?} did not survive a round trip through the hhdm",
+            phys
+        );
     }
+
+    unsafe { pmm.deallocate_frame(frame) };
+    log::debug!("hhdm self-test passed");
 }
 
 /// A [NonNull] that points to memory in the higher half of the address space.
@@ -58,3 +150,48 @@ impl<T> Clone for HigherHalf<T> {
 }
 
 impl<T> Copy for HigherHalf<T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn self_test_passes() {
+        self_test(&pmm::Global);
+    }
+
+    #[test_case]
+    fn addresses_round_trip() {
+        let hhdm = Hhdm::with_limine();
+        for phys in [0, 0x1000, 0x1234, 0xdead_b000, hhdm.size - 1] {
+            let phys = PhysAddr(phys);
+            assert_eq!(hhdm.to_physical(hhdm.to_virtual::<u8>(phys)), phys);
+        }
+    }
+
+    #[test_case]
+    fn try_to_virtual_stops_at_the_end() {
+        let hhdm = Hhdm::with_limine();
+        let end = PhysAddr(hhdm.size);
+        assert!(hhdm.try_to_virtual::<u8>(end - 1).is_some());
+        assert!(hhdm.try_to_virtual::<u8>(end).is_none());
+        assert!(hhdm.try_to_virtual::<u64>(end - 4).is_none());
+    }
+
+    #[test_case]
+    fn slice_reads_back_a_pattern() {
+        let (frame, ptr) = pmm::Global.allocate_frame_mapped().unwrap();
+        for i in 0..4096 {
+            unsafe { ptr.as_ptr().add(i).write_volatile(i as u8 ^ 0xa5) };
+        }
+
+        let hhdm = Hhdm::with_limine();
+        let bytes: &[u8] = unsafe { hhdm.slice(frame.0 + 16, 4080) };
+        assert_eq!(bytes.len(), 4080);
+        for (i, byte) in bytes.iter().enumerate() {
+            assert_eq!(*byte, (i + 16) as u8 ^ 0xa5);
+        }
+
+        unsafe { pmm::Global.deallocate_frame(frame) };
+    }
+}
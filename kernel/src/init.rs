@@ -0,0 +1,245 @@
+use core::fmt::{self, Debug, Write};
+
+use crate::{time, x86_64::tsc};
+
+/// A single step of kernel initialization.
+#[derive(Debug)]
+pub struct Stage {
+    pub name: &'static str,
+    /// Names of the stages that must have completed before this one runs.
+    pub after: &'static [&'static str],
+    pub run: unsafe fn() -> Result<(), StageError>,
+}
+
+/// The reason a stage failed, formatted up front so that reporting it never allocates.
+pub struct StageError {
+    message: [u8; 128],
+    len: usize,
+}
+
+impl StageError {
+    pub fn new(err: impl Debug) -> Self {
+        let mut this = Self {
+            message: [0; 128],
+            len: 0,
+        };
+        _ = write!(this, "{:?}", err);
+        this
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Truncation in `write_str` only ever happens on a char boundary.
+        core::str::from_utf8(&self.message[..self.len]).unwrap_or("<invalid>")
+    }
+}
+
+impl Debug for StageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Write for StageError {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let available = self.message.len() - self.len;
+        let mut n = s.len().min(available);
+        while !s.is_char_boundary(n) {
+            n -= 1;
+        }
+        self.message[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+/// An init stage that failed, or that could never run because of its dependencies.
+#[derive(Debug)]
+pub struct StageFailure {
+    pub name: &'static str,
+    pub error: StageError,
+    /// How many stages were left unrun.
+    pub skipped: usize,
+}
+
+impl fmt::Display for StageFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "init stage `{}` failed: {:?} ({} later stages not run)",
+            self.name, self.error, self.skipped
+        )
+    }
+}
+
+/// The most stages [`try_run`] can order.
+const MAX_STAGES: usize = 64;
+
+/// Runs `stages` in dependency order, logging how long each one took.
+///
+/// Each step runs the first stage in the list whose dependencies have all completed, so stages
+/// only move relative to the declared order when they have to. The first stage that fails, or
+/// that waits on a stage which is missing or part of a cycle, stops the sequence and every stage
+/// not yet run is skipped.
+pub unsafe fn try_run(stages: &[Stage]) -> Result<(), StageFailure> {
+    assert!(stages.len() <= MAX_STAGES, "too many init stages");
+
+    let mut done = [false; MAX_STAGES];
+    let completed = |done: &[bool; MAX_STAGES], name: &str| {
+        stages
+            .iter()
+            .zip(done)
+            .any(|(stage, done)| *done && stage.name == name)
+    };
+
+    for ran in 0..stages.len() {
+        let skipped = stages.len() - ran - 1;
+        let ready = stages
+            .iter()
+            .enumerate()
+            .find(|(i, stage)| !done[*i] && stage.after.iter().all(|dep| completed(&done, dep)));
+
+        let Some((i, stage)) = ready else {
+            // Nothing can run, so every remaining stage waits on something that never will.
+            let (stage, dependency) = stages
+                .iter()
+                .zip(&done)
+                .filter(|(_, done)| !**done)
+                .find_map(|(stage, _)| {
+                    let dependency = stage.after.iter().find(|dep| !completed(&done, dep))?;
+                    Some((stage, dependency))
+                })
+                .expect("a stage that can't run has an unmet dependency");
+            return Err(StageFailure {
+                name: stage.name,
+                error: StageError::new(format_args!("waits on `{}`, which never runs", dependency)),
+                skipped,
+            });
+        };
+
+        let start = tsc::read();
+        if let Err(error) = (stage.run)() {
+            return Err(StageFailure {
+                name: stage.name,
+                error,
+                skipped,
+            });
+        }
+        done[i] = true;
+
+        let cycles = tsc::read().wrapping_sub(start);
+        match time::tsc_frequency() {
+            Some(hz) => log::info!("init: {} ({}us)", stage.name, cycles * 1_000_000 / hz),
+            None => log::info!("init: {} ({} cycles)", stage.name, cycles),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use super::*;
+
+    static LATER_RAN: AtomicBool = AtomicBool::new(false);
+
+    unsafe fn succeeds() -> Result<(), StageError> {
+        Ok(())
+    }
+
+    unsafe fn fails() -> Result<(), StageError> {
+        Err(StageError::new("device missing"))
+    }
+
+    unsafe fn sets_flag() -> Result<(), StageError> {
+        LATER_RAN.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    #[test_case]
+    fn failure_aborts_later_stages() {
+        let stages = [
+            Stage {
+                name: "first",
+                after: &[],
+                run: succeeds,
+            },
+            Stage {
+                name: "broken",
+                after: &["first"],
+                run: fails,
+            },
+            Stage {
+                name: "later",
+                after: &[],
+                run: sets_flag,
+            },
+        ];
+
+        let failure = unsafe { try_run(&stages) }.unwrap_err();
+        assert!(!LATER_RAN.load(Ordering::SeqCst));
+        assert_eq!(failure.name, "broken");
+        assert_eq!(failure.error.as_str(), "\"device missing\"");
+        assert_eq!(failure.skipped, 1);
+
+        let message = failure.to_string();
+        assert!(message.contains("`broken`"));
+        assert!(message.contains("device missing"));
+    }
+
+    static ORDER: AtomicUsize = AtomicUsize::new(0);
+    static A_AT: AtomicUsize = AtomicUsize::new(0);
+    static B_AT: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe fn run_a() -> Result<(), StageError> {
+        A_AT.store(ORDER.fetch_add(1, Ordering::SeqCst), Ordering::SeqCst);
+        Ok(())
+    }
+
+    unsafe fn run_b() -> Result<(), StageError> {
+        B_AT.store(ORDER.fetch_add(1, Ordering::SeqCst), Ordering::SeqCst);
+        Ok(())
+    }
+
+    #[test_case]
+    fn stages_run_after_their_dependencies() {
+        let stages = [
+            Stage {
+                name: "b",
+                after: &["a"],
+                run: run_b,
+            },
+            Stage {
+                name: "a",
+                after: &[],
+                run: run_a,
+            },
+        ];
+
+        unsafe { try_run(&stages) }.unwrap();
+        assert_eq!(A_AT.load(Ordering::SeqCst), 0);
+        assert_eq!(B_AT.load(Ordering::SeqCst), 1);
+    }
+
+    #[test_case]
+    fn missing_dependency_is_reported() {
+        let stages = [
+            Stage {
+                name: "first",
+                after: &[],
+                run: succeeds,
+            },
+            Stage {
+                name: "orphan",
+                after: &["nowhere"],
+                run: succeeds,
+            },
+        ];
+
+        let failure = unsafe { try_run(&stages) }.unwrap_err();
+        assert_eq!(failure.name, "orphan");
+        assert!(failure.error.as_str().contains("`nowhere`"));
+        assert_eq!(failure.skipped, 0);
+    }
+}
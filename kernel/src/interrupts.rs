@@ -1,9 +1,15 @@
 pub mod x86_64;
 
+pub use self::x86_64::{counts, log_counts, register, Handler};
+
 pub unsafe fn init() {
     x86_64::init();
 }
 
+pub unsafe fn init_ap() {
+    x86_64::init_ap();
+}
+
 pub fn disable() {
     x86_64::disable();
 }
@@ -1,19 +1,137 @@
-use core::arch::asm;
+use core::{
+    arch::asm,
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
+use bitflags::bitflags;
 use spin::Lazy;
 
-use crate::x86_64::{
-    cr2,
-    idt::{Idt, RawGate},
-    RFlags,
+use crate::{
+    address_space::AddrSpace,
+    spinlock::Spinlock,
+    thread::Scheduler,
+    time,
+    trace::{self, Tag},
+    x86_64::{
+        apic::local::{self, ApicErrors},
+        cr2, gdt,
+        idt::{Idt, RawGate},
+        interrupts::{self as controller, Controller, InterruptController as _},
+        RFlags,
+    },
 };
 
 pub enum InterruptController {}
 
+bitflags! {
+    /// The error code pushed by a page fault.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct PageFaultErrorCode: u64 {
+        /// The page was present, so the access broke its protection. Otherwise the page was
+        /// not mapped.
+        const PROTECTION_VIOLATION = 1;
+        const CAUSED_BY_WRITE = 1 << 1;
+        const USER_MODE = 1 << 2;
+        /// A reserved bit was set in one of the page table entries.
+        const RESERVED_WRITE = 1 << 3;
+        const INSTRUCTION_FETCH = 1 << 4;
+    }
+}
+
+impl fmt::Display for PageFaultErrorCode {
+    /// Describes the fault, for example "write to non-present page in kernel mode".
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let access = if self.contains(Self::INSTRUCTION_FETCH) {
+            "instruction fetch from"
+        } else if self.contains(Self::CAUSED_BY_WRITE) {
+            "write to"
+        } else {
+            "read from"
+        };
+        let page = if self.contains(Self::PROTECTION_VIOLATION) {
+            "protected"
+        } else {
+            "non-present"
+        };
+        let mode = if self.contains(Self::USER_MODE) {
+            "user"
+        } else {
+            "kernel"
+        };
+        write!(f, "{} {} page in {} mode", access, page, mode)?;
+        if self.contains(Self::RESERVED_WRITE) {
+            f.write_str(" (reserved bit set in a page table entry)")?;
+        }
+        Ok(())
+    }
+}
+
 pub unsafe fn init() {
+    register(local::TIMER_VECTOR, timer_handler);
+    register(local::ERROR_VECTOR, apic_error_handler);
+    // Both come from the local APIC rather than an IRQ line.
+    controller::mark_routed(local::TIMER_VECTOR);
+    controller::mark_routed(local::ERROR_VECTOR);
     IDT.load();
 }
 
+/// Loads the IDT [`init`] built on the calling CPU, which must not be the one that ran [`init`].
+pub unsafe fn init_ap() {
+    IDT.load();
+}
+
+/// How many times each vector has fired.
+static COUNTS: [AtomicU64; 256] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; 256]
+};
+
+fn count(vector: u8) {
+    COUNTS[usize::from(vector)].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns how many times each vector has fired since boot.
+pub fn counts() -> [u64; 256] {
+    let mut counts = [0; 256];
+    for (count, counter) in counts.iter_mut().zip(&COUNTS) {
+        *count = counter.load(Ordering::Relaxed);
+    }
+    counts
+}
+
+/// Logs the count of every vector that has fired at least once.
+pub fn log_counts() {
+    for (vector, count) in counts().into_iter().enumerate() {
+        if count != 0 {
+            log::info!("vector {:>3}: {}", vector, count);
+        }
+    }
+}
+
+/// The vector user code raises with `int` to make a system call.
+pub const SYSCALL_VECTOR: u8 = 0x80;
+
+/// Handles an interrupt on a vector of 32 and up. It runs with interrupts disabled, and if the
+/// vector is [routed](crate::x86_64::interrupts::mark_routed) the interrupt is acknowledged once it returns.
+pub type Handler = fn(&StackFrame);
+
+static HANDLERS: Spinlock<[Option<Handler>; 224]> = Spinlock::new([None; 224]);
+
+/// Makes `handler` run for every interrupt on `vector`, replacing any handler already there.
+///
+/// The exception vectors below 32 have dedicated handlers and can't be registered.
+pub fn register(vector: u8, handler: Handler) {
+    assert!(vector >= 32, "vector {} is reserved for exceptions", vector);
+    assert!(
+        vector != SYSCALL_VECTOR,
+        "vector {} is reserved for syscalls",
+        vector
+    );
+    HANDLERS.lock(|handlers| handlers[usize::from(vector) - 32] = Some(handler));
+}
+
 pub unsafe fn enable() {
     unsafe { asm!("sti", options(nomem, nostack)) };
 }
@@ -50,6 +168,43 @@ pub struct StackFrame {
     pub ss: usize,
 }
 
+impl StackFrame {
+    /// Returns whether the interrupt arrived while executing user code, judged by the privilege
+    /// level of the saved code segment selector.
+    pub fn came_from_user(&self) -> bool {
+        self.cs & 0b11 == 3
+    }
+}
+
+/// Keeps the kernel's GS base active for the lifetime of a handler.
+///
+/// User code runs with its own GS base loaded, so a handler that interrupted it swaps the kernel
+/// one in on entry and swaps back on return. Kernel code already has the right GS base, so
+/// nothing is swapped there. Nothing in a handler's compiler-generated prologue touches GS,
+/// which is what makes doing this from the handler body (rather than a separate assembly
+/// trampoline) sound.
+struct KernelGs {
+    swapped: bool,
+}
+
+impl KernelGs {
+    unsafe fn enter(frame: &StackFrame) -> Self {
+        let swapped = frame.came_from_user();
+        if swapped {
+            asm!("swapgs", options(nomem, nostack, preserves_flags));
+        }
+        Self { swapped }
+    }
+}
+
+impl Drop for KernelGs {
+    fn drop(&mut self) {
+        if self.swapped {
+            unsafe { asm!("swapgs", options(nomem, nostack, preserves_flags)) };
+        }
+    }
+}
+
 fn build_idt() -> Idt {
     let mut idt = Idt {
         divide_error: RawGate::with_addr(divide_error_handler as usize),
@@ -80,80 +235,402 @@ fn build_idt() -> Idt {
 
         ..Idt::empty()
     };
-    idt.gates[0].set_addr(timer_handler as usize);
+    for (gate, trampoline) in idt.gates.iter_mut().zip(TRAMPOLINES) {
+        gate.set_addr(trampoline as usize);
+    }
+    idt.gates[usize::from(SYSCALL_VECTOR) - 32] =
+        RawGate::with_syscall_handler(syscall_handler as usize);
+
+    unsafe {
+        idt.double_fault.set_stack_index(gdt::DOUBLE_FAULT_IST);
+        idt.non_maskable_interrupt.set_stack_index(gdt::NMI_IST);
+        idt.machine_check.set_stack_index(gdt::MACHINE_CHECK_IST);
+    }
 
     idt
 }
 
-extern "x86-interrupt" fn divide_error_handler(_frame: StackFrame) {
-    todo!("divide error handling");
-}
-extern "x86-interrupt" fn debug_handler(_frame: StackFrame) {
-    todo!("debug handling");
-}
-extern "x86-interrupt" fn nmi_handler(_frame: StackFrame) {
-    todo!("non-maskable interrupt handling");
-}
-extern "x86-interrupt" fn breakpoint_handler(_frame: StackFrame) {
-    log::info!("BREAKPOINT");
-}
-extern "x86-interrupt" fn overflow_handler(_frame: StackFrame) {
-    todo!("overflow handling");
-}
-extern "x86-interrupt" fn bound_range_exceeded_handler(_frame: StackFrame) {
-    todo!("bound range exceeded handling");
-}
-extern "x86-interrupt" fn invalid_opcode_handler(_frame: StackFrame) {
-    todo!()
+/// Defines a handler for an exception the kernel doesn't handle yet.
+///
+/// Rather than a bare `todo!()`, the generated handler reports which exception fired, the
+/// interrupted context and which handler needs implementing.
+macro_rules! unhandled_exception {
+    ($handler:ident, $name:literal, $vector:literal) => {
+        extern "x86-interrupt" fn $handler(frame: StackFrame) -> ! {
+            unhandled_exception(
+                UnhandledException {
+                    name: $name,
+                    vector: $vector,
+                    handler: stringify!($handler),
+                },
+                &frame,
+                None,
+            )
+        }
+    };
+    ($handler:ident, $name:literal, $vector:literal, error_code) => {
+        extern "x86-interrupt" fn $handler(frame: StackFrame, error: u64) -> ! {
+            unhandled_exception(
+                UnhandledException {
+                    name: $name,
+                    vector: $vector,
+                    handler: stringify!($handler),
+                },
+                &frame,
+                Some(error),
+            )
+        }
+    };
 }
-extern "x86-interrupt" fn device_not_available_handler(_frame: StackFrame) {
-    todo!()
+
+/// An exception that fired without a real handler, and the handler that needs implementing.
+struct UnhandledException {
+    name: &'static str,
+    vector: u8,
+    handler: &'static str,
 }
-extern "x86-interrupt" fn double_fault_handler(_frame: StackFrame, _error: u64) -> ! {
-    panic!("DOUBLE FAULT");
+
+impl fmt::Display for UnhandledException {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unhandled exception: {} (vector {}), implement `interrupts::x86_64::{}` to handle it",
+            self.name, self.vector, self.handler
+        )
+    }
 }
-extern "x86-interrupt" fn invalid_tss_handler(_frame: StackFrame, _error: u64) {
-    todo!()
+
+fn unhandled_exception(exception: UnhandledException, frame: &StackFrame, error: Option<u64>) -> ! {
+    count(exception.vector);
+    log::error!(
+        "{} (vector {}) at {:#x}",
+        exception.name,
+        exception.vector,
+        frame.ip
+    );
+    log::error!("{:#x?}", frame);
+    if let Some(error) = error {
+        log::error!("error code: {:#x}", error);
+    }
+    panic!("{}", exception);
 }
-extern "x86-interrupt" fn segment_not_present_handler(_frame: StackFrame, _error: u64) {
-    todo!()
+
+unhandled_exception!(divide_error_handler, "DIVIDE ERROR", 0);
+unhandled_exception!(debug_handler, "DEBUG", 1);
+unhandled_exception!(nmi_handler, "NON-MASKABLE INTERRUPT", 2);
+
+extern "x86-interrupt" fn breakpoint_handler(frame: StackFrame) {
+    let _gs = unsafe { KernelGs::enter(&frame) };
+    count(3);
+    trace::emit(Tag::InterruptEntry, 3, frame.ip as u64);
+    log::info!("BREAKPOINT");
+    trace::emit(Tag::InterruptExit, 3, 0);
 }
-extern "x86-interrupt" fn stack_segment_fault_handler(_frame: StackFrame, _error: u64) {
-    todo!()
+
+unhandled_exception!(overflow_handler, "OVERFLOW", 4);
+unhandled_exception!(bound_range_exceeded_handler, "BOUND RANGE EXCEEDED", 5);
+unhandled_exception!(invalid_opcode_handler, "INVALID OPCODE", 6);
+unhandled_exception!(device_not_available_handler, "DEVICE NOT AVAILABLE", 7);
+
+/// Runs on its own interrupt stack (see [`gdt::DOUBLE_FAULT_IST`]), so that a kernel stack
+/// overflow, which leaves nowhere to push the page fault frame, still gets reported.
+extern "x86-interrupt" fn double_fault_handler(frame: StackFrame, _error: u64) -> ! {
+    // The saved state is architecturally undefined, but in practice it's the faulting context.
+    panic!(
+        "DOUBLE FAULT at {:#x} with stack pointer {:#x}",
+        frame.ip, frame.sp
+    );
 }
-extern "x86-interrupt" fn general_protection_fault_handler(_frame: StackFrame, error: u64) -> ! {
+
+unhandled_exception!(invalid_tss_handler, "INVALID TSS", 10, error_code);
+unhandled_exception!(
+    segment_not_present_handler,
+    "SEGMENT NOT PRESENT",
+    11,
+    error_code
+);
+unhandled_exception!(
+    stack_segment_fault_handler,
+    "STACK SEGMENT FAULT",
+    12,
+    error_code
+);
+
+extern "x86-interrupt" fn general_protection_fault_handler(frame: StackFrame, error: u64) -> ! {
+    let _gs = unsafe { KernelGs::enter(&frame) };
     panic!("GENERAL PROTECTION FAULT: {:#b}", error);
 }
-extern "x86-interrupt" fn page_fault_handler(_frame: StackFrame, error: u64) -> ! {
-    panic!("PAGE FAULT: {:#x?}: {:#b}", cr2::read(), error);
-}
-extern "x86-interrupt" fn x87_floating_point_handler(_frame: StackFrame) {
-    todo!()
-}
-extern "x86-interrupt" fn alignment_check_handler(_frame: StackFrame, _error: u64) {
-    todo!()
+extern "x86-interrupt" fn page_fault_handler(frame: StackFrame, error: u64) {
+    let _gs = unsafe { KernelGs::enter(&frame) };
+    count(14);
+    let addr = cr2::read();
+    let error = PageFaultErrorCode::from_bits_retain(error);
+
+    let result =
+        AddrSpace::with_active(|addr_space| unsafe { addr_space.handle_page_fault(addr, error) });
+    if result.is_err() {
+        panic!(
+            "PAGE FAULT at {:#x} (ip {:#x}): {}",
+            addr.0, frame.ip, error
+        );
+    }
 }
-extern "x86-interrupt" fn machine_check_handler(_frame: StackFrame) -> ! {
-    todo!()
+
+unhandled_exception!(x87_floating_point_handler, "X87 FLOATING POINT", 16);
+unhandled_exception!(alignment_check_handler, "ALIGNMENT CHECK", 17, error_code);
+unhandled_exception!(machine_check_handler, "MACHINE CHECK", 18);
+unhandled_exception!(simd_floating_point_handler, "SIMD FLOATING POINT", 19);
+unhandled_exception!(
+    virtualization_exception_handler,
+    "VIRTUALIZATION EXCEPTION",
+    20
+);
+unhandled_exception!(
+    control_protection_exception_handler,
+    "CONTROL PROTECTION EXCEPTION",
+    21,
+    error_code
+);
+unhandled_exception!(hypervisor_injection_handler, "HYPERVISOR INJECTION", 28);
+unhandled_exception!(
+    vmm_communication_handler,
+    "VMM COMMUNICATION",
+    29,
+    error_code
+);
+unhandled_exception!(security_handler, "SECURITY EXCEPTION", 30, error_code);
+
+/// Expands to the trampolines for the vectors of each given row of 16, in order.
+macro_rules! trampolines {
+    ($($row:literal)*) => {
+        [$(
+            trampoline::<{ $row * 16 }>,
+            trampoline::<{ $row * 16 + 1 }>,
+            trampoline::<{ $row * 16 + 2 }>,
+            trampoline::<{ $row * 16 + 3 }>,
+            trampoline::<{ $row * 16 + 4 }>,
+            trampoline::<{ $row * 16 + 5 }>,
+            trampoline::<{ $row * 16 + 6 }>,
+            trampoline::<{ $row * 16 + 7 }>,
+            trampoline::<{ $row * 16 + 8 }>,
+            trampoline::<{ $row * 16 + 9 }>,
+            trampoline::<{ $row * 16 + 10 }>,
+            trampoline::<{ $row * 16 + 11 }>,
+            trampoline::<{ $row * 16 + 12 }>,
+            trampoline::<{ $row * 16 + 13 }>,
+            trampoline::<{ $row * 16 + 14 }>,
+            trampoline::<{ $row * 16 + 15 }>,
+        )*]
+    };
 }
-extern "x86-interrupt" fn simd_floating_point_handler(_frame: StackFrame) {
-    todo!()
+
+/// One trampoline per vector from 32 up, since the vector isn't passed to the handler.
+static TRAMPOLINES: [extern "x86-interrupt" fn(StackFrame); 224] =
+    trampolines!(2 3 4 5 6 7 8 9 10 11 12 13 14 15);
+
+extern "x86-interrupt" fn trampoline<const VECTOR: u8>(frame: StackFrame) {
+    dispatch(VECTOR, &frame);
 }
-extern "x86-interrupt" fn virtualization_exception_handler(_frame: StackFrame) {
-    todo!()
+
+fn dispatch(vector: u8, frame: &StackFrame) {
+    let _gs = unsafe { KernelGs::enter(frame) };
+    count(vector);
+    trace::emit(Tag::InterruptEntry, vector.into(), frame.ip as u64);
+
+    match HANDLERS.lock(|handlers| handlers[usize::from(vector) - 32]) {
+        Some(handler) => handler(frame),
+        None => log::warn!("spurious interrupt on vector {}", vector),
+    }
+
+    // Software interrupts and the local APIC's own spurious interrupts must not be acknowledged,
+    // or the EOI would go to whatever interrupt is in service instead.
+    if controller::is_routed(vector) {
+        unsafe { end_of_interrupt(vector) };
+    }
+    trace::emit(Tag::InterruptExit, vector.into(), 0);
+
+    // This may switch to another thread, which can run for a long time before this one resumes
+    // and returns from the interrupt, so the interrupt must have been acknowledged already.
+    if vector == local::TIMER_VECTOR {
+        Scheduler::tick();
+    }
 }
-extern "x86-interrupt" fn control_protection_exception_handler(_frame: StackFrame) {
-    todo!()
+
+/// Entered through a trap gate, so interrupts stay as they were in the caller.
+extern "x86-interrupt" fn syscall_handler(frame: StackFrame) {
+    let _gs = unsafe { KernelGs::enter(&frame) };
+    count(SYSCALL_VECTOR);
+    log::debug!("syscall from {:#x}", frame.ip);
 }
-extern "x86-interrupt" fn hypervisor_injection_handler(_frame: StackFrame) {
-    todo!()
+
+fn timer_handler(_frame: &StackFrame) {
+    time::tick();
 }
-extern "x86-interrupt" fn vmm_communication_handler(_frame: StackFrame, _error: u64) {
-    todo!()
+
+fn apic_error_handler(_frame: &StackFrame) {
+    let errors = controller::with_controller(|controller| match controller {
+        Controller::Apic(apic) => unsafe { apic.local_apic().error_status() },
+        Controller::Pic(_) => ApicErrors::empty(),
+    });
+    log::error!("apic error: {:?}", errors.unwrap_or(ApicErrors::empty()));
 }
-extern "x86-interrupt" fn security_handler(_frame: StackFrame, _error: u64) {
-    todo!()
+
+/// Acknowledges the interrupt on `vector` so the controller delivers the next one.
+///
+/// This takes the lock around the installed controller, which can't deadlock against the code
+/// that was interrupted: [`Spinlock`] keeps interrupts disabled while it is held, so no
+/// interrupt can arrive on a CPU that holds the lock. Another CPU holding it only makes this
+/// wait until it is released.
+unsafe fn end_of_interrupt(vector: u8) {
+    debug_assert!(
+        !are_enabled(),
+        "end of interrupt outside an interrupt handler"
+    );
+    if controller::with_controller(|controller| controller.end_of_interrupt(vector)).is_none() {
+        log::warn!(
+            "interrupt on vector {} before any controller was installed",
+            vector
+        );
+    }
 }
-extern "x86-interrupt" fn timer_handler(_frame: StackFrame) {
-    log::info!("Timer!");
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+    use crate::x86_64::{rdmsr, wrmsr};
+
+    const IA32_GS_BASE: u32 = 0xc000_0101;
+    const IA32_KERNEL_GS_BASE: u32 = 0xc000_0102;
+
+    fn frame(cs: u16) -> StackFrame {
+        StackFrame {
+            ip: 0,
+            cs: cs.into(),
+            flags: 0,
+            sp: 0,
+            ss: 0,
+        }
+    }
+
+    #[test_case]
+    fn privilege_level_comes_from_cs() {
+        assert!(!frame(gdt::KERNEL_CODE.0).came_from_user());
+        assert!(frame(gdt::USER_CODE.0).came_from_user());
+        // Only the requested privilege level in the low two bits counts.
+        assert!(!frame(gdt::USER_CODE.0 & !0b11).came_from_user());
+        assert!(!frame(gdt::KERNEL_CODE.0 | 1).came_from_user());
+    }
+
+    #[test_case]
+    fn unhandled_exception_names_the_handler_to_write() {
+        let exception = UnhandledException {
+            name: "OVERFLOW",
+            vector: 4,
+            handler: stringify!(overflow_handler),
+        };
+        assert_eq!(
+            exception.to_string(),
+            "unhandled exception: OVERFLOW (vector 4), implement \
+             `interrupts::x86_64::overflow_handler` to handle it"
+        );
+    }
+
+    #[test_case]
+    fn kernel_gs_swaps_only_for_user_frames() {
+        const USER_GS: u64 = 0xffff_8000_dead_0000;
+
+        crate::interrupts::without(|| unsafe {
+            let kernel_gs = rdmsr(IA32_GS_BASE);
+            let saved = rdmsr(IA32_KERNEL_GS_BASE);
+            wrmsr(IA32_KERNEL_GS_BASE, USER_GS);
+
+            let from_kernel = KernelGs::enter(&frame(gdt::KERNEL_CODE.0));
+            let kernel_swapped = from_kernel.swapped;
+            let gs_in_kernel_handler = rdmsr(IA32_GS_BASE);
+            drop(from_kernel);
+
+            // Nothing may touch GS until the guard swaps it back, so the checks wait until then.
+            let from_user = KernelGs::enter(&frame(gdt::USER_CODE.0));
+            let user_swapped = from_user.swapped;
+            let gs_in_user_handler = rdmsr(IA32_GS_BASE);
+            drop(from_user);
+
+            let gs_after = rdmsr(IA32_GS_BASE);
+            let user_gs_after = rdmsr(IA32_KERNEL_GS_BASE);
+            wrmsr(IA32_KERNEL_GS_BASE, saved);
+
+            assert!(!kernel_swapped);
+            assert_eq!(gs_in_kernel_handler, kernel_gs);
+            assert!(user_swapped);
+            assert_eq!(gs_in_user_handler, USER_GS);
+            assert_eq!(gs_after, kernel_gs);
+            assert_eq!(user_gs_after, USER_GS);
+        });
+    }
+
+    static HANDLED: AtomicU64 = AtomicU64::new(0);
+
+    #[test_case]
+    fn registered_handlers_run() {
+        const VECTOR: u8 = 0xf4;
+
+        HANDLED.store(0, Ordering::Relaxed);
+        register(VECTOR, |_| {
+            HANDLED.fetch_add(1, Ordering::Relaxed);
+        });
+        // A software interrupt, which must not be acknowledged, so this also holds on the PIC.
+        assert!(!controller::is_routed(VECTOR));
+        unsafe { asm!("int {}", const VECTOR) };
+        assert_eq!(HANDLED.load(Ordering::Relaxed), 1);
+    }
+
+    #[test_case]
+    fn page_fault_error_codes_decode() {
+        let cases = [
+            (0b00000, "read from non-present page in kernel mode"),
+            (0b00010, "write to non-present page in kernel mode"),
+            (0b00111, "write to protected page in user mode"),
+            (
+                0b10101,
+                "instruction fetch from protected page in user mode",
+            ),
+            (
+                0b01001,
+                "read from protected page in kernel mode (reserved bit set in a page table entry)",
+            ),
+        ];
+        for (bits, description) in cases {
+            let error = PageFaultErrorCode::from_bits_retain(bits);
+            assert_eq!(error.to_string(), description, "error code {:#b}", bits);
+        }
+    }
+
+    #[test_case]
+    fn page_fault_error_code_bits_are_the_architectural_ones() {
+        let error = PageFaultErrorCode::from_bits_retain(0b10110);
+        assert_eq!(
+            error,
+            PageFaultErrorCode::CAUSED_BY_WRITE
+                | PageFaultErrorCode::USER_MODE
+                | PageFaultErrorCode::INSTRUCTION_FETCH
+        );
+        assert!(!error.contains(PageFaultErrorCode::PROTECTION_VIOLATION));
+        // Bits the type has no name for, such as protection keys, are kept.
+        assert_eq!(PageFaultErrorCode::from_bits_retain(1 << 5).bits(), 1 << 5);
+    }
+
+    #[test_case]
+    fn syscall_gate_is_a_ring_3_trap_gate() {
+        let syscall = IDT.gates[usize::from(SYSCALL_VECTOR) - 32].options();
+        assert_eq!(syscall.privilege_level(), 3);
+        assert!(syscall.is_trap_gate());
+
+        // Hardware vectors such as the timer's stay out of reach of ring 3 and run with interrupts
+        // off.
+        let timer = IDT.gates[usize::from(local::TIMER_VECTOR) - 32].options();
+        assert_eq!(timer.privilege_level(), 0);
+        assert!(!timer.is_trap_gate());
+    }
 }
@@ -1,10 +1,10 @@
 use core::{
-    alloc::GlobalAlloc,
+    alloc::{GlobalAlloc, Layout},
     num::NonZeroUsize,
     ptr::{self, NonNull},
 };
 
-use talc::{InitOnOom, Span, Talc};
+use talc::{OomHandler, Span, Talc};
 
 use crate::{
     address_space::{self, AddrSpace, KernelAddrSpaceNotInitializedError},
@@ -38,11 +38,12 @@ pub unsafe fn init() -> Result<(), InitGlobalAllocError> {
     const PAGES: usize = 10000;
 
     let addr_space = AddrSpace::kernel();
-    let memory = addr_space.allocate(NonZeroUsize::new(10000).unwrap())?;
-    let size = 10000 * 4096;
-    let span = Span::from_base_size(memory.as_ptr(), size);
+    let memory = addr_space.allocate(NonZeroUsize::new(PAGES).unwrap())?;
+    let span = Span::from_base_size(memory.as_ptr(), PAGES * 4096);
 
-    let talc = Talc::new(InitOnOom::new(span));
+    let mut talc = Talc::new(GrowOnOom);
+    talc.claim(span)
+        .expect("initial heap too small for the allocator's metadata");
 
     ALLOCATOR.inner.lock(|slot| {
         assert!(slot.is_none());
@@ -51,13 +52,46 @@ pub unsafe fn init() -> Result<(), InitGlobalAllocError> {
     Ok(())
 }
 
+/// Grows the heap with fresh pages from the kernel address space whenever it runs out.
+///
+/// This runs from inside `malloc`, with the allocator's lock held. Anything it calls that touched
+/// the heap would deadlock on that lock, so the path through [`AddrSpace::allocate`] must never
+/// allocate: it only takes frames from the pmm and regions from a bump allocator, and page tables
+/// come from the pmm too. For the same reason, nothing may allocate on the heap while holding the
+/// kernel address space's lock.
+#[derive(Debug)]
+struct GrowOnOom;
+
+impl GrowOnOom {
+    /// The least the heap grows by at once, so that a run of small allocations doesn't grow it
+    /// a page at a time.
+    const MIN_PAGES: usize = 256;
+}
+
+impl OomHandler for GrowOnOom {
+    fn handle_oom(talc: &mut Talc<Self>, layout: Layout) -> Result<(), ()> {
+        // Extra room for the allocator's own bookkeeping around the allocation.
+        let size = layout.size() + layout.align() + 4096;
+        let pages = size.div_ceil(4096).max(Self::MIN_PAGES);
+
+        let memory = AddrSpace::kernel()
+            .allocate(NonZeroUsize::new(pages).unwrap())
+            .map_err(|_| ())?;
+        log::debug!("growing the kernel heap by {} pages", pages);
+
+        let span = Span::from_base_size(memory.as_ptr(), pages * 4096);
+        unsafe { talc.claim(span) }?;
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 struct TalcWrapper {
-    inner: Spinlock<Option<Talc<InitOnOom>>>,
+    inner: Spinlock<Option<Talc<GrowOnOom>>>,
 }
 
 unsafe impl GlobalAlloc for TalcWrapper {
-    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         self.inner.lock(|talc| {
             if let Some(t) = talc {
                 t.malloc(layout)
@@ -69,7 +103,7 @@ unsafe impl GlobalAlloc for TalcWrapper {
         })
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         if let Some(ptr) = NonNull::new(ptr) {
             self.inner.lock(|talc| {
                 if let Some(a) = talc {
@@ -79,3 +113,22 @@ unsafe impl GlobalAlloc for TalcWrapper {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    #[test_case]
+    fn heap_grows_past_its_initial_size() {
+        // Bigger than the whole initial heap, so this can only succeed by growing it.
+        const SIZE: usize = 48 << 20;
+
+        let mut buffer = vec![0u8; SIZE];
+        let tail = &mut buffer[SIZE - 4096..];
+        for (i, byte) in tail.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        assert!(tail.iter().enumerate().all(|(i, byte)| *byte == i as u8));
+        assert_eq!(buffer[0], 0);
+    }
+}
@@ -1,84 +1,384 @@
 #![no_std]
 #![no_main]
-#![feature(abi_x86_interrupt, allocator_api, step_trait, naked_functions)]
+#![feature(
+    abi_x86_interrupt,
+    allocator_api,
+    step_trait,
+    naked_functions,
+    custom_test_frameworks
+)]
+#![test_runner(crate::testing::run)]
+#![reexport_test_harness_main = "test_main"]
 
 extern crate alloc;
 
 use core::{
     arch::asm,
     fmt::{self, Write},
-    iter::Step,
     panic::PanicInfo,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
 };
 
 use owo_colors::{style, OwoColorize};
 use serial_port::{SerialPort, SpinWriter};
-use spin::{
-    mutex::{SpinMutex, SpinMutexGuard},
-    Lazy,
-};
+use spin::Lazy;
 
 use crate::{
     address_space::{AddrSpace, MapOptions},
-    types::Frame,
+    framebuffer::Console,
+    init::{Stage, StageError},
+    spinlock::Spinlock,
     x86_64::{
-        apic::local::{LocalApic, X2Apic, XApic},
-        pic,
+        apic::{io::IoApic, local::LocalApicP},
+        fpu, gdt,
+        interrupts::{Apic, Controller, InterruptController, Pic},
+        percpu, pic, smp, syscall,
     },
 };
 
+mod acpi;
 mod address_space;
 mod boot;
 mod dbg;
+mod framebuffer;
 mod hhdm;
+mod init;
 mod interrupts;
 mod kernel_alloc;
 mod pmm;
+mod qemu;
 mod serial_port;
 mod spinlock;
+#[cfg(test)]
+mod testing;
 mod thread;
+mod time;
+mod trace;
 mod types;
 mod vmm;
 mod x86_64;
 
-static COM1: Lazy<SpinMutex<SpinWriter>> = Lazy::new(|| {
-    let port = unsafe { SerialPort::com1() };
-    let writer = SpinWriter::new(port);
-    SpinMutex::new(writer)
+/// The vector COM1's IRQ is routed to. It lines up with the legacy PIC's offset of 40, so it
+/// works with either interrupt controller.
+const COM1_VECTOR: u8 = 40 + serial_port::COM1_IRQ;
+
+static COM1: Lazy<Spinlock<SpinWriter>> = Lazy::new(|| {
+    let port = SerialPort::take_com1().expect("com1 already taken");
+    Spinlock::new(SpinWriter::new(port))
 });
+/// Set once [`COM1`] has been initialized. Until then, touching `COM1` may run its initializer,
+/// or spin forever waiting for an initializer that was interrupted by a panic.
+static COM1_READY: AtomicBool = AtomicBool::new(false);
+static COM2: Lazy<Spinlock<SpinWriter>> =
+    Lazy::new(|| Spinlock::new(SpinWriter::new(unsafe { SerialPort::com2() })));
+
+/// Where log output goes: COM1, unless the `log_port=com2` command line option moves it to COM2
+/// to keep COM1 free for other uses.
+static LOG_PORT: Lazy<&Lazy<Spinlock<SpinWriter>>> =
+    Lazy::new(|| match boot::cmdline_option("log_port") {
+        Some("com2") => &COM2,
+        _ => &COM1,
+    });
+
+/// A console on the display that log output is copied to, if limine gave us a framebuffer. The
+/// `log_fb=off` command line option keeps the display untouched.
+static LOG_CONSOLE: Lazy<Option<Spinlock<Console>>> =
+    Lazy::new(|| match boot::cmdline_option("log_fb") {
+        Some("off") => None,
+        _ => unsafe { Console::from_limine() }.map(Spinlock::new),
+    });
+
+/// The log level when the command line doesn't set one with `log=`.
+const DEFAULT_LOG_LEVEL: log::LevelFilter = log::LevelFilter::Debug;
+
+/// Returns the log level a `log=` option asks for, the default if there is none, or the option's
+/// value if it doesn't name a level.
+fn log_level(option: Option<&str>) -> Result<log::LevelFilter, &str> {
+    match option {
+        None => Ok(DEFAULT_LOG_LEVEL),
+        Some(value) => value.parse().map_err(|_| value),
+    }
+}
 
 fn kernel_main() {
+    Lazy::force(&COM1);
+    COM1_READY.store(true, Ordering::Release);
     log::set_logger(&Logger).ok();
-    log::set_max_level(log::LevelFilter::Debug);
+    let level = log_level(boot::cmdline_option("log"));
+    log::set_max_level(level.unwrap_or(DEFAULT_LOG_LEVEL));
     log::info!("Hello!");
+    if let Err(value) = level {
+        log::warn!("unknown log level {:?}, using {}", value, DEFAULT_LOG_LEVEL);
+    }
 
-    unsafe {
-        interrupts::init();
-        kernel_alloc::init().expect("failed to initialize global kernel allocator");
+    for module in boot::modules() {
+        log::info!("module {} ({} bytes)", module.path, module.len);
+    }
+
+    if let Err(failure) = unsafe { init::try_run(INIT_STAGES) } {
+        panic!("{}", failure);
+    }
+
+    #[cfg(test)]
+    test_main();
+
+    let usage = AddrSpace::kernel().virtual_usage();
+    log::info!(
+        "kernel virtual address space: {} pages used, {} remaining",
+        usage.used,
+        usage.remaining
+    );
+
+    loop {
+        unsafe { interrupts::enable() };
+        interrupts::wait();
+    }
+    log::info!("kernel exit");
+}
+
+static INIT_STAGES: &[Stage] = &[
+    // First, so that everything after it may use floating point and SIMD instructions.
+    Stage {
+        name: "fpu",
+        after: &[],
+        run: init_fpu,
+    },
+    Stage {
+        name: "hhdm self-test",
+        after: &[],
+        run: init_hhdm,
+    },
+    Stage {
+        name: "no-execute",
+        after: &[],
+        run: init_nxe,
+    },
+    Stage {
+        name: "global pages",
+        after: &[],
+        run: init_global_pages,
+    },
+    Stage {
+        name: "acpi",
+        after: &["hhdm self-test"],
+        run: init_acpi,
+    },
+    Stage {
+        name: "kernel allocator",
+        after: &["fpu", "hhdm self-test", "no-execute", "global pages"],
+        run: init_kernel_alloc,
+    },
+    Stage {
+        name: "gdt",
+        after: &["kernel allocator"],
+        run: init_gdt,
+    },
+    Stage {
+        name: "per-cpu data",
+        after: &["gdt"],
+        run: init_percpu,
+    },
+    Stage {
+        name: "syscall",
+        after: &["per-cpu data"],
+        run: init_syscall,
+    },
+    Stage {
+        name: "interrupts",
+        after: &["gdt"],
+        run: init_interrupts,
+    },
+    Stage {
+        name: "time",
+        after: &[],
+        run: init_time,
+    },
+    Stage {
+        name: "pic",
+        after: &["interrupts", "time"],
+        run: init_pic,
+    },
+    Stage {
+        name: "interrupt controller",
+        after: &["kernel allocator", "pic"],
+        run: init_interrupt_controller,
+    },
+    Stage {
+        name: "timer",
+        after: &["interrupt controller"],
+        run: init_timer,
+    },
+    Stage {
+        name: "serial input",
+        after: &["interrupt controller"],
+        run: init_serial_input,
+    },
+    Stage {
+        name: "smp",
+        after: &["interrupt controller", "syscall", "acpi"],
+        run: init_smp,
+    },
+];
+
+unsafe fn init_fpu() -> Result<(), StageError> {
+    fpu::init();
+    Ok(())
+}
+
+unsafe fn init_hhdm() -> Result<(), StageError> {
+    hhdm::self_test(&pmm::Global);
+    Ok(())
+}
+
+unsafe fn init_acpi() -> Result<(), StageError> {
+    // Machines without ACPI still boot, they just can't use anything found through it.
+    match acpi::tables() {
+        Ok(tables) => {
+            for table in tables {
+                log::debug!(
+                    "acpi table {} at {:#x?}",
+                    core::str::from_utf8(&table.signature()).unwrap_or("????"),
+                    table.phys
+                );
+            }
+        }
+        Err(err) => log::warn!("acpi unavailable: {:?}", err),
+    }
+    Ok(())
+}
+
+unsafe fn init_nxe() -> Result<(), StageError> {
+    if !x86_64::enable_nxe() {
+        log::warn!("no-execute pages are unsupported, all mappings will be executable");
     }
+    Ok(())
+}
+
+unsafe fn init_global_pages() -> Result<(), StageError> {
+    if !x86_64::enable_global_pages() {
+        log::warn!("global pages are unsupported, kernel mappings will be flushed on cr3 reloads");
+    }
+    Ok(())
+}
+
+unsafe fn init_gdt() -> Result<(), StageError> {
+    gdt::setup_cpu(0).map_err(StageError::new)
+}
+
+unsafe fn init_percpu() -> Result<(), StageError> {
+    percpu::init(0);
+    assert_eq!(
+        percpu::current().cpu_id,
+        0,
+        "per-cpu data unreachable through gs"
+    );
+    Ok(())
+}
+
+unsafe fn init_syscall() -> Result<(), StageError> {
+    syscall::init();
+    Ok(())
+}
+
+unsafe fn init_interrupts() -> Result<(), StageError> {
+    interrupts::init();
+    Ok(())
+}
+
+unsafe fn init_kernel_alloc() -> Result<(), StageError> {
+    kernel_alloc::init().map_err(StageError::new)
+}
+
+unsafe fn init_time() -> Result<(), StageError> {
+    time::init();
+    Ok(())
+}
+
+unsafe fn init_pic() -> Result<(), StageError> {
+    pic::init(40, 48);
+    pic::write_masks([0xff, 0xff]);
+    // pic::init(32, 40);
+    // pic::write_masks([0xfe, 0xff]);
+    Ok(())
+}
+
+unsafe fn init_interrupt_controller() -> Result<(), StageError> {
+    let controller = match init_apic() {
+        Ok(apic) => Controller::Apic(apic),
+        Err(err) => {
+            log::warn!("apic unavailable, using the legacy pic: {:?}", err);
+            Controller::Pic(Pic::with_offsets(40, 48))
+        }
+    };
+    x86_64::interrupts::install(controller);
+    Ok(())
+}
+
+unsafe fn init_timer() -> Result<(), StageError> {
+    let hz = match boot::cmdline_option("tick_hz").map(str::parse::<u32>) {
+        None => time::DEFAULT_TICK_HZ,
+        Some(Ok(hz)) if hz != 0 => hz,
+        Some(_) => {
+            log::warn!(
+                "invalid tick rate {:?}, using {} Hz",
+                boot::cmdline_option("tick_hz"),
+                time::DEFAULT_TICK_HZ
+            );
+            time::DEFAULT_TICK_HZ
+        }
+    };
+    if let Err(err) = time::set_tick_hz(hz) {
+        log::warn!("no programmable timer, running without ticks: {:?}", err);
+    }
+    Ok(())
+}
+
+unsafe fn init_serial_input() -> Result<(), StageError> {
+    // Make sure COM1 has been programmed before its interrupt can fire, and that it is really
+    // there: without a UART, input would never arrive and output has gone nowhere all along.
+    if let Err(err) = COM1.lock(|writer| writer.port_mut().self_test()) {
+        log::warn!(
+            "com1 failed its self-test, serial input disabled: {:?}",
+            err
+        );
+        return Ok(());
+    }
+    interrupts::register(COM1_VECTOR, |_| serial_port::handle_com1_interrupt());
+    x86_64::interrupts::with_controller(|controller| {
+        controller.set_vector(serial_port::COM1_IRQ, COM1_VECTOR);
+        controller.unmask(serial_port::COM1_IRQ);
+    })
+    .ok_or_else(|| StageError::new("no interrupt controller installed"))
+}
+
+unsafe fn init_smp() -> Result<(), StageError> {
+    // Everything still runs on this processor, so failing to start the others isn't fatal.
+    match smp::boot_all() {
+        Ok(booted) => log::info!(
+            "{} of {} application processors online",
+            booted.online,
+            booted.found
+        ),
+        Err(err) => log::warn!("application processors not started: {:?}", err),
+    }
+    Ok(())
+}
+
+unsafe fn init_apic() -> Result<Apic, StageError> {
+    let mut lapic = LocalApicP::detect().map_err(StageError::new)?;
+    lapic.calibrate(&mut time::TscClock);
 
     let map_options = MapOptions {
         writable: true,
-        disable_cache: true,
         ..Default::default()
     };
-    let addr = XApic::physical_address();
-    let frame = Frame(addr);
-    let frame_range_end = Step::forward(frame, 1);
-    let local_apic_address = AddrSpace::kernel()
-        .map_frames(frame..frame_range_end, map_options)
-        .unwrap();
+    let io_apic_phys = IoApic::physical_address().map_err(StageError::new)?;
+    let io_apic_address = AddrSpace::kernel()
+        .map_mmio(io_apic_phys, 0x20, map_options)
+        .map_err(StageError::new)?;
+    let io_apic = IoApic::with_address(io_apic_address.cast());
 
-    unsafe {
-        pic::init(40, 48);
-        pic::write_masks([0xff, 0xff]);
-
-        let xapic = XApic::with_address(local_apic_address.cast());
-        let mut lapic = LocalApic::enable(xapic).unwrap();
-        lapic.enable_timer();
-        // pic::init(32, 40);
-        // pic::write_masks([0xfe, 0xff]);
-    }
     // let mut local_apic = unsafe {
     //     LocalApicBuilder::with_addresses(addr, ptr.cast())
     //         .finish()
@@ -87,12 +387,7 @@ fn kernel_main() {
 
     // local_apic.enable_timer();
     // log::info!("{:#x?}", local_apic);
-
-    loop {
-        unsafe { interrupts::enable() };
-        interrupts::wait();
-    }
-    log::info!("kernel exit");
+    Ok(Apic::new(io_apic, lapic))
 }
 
 #[derive(Debug)]
@@ -112,80 +407,98 @@ impl log::Log for Logger {
             log::Level::Debug => level_style.blue(),
             log::Level::Trace => level_style.white(),
         };
-        let mut writer = DbgWriter::lock();
-        _ = writeln!(
-            writer,
-            "[{}][{}] {}",
-            record.level().style(level_style),
-            record.target().bold(),
-            record.args()
-        );
+
+        // Interrupt handlers log too, so nothing here may be interrupted on this core, not even
+        // the first use of the lazily set up writers: a handler would spin forever on a lock or
+        // `Lazy` the code it interrupted holds. This also keeps a line logged from an interrupt
+        // from landing between the port and console copies of another.
+        interrupts::without(|| {
+            let timestamp = Timestamp(time::uptime());
+            LOG_PORT.lock(|writer| {
+                _ = writeln!(
+                    writer,
+                    "{}[{}][{}] {}",
+                    timestamp,
+                    record.level().style(level_style),
+                    record.target().bold(),
+                    record.args()
+                );
+            });
+            // The console can't show colors, so it gets a plain copy of the line.
+            if let Some(console) = &*LOG_CONSOLE {
+                console.lock(|console| {
+                    _ = writeln!(
+                        console,
+                        "{}[{}][{}] {}",
+                        timestamp,
+                        record.level(),
+                        record.target(),
+                        record.args()
+                    );
+                });
+            }
+        });
     }
 
     fn flush(&self) {}
 }
 
+/// Formats as `[   12.345678]`: seconds and microseconds since boot.
+struct Timestamp(Duration);
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{:5}.{:06}]", self.0.as_secs(), self.0.subsec_micros())
+    }
+}
+
 #[no_mangle]
 unsafe extern "C" fn _start() -> ! {
     asm!("xor rbp, rbp");
 
     kernel_main();
 
-    // // Ensure we got a framebuffer.
-    // if let Some(framebuffer_response) = FRAMEBUFFER_REQUEST.get_response().get() {
-    //     if framebuffer_response.framebuffer_count < 1 {
-    //         hcf();
-    //     }
-
-    //     // Get the first framebuffer's information.
-    //     let framebuffer = &framebuffer_response.framebuffers()[0];
-
-    //     for i in 0..100_usize {
-    //         // Calculate the pixel offset using the framebuffer information we obtained above.
-    //         // We skip `i` scanlines (pitch is provided in bytes) and add `i * 4` to skip `i` pixels forward.
-    //         let pixel_offset = i * framebuffer.pitch as usize + i * 4;
-
-    //         // Write 0xFFFFFFFF to the provided pixel offset to fill it white.
-    //         // We can safely unwrap the result of `as_ptr()` because the framebuffer address is
-    //         // guaranteed to be provided by the bootloader.
-    //         unsafe {
-    //             *(framebuffer
-    //                 .address
-    //                 .as_ptr()
-    //                 .unwrap()
-    //                 .offset(pixel_offset as isize) as *mut u32) = 0xffffffff;
-    //         }
-    //     }
-    // }
-
     hcf();
 }
 
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
 #[panic_handler]
 fn rust_panic(info: &PanicInfo) -> ! {
     interrupts::disable();
 
-    let mut writer = DbgWriter::lock();
+    // A panic while reporting a panic, e.g. from a corrupted stack during the backtrace, would
+    // otherwise recurse until the stack overflows.
+    if PANICKING.swap(true, Ordering::Relaxed) {
+        hcf();
+    }
 
-    _ = writeln!(writer, "{}", "KERNEL PANIC".bold().red());
-    _ = writeln!(writer, "{}", info);
+    // The panic may have happened while COM1 was held, or while it was still being set up, in
+    // which case waiting for it would deadlock. Write straight to the port instead and accept
+    // that output may interleave.
+    let writer = COM1_READY
+        .load(Ordering::Acquire)
+        .then(|| COM1.try_lock())
+        .flatten();
+    match writer {
+        Some(mut writer) => report_panic(&mut *writer, info),
+        None => report_panic(&mut serial_port::emergency_writer(), info),
+    }
 
-    hcf();
-}
-struct DbgWriter {
-    com1: SpinMutexGuard<'static, SpinWriter>,
-}
+    #[cfg(test)]
+    qemu::exit(qemu::ExitCode::Failure);
 
-impl DbgWriter {
-    fn lock() -> DbgWriter {
-        DbgWriter { com1: COM1.lock() }
-    }
+    hcf();
 }
 
-impl fmt::Write for DbgWriter {
-    fn write_str(&mut self, s: &str) -> fmt::Result {
-        self.com1.write_str(s)
-    }
+fn report_panic(writer: &mut impl Write, info: &PanicInfo) {
+    _ = writeln!(writer, "{}", "KERNEL PANIC".bold().red());
+    _ = writeln!(writer, "{}", info);
+    // Nothing below allocates, so this still works if the heap is what's broken.
+    _ = writeln!(writer, "{}", "backtrace:".bold());
+    _ = dbg::write_backtrace(writer);
+    _ = writeln!(writer, "{}", "recent events:".bold());
+    _ = trace::dump(writer);
 }
 
 fn hcf() -> ! {
@@ -196,3 +509,62 @@ fn hcf() -> ! {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::AtomicUsize;
+
+    use super::*;
+    use crate::thread::Scheduler;
+
+    #[test_case]
+    fn log_level_defaults_without_an_option() {
+        assert_eq!(log_level(None), Ok(DEFAULT_LOG_LEVEL));
+    }
+
+    #[test_case]
+    fn log_level_parses_level_names() {
+        assert_eq!(log_level(Some("trace")), Ok(log::LevelFilter::Trace));
+        assert_eq!(log_level(Some("WARN")), Ok(log::LevelFilter::Warn));
+        assert_eq!(log_level(Some("off")), Ok(log::LevelFilter::Off));
+    }
+
+    #[test_case]
+    fn log_level_rejects_unknown_levels() {
+        assert_eq!(log_level(Some("loud")), Err("loud"));
+        assert_eq!(log_level(Some("")), Err(""));
+    }
+
+    const COM2_LINES: usize = 8;
+
+    static COM2_WRITTEN: AtomicUsize = AtomicUsize::new(0);
+
+    fn write_com2() {
+        for line in 0..COM2_LINES {
+            COM2.lock(|com2| writeln!(com2, "com2 line {}", line))
+                .unwrap();
+            COM2_WRITTEN.fetch_add(1, Ordering::Relaxed);
+            Scheduler::yield_now();
+        }
+    }
+
+    #[test_case]
+    fn com1_and_com2_write_concurrently() {
+        COM2_WRITTEN.store(0, Ordering::Relaxed);
+        Scheduler::spawn(write_com2).unwrap();
+
+        // Each side yields after every line, so the two ports' writes interleave.
+        let mut com1_lines = 0;
+        while COM2_WRITTEN.load(Ordering::Relaxed) < COM2_LINES {
+            assert!(com1_lines < 1000, "the com2 writer stopped making progress");
+            COM1.lock(|com1| writeln!(com1, "com1 line {}", com1_lines))
+                .unwrap();
+            com1_lines += 1;
+            Scheduler::yield_now();
+        }
+
+        // Each port has its own lock, so both can be held at once.
+        COM1.lock(|com1| COM2.lock(|com2| writeln!(com1, "com1").and(writeln!(com2, "com2"))))
+            .unwrap();
+    }
+}
@@ -1,41 +1,84 @@
-use core::{ops::Range, slice};
+use core::{iter::Step, num::NonZeroUsize, ops::Range, ptr::NonNull};
+#[cfg(feature = "debug_poison")]
+use core::{mem, slice};
 
 use limine::{MemmapEntry, MemmapRequest, MemoryMapEntryType, NonNullPtr};
 
 use crate::{
     hhdm::{Hhdm, HigherHalf},
     spinlock::Spinlock,
+    trace::{self, Tag},
     types::{Frame, PhysAddr},
 };
 
+mod bitmap;
+
+pub use self::bitmap::Bitmap;
+
 static GLOBAL: Spinlock<Option<GlobalInner>> = Spinlock::new(None);
+static MEMMAP_REQUEST: MemmapRequest = MemmapRequest::new(0);
+
+/// The most memory map regions of one type the allocator keeps track of.
+const MAX_REGIONS: usize = 64;
+
+/// Memory below 1 MiB is kept out of the allocator, for the few things that need an address real
+/// mode code can reach, like the startup code for application processors.
+pub const LOW_MEMORY_END: u64 = 0x10_0000;
 
 #[derive(Debug, Default, Clone, Copy)]
 pub struct Global;
 
+impl Global {
+    /// Allocates a frame and returns it together with a pointer to it through the allocator's
+    /// own higher half mapping.
+    pub fn allocate_frame_mapped(&self) -> Result<(Frame, NonNull<u8>), PhysAllocError> {
+        let result = with_global(|global| {
+            let frame = global.allocate_frame()?;
+            let ptr = global.hhdm.to_virtual(frame.0).as_nonnull();
+            Ok((frame, ptr))
+        });
+        if let Ok((frame, ptr)) = result {
+            trace::emit(Tag::FrameAlloc, frame.0 .0, 0);
+            log::trace!("allocated frame {:#x?} at {:p}", frame, ptr);
+        }
+        result
+    }
+
+    /// Hands the bootloader-reclaimable memory regions to the allocator.
+    ///
+    /// Limine's responses, including the memory map itself, live in that memory. Nothing may
+    /// read any limine response after this is called, which includes anything that looks up the
+    /// HHDM offset or kernel address through limine. Because of that, the kernel doesn't call
+    /// this yet.
+    pub unsafe fn reclaim_bootloader_memory(&self) -> Result<(), PhysAllocError> {
+        with_global(|global| {
+            global.reclaim_bootloader_memory();
+            Ok(())
+        })
+    }
+}
+
 unsafe impl PhysicalMemoryAllocator for Global {
     fn allocate_frame(&self) -> Result<Frame, PhysAllocError> {
-        let frame = GLOBAL.lock(|global| {
-            let global = match global {
-                Some(v) => v,
-                None => {
-                    let inner = GlobalInner::with_limine().ok_or(PhysAllocError)?;
-                    global.insert(inner)
-                }
-            };
-
-            if let Some(frame) = global.freelist_pop() {
-                return Ok(frame);
-            }
-            global.memmap_pop().ok_or(PhysAllocError)
-        });
+        let frame = with_global(|global| global.allocate_frame());
         if let Ok(frame) = frame {
+            trace::emit(Tag::FrameAlloc, frame.0 .0, 0);
             log::trace!("allocated frame {:#x?}", frame);
         }
         frame
     }
 
+    fn allocate_frames(&self, count: NonZeroUsize) -> Result<Range<Frame>, PhysAllocError> {
+        let frames = with_global(|global| global.allocate_frames(count));
+        if let Ok(frames) = &frames {
+            trace::emit(Tag::FrameAlloc, frames.start.0 .0, count.get() as u64);
+            log::trace!("allocated frames {:#x?}", frames);
+        }
+        frames
+    }
+
     unsafe fn deallocate_frame(&self, frame: Frame) {
+        trace::emit(Tag::FrameFree, frame.0 .0, 0);
         GLOBAL.lock(|global| {
             let global = global.as_mut().expect("deallocation prior to pmm init");
             global.freelist_push(frame);
@@ -43,60 +86,201 @@ unsafe impl PhysicalMemoryAllocator for Global {
     }
 }
 
+/// Returns the end of the highest region in the memory map, of any type.
+pub fn memmap_end() -> Option<PhysAddr> {
+    let response = MEMMAP_REQUEST.get_response().get()?;
+    response
+        .memmap()
+        .iter()
+        .map(|entry| PhysAddr(entry.base + entry.len))
+        .max()
+}
+
+/// Returns the first run of `count` usable frames below [`LOW_MEMORY_END`], other than the
+/// frame at zero.
+///
+/// The allocator never hands these frames out, but nothing stops two callers from being given
+/// the same run either, so each use needs to be coordinated with the others.
+pub fn low_memory(count: usize) -> Option<Frame> {
+    let response = MEMMAP_REQUEST.get_response().get()?;
+    let size = count as u64 * 4096;
+    response
+        .memmap()
+        .iter()
+        .filter(|entry| entry.typ == MemoryMapEntryType::Usable)
+        .find_map(|entry| {
+            let start = entry.base.next_multiple_of(4096).max(4096);
+            let end = (entry.base + entry.len).min(LOW_MEMORY_END);
+            (start + size <= end).then_some(Frame(PhysAddr(start)))
+        })
+}
+
+fn with_global<F, T>(f: F) -> Result<T, PhysAllocError>
+where
+    F: FnOnce(&mut GlobalInner) -> Result<T, PhysAllocError>,
+{
+    GLOBAL.lock(|global| {
+        let global = match global {
+            Some(v) => v,
+            None => {
+                let inner = GlobalInner::with_limine().ok_or(PhysAllocError)?;
+                global.insert(inner)
+            }
+        };
+        f(global)
+    })
+}
+
 #[derive(Debug)]
 pub struct PhysAllocError;
 
 pub unsafe trait PhysicalMemoryAllocator {
     fn allocate_frame(&self) -> Result<Frame, PhysAllocError>;
     unsafe fn deallocate_frame(&self, frame: Frame);
+
+    /// Allocates `count` physically contiguous frames.
+    ///
+    /// The default implementation allocates frames one at a time and fails if they don't happen
+    /// to be contiguous, so allocators should override it where they can do better.
+    fn allocate_frames(&self, count: NonZeroUsize) -> Result<Range<Frame>, PhysAllocError> {
+        let start = self.allocate_frame()?;
+        let mut frames = start..Step::forward(start, 1);
+
+        while Step::steps_between(&frames.start, &frames.end) < Some(count.get()) {
+            match self.allocate_frame() {
+                Ok(frame) if frame == frames.end => frames.end = Step::forward(frame, 1),
+                result => {
+                    unsafe {
+                        if let Ok(frame) = result {
+                            self.deallocate_frame(frame);
+                        }
+                        self.deallocate_frames(frames);
+                    }
+                    return Err(PhysAllocError);
+                }
+            }
+        }
+        Ok(frames)
+    }
+
+    /// Frees each frame of `frames` individually.
+    unsafe fn deallocate_frames(&self, frames: Range<Frame>) {
+        for frame in frames {
+            self.deallocate_frame(frame);
+        }
+    }
 }
 
 struct GlobalInner {
     hhdm: Hhdm,
     free: Option<HigherHalf<Node>>,
     current: Range<u64>,
-    entries: slice::Iter<'static, NonNullPtr<MemmapEntry>>,
+    regions: Regions,
 }
 
 unsafe impl Send for GlobalInner {}
 
 impl GlobalInner {
     pub fn with_limine() -> Option<Self> {
-        static REQUEST: MemmapRequest = MemmapRequest::new(0);
-        let response = REQUEST.get_response().get()?;
-        let entries = response.memmap().iter();
+        let response = MEMMAP_REQUEST.get_response().get()?;
+
+        // Copy the usable regions out, so that the allocator keeps working once the memory
+        // holding the limine response has been reclaimed.
+        let mut regions = Regions::collect(response.memmap(), MemoryMapEntryType::Usable);
+        regions.remove_below(LOW_MEMORY_END);
 
         Some(Self {
             hhdm: Hhdm::with_limine(),
             free: None,
             current: 0..0,
-            entries,
+            regions,
         })
     }
 
-    fn memmap_pop(&mut self) -> Option<Frame> {
-        while (self.current.end - self.current.start) < 4096 {
-            let entry = self.entries.next()?;
-            if entry.typ != MemoryMapEntryType::Usable {
-                continue;
+    unsafe fn reclaim_bootloader_memory(&mut self) {
+        let Some(response) = MEMMAP_REQUEST.get_response().get() else {
+            return;
+        };
+
+        // The memory map is itself in reclaimable memory, so it must be read in full before the
+        // first frame is pushed.
+        let reclaimable =
+            Regions::collect(response.memmap(), MemoryMapEntryType::BootloaderReclaimable);
+
+        let mut reclaimed = 0;
+        for region in reclaimable {
+            for addr in region.step_by(4096) {
+                self.freelist_push(Frame(PhysAddr(addr)));
+                reclaimed += 1;
+            }
+        }
+        log::info!("reclaimed {} KiB of bootloader memory", reclaimed * 4);
+    }
+
+    fn allocate_frame(&mut self) -> Result<Frame, PhysAllocError> {
+        if let Some(frame) = self.freelist_pop() {
+            return Ok(frame);
+        }
+        self.memmap_pop().ok_or(PhysAllocError)
+    }
+
+    /// Carves a contiguous run out of the memory map. The freelist is never used for this, since
+    /// nothing keeps its frames in order.
+    ///
+    /// Usable regions too small for the request are moved to the freelist whole, so that they
+    /// still get handed out for single frame allocations.
+    fn allocate_frames(&mut self, count: NonZeroUsize) -> Result<Range<Frame>, PhysAllocError> {
+        let size = (count.get() as u64)
+            .checked_mul(4096)
+            .ok_or(PhysAllocError)?;
+
+        while self.current.end - self.current.start < size {
+            while let Some(frame) = self.memmap_pop_current() {
+                unsafe { self.freelist_push(frame) };
             }
-            self.current = entry.base..entry.base + entry.len;
+            self.current = self.regions.next().ok_or(PhysAllocError)?;
         }
 
+        let start = Frame(PhysAddr(self.current.start));
+        self.current.start += size;
+        Ok(start..Frame(PhysAddr(self.current.start)))
+    }
+
+    /// Takes a frame from the memory map region currently being handed out, if it has any left.
+    fn memmap_pop_current(&mut self) -> Option<Frame> {
+        if self.current.end - self.current.start < 4096 {
+            return None;
+        }
         let addr = PhysAddr(self.current.start);
         self.current.start += 4096;
         Some(Frame(addr))
     }
 
+    fn memmap_pop(&mut self) -> Option<Frame> {
+        loop {
+            if let Some(frame) = self.memmap_pop_current() {
+                return Some(frame);
+            }
+            self.current = self.regions.next()?;
+        }
+    }
+
     fn freelist_pop(&mut self) -> Option<Frame> {
         let head = self.free.take()?;
+        #[cfg(feature = "debug_poison")]
+        unsafe {
+            check_poison(head)
+        };
         self.free = unsafe { (*head.as_ptr()).next };
         let phys = self.hhdm.to_physical(head);
         Some(Frame(phys))
     }
 
     unsafe fn freelist_push(&mut self, frame: Frame) {
+        crate::kassert_eq!(frame.0 .0 % 4096, 0);
         let ptr: HigherHalf<Node> = self.hhdm.to_virtual(frame.0);
+        #[cfg(feature = "debug_poison")]
+        poison(ptr);
         unsafe {
             (*ptr.as_ptr()).next = self.free;
         }
@@ -104,8 +288,138 @@ impl GlobalInner {
     }
 }
 
+/// The pattern freed frames are filled with: the bytes `de ad be ef`, repeated.
+#[cfg(feature = "debug_poison")]
+const POISON: u32 = u32::from_le_bytes([0xde, 0xad, 0xbe, 0xef]);
+
+#[cfg(feature = "debug_poison")]
+unsafe fn poison(node: HigherHalf<Node>) {
+    let words = slice::from_raw_parts_mut(node.as_ptr().cast::<u32>(), 1024);
+    words.fill(POISON);
+}
+
+/// Panics if anything but the freelist link was written to a frame since it was freed.
+#[cfg(feature = "debug_poison")]
+unsafe fn check_poison(node: HigherHalf<Node>) {
+    let words = slice::from_raw_parts(node.as_ptr().cast::<u32>(), 1024);
+    let link_words = mem::size_of::<Option<HigherHalf<Node>>>() / 4;
+
+    if let Some(offset) = words[link_words..].iter().position(|word| *word != POISON) {
+        let offset = (link_words + offset) * 4;
+        crate::kassert!(
+            false,
+            "use after free: frame at {:p} modified at offset {:#x}",
+            node.as_ptr(),
+            offset
+        );
+    }
+}
+
+/// A queue of memory map regions, copied out of the limine response.
+struct Regions {
+    ranges: [Range<u64>; MAX_REGIONS],
+    next: usize,
+    len: usize,
+}
+
+impl Regions {
+    fn collect(memmap: &[NonNullPtr<MemmapEntry>], typ: MemoryMapEntryType) -> Self {
+        const EMPTY: Range<u64> = 0..0;
+        let mut regions = Regions {
+            ranges: [EMPTY; MAX_REGIONS],
+            next: 0,
+            len: 0,
+        };
+
+        for entry in memmap.iter().filter(|entry| entry.typ == typ) {
+            let Some(slot) = regions.ranges.get_mut(regions.len) else {
+                log::warn!("too many {:?} memory regions, ignoring the rest", typ);
+                break;
+            };
+            *slot = entry.base..entry.base + entry.len;
+            regions.len += 1;
+        }
+        regions
+    }
+
+    /// Trims every region so that none of it lies below `addr`.
+    fn remove_below(&mut self, addr: u64) {
+        for range in &mut self.ranges[..self.len] {
+            range.start = range.start.max(addr).min(range.end);
+        }
+    }
+}
+
+impl Iterator for Regions {
+    type Item = Range<u64>;
+
+    fn next(&mut self) -> Option<Range<u64>> {
+        let range = self.ranges[..self.len].get(self.next)?.clone();
+        self.next += 1;
+        Some(range)
+    }
+}
+
 #[repr(C, align(4096))]
 #[derive(Debug, Default)]
 struct Node {
     next: Option<HigherHalf<Node>>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn allocate_frame_mapped_points_at_the_frame() {
+        let (frame, ptr) = Global.allocate_frame_mapped().unwrap();
+        assert_eq!(frame.0 .0 % 4096, 0);
+        assert!(frame.0 .0 >= LOW_MEMORY_END);
+
+        let hhdm = Hhdm::with_limine();
+        assert_eq!(ptr, hhdm.to_virtual::<u8>(frame.0).as_nonnull());
+
+        let words = ptr.as_ptr().cast::<u64>();
+        for i in 0..512 {
+            unsafe {
+                words
+                    .add(i)
+                    .write_volatile(0x5eed_0000_0000_0000 | i as u64)
+            };
+        }
+        let readback: HigherHalf<u64> = hhdm.to_virtual(frame.0);
+        for i in 0..512 {
+            let word = unsafe { readback.as_ptr().add(i).read_volatile() };
+            assert_eq!(word, 0x5eed_0000_0000_0000 | i as u64);
+        }
+
+        unsafe { Global.deallocate_frame(frame) };
+    }
+
+    #[test_case]
+    fn allocate_frames_is_contiguous() {
+        let count = NonZeroUsize::new(16).unwrap();
+        let frames = Global.allocate_frames(count).unwrap();
+        assert_eq!(Step::steps_between(&frames.start, &frames.end), Some(16));
+        assert_eq!(frames.start.0 .0 % 4096, 0);
+
+        let addrs: [u64; 16] = core::array::from_fn(|i| Step::forward(frames.start, i).0 .0);
+        for pair in addrs.windows(2) {
+            assert_eq!(pair[1] - pair[0], 4096);
+        }
+        assert_eq!(addrs[15] + 4096, frames.end.0 .0);
+
+        // Every frame of the run must be backed by its own memory.
+        let hhdm = Hhdm::with_limine();
+        for (i, frame) in frames.clone().enumerate() {
+            let ptr: HigherHalf<u64> = hhdm.to_virtual(frame.0);
+            unsafe { ptr.as_ptr().write_volatile(i as u64) };
+        }
+        for (i, frame) in frames.clone().enumerate() {
+            let ptr: HigherHalf<u64> = hhdm.to_virtual(frame.0);
+            assert_eq!(unsafe { ptr.as_ptr().read_volatile() }, i as u64);
+        }
+
+        unsafe { Global.deallocate_frames(frames) };
+    }
+}
@@ -0,0 +1,268 @@
+use core::{num::NonZeroUsize, ops::Range, slice};
+
+use limine::MemoryMapEntryType;
+
+use super::{PhysAllocError, PhysicalMemoryAllocator, LOW_MEMORY_END, MEMMAP_REQUEST};
+use crate::{
+    hhdm::Hhdm,
+    spinlock::Spinlock,
+    types::{Frame, PhysAddr},
+};
+
+/// A physical allocator tracking every frame with one bit, set while the frame is in use.
+///
+/// Unlike the freelist behind [`super::Global`], this can tell whether a particular frame is
+/// free and hand out that exact frame. It manages the same usable memory as `Global`, including
+/// the frames `Global` has already handed out, so the two are mutually exclusive: a kernel uses
+/// one or the other as its frame allocator, and never builds a bitmap once `Global` is in use.
+///
+/// Like `Global`, it never hands out memory below [`LOW_MEMORY_END`].
+#[derive(Debug)]
+pub struct Bitmap {
+    inner: Spinlock<BitmapInner>,
+}
+
+#[derive(Debug)]
+struct BitmapInner {
+    words: &'static mut [u64],
+    /// The number of frames tracked, starting from physical address zero.
+    frames: usize,
+    /// The word to start searching from, below which every frame is known to be allocated.
+    hint: usize,
+}
+
+unsafe impl Send for BitmapInner {}
+
+impl Bitmap {
+    /// Builds a bitmap covering memory up to the end of the highest usable region.
+    ///
+    /// The bitmap is stored at the start of the first usable region with room for it above
+    /// [`LOW_MEMORY_END`], and those frames are marked allocated.
+    pub fn with_limine() -> Option<Self> {
+        let response = MEMMAP_REQUEST.get_response().get()?;
+        let usable = || {
+            response
+                .memmap()
+                .iter()
+                .filter(|entry| entry.typ == MemoryMapEntryType::Usable)
+                .map(|entry| entry.base..entry.base + entry.len)
+        };
+
+        let end = usable().map(|range| range.end).max()?;
+        let frames = (end / 4096) as usize;
+        let words = frames.div_ceil(64);
+        let storage_size = (words * 8).next_multiple_of(4096) as u64;
+
+        let storage = usable()
+            .map(|range| range.start.max(LOW_MEMORY_END)..range.end)
+            .find(|range| range.end.saturating_sub(range.start) >= storage_size)?
+            .start;
+        let words = unsafe {
+            let ptr = Hhdm::with_limine()
+                .to_virtual::<u64>(PhysAddr(storage))
+                .as_ptr();
+            slice::from_raw_parts_mut(ptr, words)
+        };
+
+        let mut inner = BitmapInner::new(
+            words,
+            frames,
+            usable().map(|range| Frame(PhysAddr(range.start))..Frame(PhysAddr(range.end))),
+        );
+        inner.set_range(
+            Frame(PhysAddr(storage))..Frame(PhysAddr(storage + storage_size)),
+            true,
+        );
+
+        Some(Self {
+            inner: Spinlock::new(inner),
+        })
+    }
+
+    /// Allocates exactly `frame`, failing if it is already in use or isn't usable memory.
+    pub fn allocate_specific(&self, frame: Frame) -> Result<(), PhysAllocError> {
+        self.inner.lock(|inner| {
+            if !inner.is_free(frame) {
+                return Err(PhysAllocError);
+            }
+            inner.set(frame, true);
+            Ok(())
+        })
+    }
+
+    pub fn is_free(&self, frame: Frame) -> bool {
+        self.inner.lock(|inner| inner.is_free(frame))
+    }
+}
+
+unsafe impl PhysicalMemoryAllocator for Bitmap {
+    fn allocate_frame(&self) -> Result<Frame, PhysAllocError> {
+        self.inner.lock(|inner| {
+            let index = inner.find_free(inner.hint).ok_or(PhysAllocError)?;
+            inner.hint = index / 64;
+            let frame = Frame(PhysAddr(index as u64 * 4096));
+            inner.set(frame, true);
+            Ok(frame)
+        })
+    }
+
+    unsafe fn deallocate_frame(&self, frame: Frame) {
+        self.inner.lock(|inner| {
+            crate::kassert!(!inner.is_free(frame), "double free of {:x?}", frame);
+            inner.set(frame, false);
+            inner.hint = inner.hint.min(BitmapInner::index(frame) / 64);
+        });
+    }
+
+    fn allocate_frames(&self, count: NonZeroUsize) -> Result<Range<Frame>, PhysAllocError> {
+        self.inner.lock(|inner| {
+            let mut start = inner.find_free(inner.hint).ok_or(PhysAllocError)?;
+            loop {
+                let end = start.checked_add(count.get()).ok_or(PhysAllocError)?;
+                if end > inner.frames {
+                    return Err(PhysAllocError);
+                }
+
+                let frames =
+                    Frame(PhysAddr(start as u64 * 4096))..Frame(PhysAddr(end as u64 * 4096));
+                match frames.clone().find(|frame| !inner.is_free(*frame)) {
+                    Some(used) => {
+                        let next = BitmapInner::index(used) + 1;
+                        start = inner.find_free(next / 64).ok_or(PhysAllocError)?.max(next);
+                    }
+                    None => {
+                        for frame in frames.clone() {
+                            inner.set(frame, true);
+                        }
+                        return Ok(frames);
+                    }
+                }
+            }
+        })
+    }
+}
+
+impl BitmapInner {
+    /// Marks every frame allocated except those in `usable`, then reserves low memory again.
+    fn new(
+        words: &'static mut [u64],
+        frames: usize,
+        usable: impl Iterator<Item = Range<Frame>>,
+    ) -> Self {
+        words.fill(u64::MAX);
+        let mut inner = Self {
+            words,
+            frames,
+            hint: 0,
+        };
+        for range in usable {
+            inner.set_range(range, false);
+        }
+        inner.set_range(Frame(PhysAddr(0))..Frame(PhysAddr(LOW_MEMORY_END)), true);
+        inner
+    }
+
+    fn index(frame: Frame) -> usize {
+        (frame.0 .0 / 4096) as usize
+    }
+
+    fn is_free(&self, frame: Frame) -> bool {
+        let index = Self::index(frame);
+        index < self.frames && self.words[index / 64] & (1 << (index % 64)) == 0
+    }
+
+    fn set(&mut self, frame: Frame, used: bool) {
+        let index = Self::index(frame);
+        if index >= self.frames {
+            return;
+        }
+        let word = &mut self.words[index / 64];
+        if used {
+            *word |= 1 << (index % 64);
+        } else {
+            *word &= !(1 << (index % 64));
+        }
+    }
+
+    fn set_range(&mut self, frames: Range<Frame>, used: bool) {
+        for frame in frames {
+            self.set(frame, used);
+        }
+    }
+
+    /// Returns the index of the first free frame in or after word `from`.
+    fn find_free(&self, from: usize) -> Option<usize> {
+        let (offset, word) = self
+            .words
+            .iter()
+            .enumerate()
+            .skip(from)
+            .find(|(_, word)| **word != u64::MAX)?;
+        let index = offset * 64 + word.trailing_ones() as usize;
+        (index < self.frames).then_some(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{boxed::Box, vec};
+
+    use super::*;
+
+    /// Builds a bitmap over 4 MiB of memory, all of it usable, without touching real frames.
+    fn bitmap() -> Bitmap {
+        let frames = 1024;
+        let words = Box::leak(vec![0; frames / 64].into_boxed_slice());
+        let usable = Frame(PhysAddr(0))..Frame(PhysAddr(frames as u64 * 4096));
+        Bitmap {
+            inner: Spinlock::new(BitmapInner::new(words, frames, [usable].into_iter())),
+        }
+    }
+
+    #[test_case]
+    fn low_memory_is_reserved() {
+        let bitmap = bitmap();
+        assert!(!bitmap.is_free(Frame(PhysAddr(0))));
+        assert!(!bitmap.is_free(Frame(PhysAddr(LOW_MEMORY_END - 4096))));
+        assert!(bitmap.is_free(Frame(PhysAddr(LOW_MEMORY_END))));
+
+        let frame = bitmap.allocate_frame().unwrap();
+        assert!(frame.0 .0 >= LOW_MEMORY_END);
+    }
+
+    #[test_case]
+    fn allocate_specific_marks_the_frame_used() {
+        let bitmap = bitmap();
+        let frame = Frame(PhysAddr(0x20_0000));
+        assert!(bitmap.is_free(frame));
+
+        bitmap.allocate_specific(frame).unwrap();
+        assert!(!bitmap.is_free(frame));
+
+        unsafe { bitmap.deallocate_frame(frame) };
+        assert!(bitmap.is_free(frame));
+    }
+
+    #[test_case]
+    fn allocate_specific_rejects_used_frames() {
+        let bitmap = bitmap();
+        let frame = Frame(PhysAddr(0x20_0000));
+        assert!(bitmap.allocate_specific(frame).is_ok());
+        assert!(bitmap.allocate_specific(frame).is_err());
+
+        assert!(bitmap.allocate_specific(Frame(PhysAddr(0))).is_err());
+        assert!(bitmap
+            .allocate_specific(Frame(PhysAddr(0x40_0000)))
+            .is_err());
+    }
+
+    #[test_case]
+    fn allocate_frame_skips_specific_allocations() {
+        let bitmap = bitmap();
+        bitmap
+            .allocate_specific(Frame(PhysAddr(LOW_MEMORY_END)))
+            .unwrap();
+        let frame = bitmap.allocate_frame().unwrap();
+        assert_eq!(frame, Frame(PhysAddr(LOW_MEMORY_END + 4096)));
+    }
+}
@@ -0,0 +1,22 @@
+use crate::x86_64::out32;
+
+/// The port of the `isa-debug-exit` device that `xtask test` adds to the machine.
+const DEBUG_EXIT_PORT: u16 = 0xf4;
+
+/// Codes written to the `isa-debug-exit` device. QEMU exits with status `(code << 1) | 1`, so
+/// neither can be confused with QEMU's own exit statuses of 0 and 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum ExitCode {
+    Success = 0x10,
+    Failure = 0x11,
+}
+
+/// Shuts QEMU down with the given exit code.
+///
+/// Without the `isa-debug-exit` device, e.g. on real hardware, the port write does nothing and
+/// this halts instead.
+pub fn exit(code: ExitCode) -> ! {
+    unsafe { out32(DEBUG_EXIT_PORT, code as u32) };
+    crate::hcf();
+}
@@ -1,7 +1,13 @@
-use core::{arch::asm, fmt, hint};
+use core::{
+    arch::asm,
+    fmt, hint,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 use bitflags::bitflags;
 
+use crate::{spinlock::Spinlock, x86_64::io_pause};
+
 #[derive(Debug)]
 pub struct SpinWriter {
     port: SerialPort,
@@ -11,6 +17,10 @@ impl SpinWriter {
     pub fn new(serial_port: SerialPort) -> Self {
         Self { port: serial_port }
     }
+
+    pub fn port_mut(&mut self) -> &mut SerialPort {
+        &mut self.port
+    }
 }
 
 impl fmt::Write for SpinWriter {
@@ -24,32 +34,250 @@ impl fmt::Write for SpinWriter {
     }
 }
 
+/// Reads from a serial port by spinning until data arrives.
+#[derive(Debug)]
+pub struct SpinReader {
+    port: SerialPort,
+}
+
+impl SpinReader {
+    pub fn new(serial_port: SerialPort) -> Self {
+        Self { port: serial_port }
+    }
+
+    /// Waits for the next byte. This goes through [`SerialPort::try_read`], so on COM1 bytes its
+    /// interrupt handler has already buffered come first.
+    pub fn read_byte(&mut self) -> u8 {
+        loop {
+            match self.port.try_read() {
+                Some(byte) => return byte,
+                None => hint::spin_loop(),
+            }
+        }
+    }
+
+    /// Reads a line into `buf`, up to but not including the `\r` or `\n` ending it, and
+    /// returns its length. Bytes past the end of `buf` are dropped.
+    ///
+    /// Input is echoed back out of the same port, with backspace erasing the last byte, so the
+    /// line can be edited from a terminal. The echo doesn't go through any shared writer, so it
+    /// may interleave with other output.
+    pub fn read_line(&mut self, buf: &mut [u8]) -> usize {
+        let mut len = 0;
+        loop {
+            match self.read_byte() {
+                b'\r' | b'\n' => {
+                    self.echo(b"\r\n");
+                    return len;
+                }
+                0x08 | 0x7f => {
+                    if len > 0 {
+                        len -= 1;
+                        self.echo(b"\x08 \x08");
+                    }
+                }
+                byte => {
+                    if let Some(slot) = buf.get_mut(len) {
+                        *slot = byte;
+                        len += 1;
+                        self.echo(&[byte]);
+                    }
+                }
+            }
+        }
+    }
+
+    fn echo(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            while let Err(SendError::Full) = self.port.send(*byte) {
+                hint::spin_loop();
+            }
+        }
+    }
+}
+
+/// The IRQ line COM1 raises its interrupts on.
+pub const COM1_IRQ: u8 = 4;
+
+/// Bytes COM1's interrupt handler has taken out of the receive FIFO, waiting to be read.
+static RECEIVED: Spinlock<RingBuffer> = Spinlock::new(RingBuffer::new());
+
+/// Set once [`SerialPort::take_com1`] has handed out COM1.
+static COM1_TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Services every pending COM1 interrupt, moving received bytes into the receive buffer.
+///
+/// Meant to run from the handler for [`COM1_IRQ`]. The line is edge triggered, so every cause
+/// has to be cleared before returning or the port never interrupts again.
+pub fn handle_com1_interrupt() {
+    let mut port = unsafe { SerialPort::com1_uninit() };
+    while let Some(cause) = port.interrupt_cause() {
+        match cause {
+            InterruptCause::DataAvailable | InterruptCause::CharacterTimeout => {
+                RECEIVED.lock(|received| {
+                    while let Ok(byte) = port.recv() {
+                        received.push(byte);
+                    }
+                });
+            }
+            InterruptCause::LineStatus => _ = port.line_status(),
+            InterruptCause::ModemStatus => _ = unsafe { port.read(6) },
+            // Reading the identification register already cleared it.
+            InterruptCause::TransmitterEmpty => {}
+        }
+    }
+}
+
+/// Returns a writer to COM1 that bypasses the shared, locked one.
+///
+/// Meant for reporting fatal errors, when the normal writer may be held by the code that failed.
+/// Output may interleave with anything else being written at the same time.
+pub fn emergency_writer() -> SpinWriter {
+    SpinWriter::new(unsafe { SerialPort::com1_uninit() })
+}
+
 #[derive(Debug)]
 pub struct SerialPort {
     port: u16,
 }
 
 impl SerialPort {
-    unsafe fn init(&mut self) {
-        self.write(1, 0);
+    /// Programs the port for `baud` bits per second. The framing is always 8N1: eight data
+    /// bits, no parity and one stop bit.
+    ///
+    /// The UART can only divide its 115200 Hz clock by a whole number, so rates it can't hit
+    /// exactly are rejected. With `self_test`, the port must also pass [`SerialPort::self_test`]
+    /// before it is returned.
+    pub unsafe fn with_baud(
+        port: u16,
+        baud: u32,
+        self_test: bool,
+    ) -> Result<SerialPort, SerialError> {
+        if baud == 0 || BASE_BAUD % baud != 0 {
+            return Err(InvalidBaudRate(baud).into());
+        }
+        let divisor = u16::try_from(BASE_BAUD / baud).map_err(|_| InvalidBaudRate(baud))?;
+
+        let mut port = SerialPort { port };
+        port.init(divisor, self_test)?;
+        Ok(port)
+    }
 
-        // Configure DLAB, set BAUD to 0x3 (38400)
-        self.write(3, 0x80);
-        self.write(0, 0x3);
+    unsafe fn init(&mut self, divisor: u16, self_test: bool) -> Result<(), SerialError> {
         self.write(1, 0);
 
+        // Set DLAB to expose the divisor latch, then program the divisor.
+        self.write(3, LineControl::DLAB.bits());
+        let [low, high] = divisor.to_le_bytes();
+        self.write(0, low);
+        self.write(1, high);
+
         // Configure line control to 8n1
         self.write(3, 0x3);
 
         self.write(2, 0xc7);
         self.write(4, 0xb);
-        self.write(1, 0x1);
+        // Test before the receive interrupt is on, so a handler can't take the test byte.
+        if self_test {
+            self.self_test()?;
+        }
+        self.write(1, InterruptFlags::DATA_AVAILABLE.bits());
+        Ok(())
+    }
+
+    /// Checks that the UART is there and working by sending a byte to itself in loopback mode.
+    ///
+    /// A port that fails would otherwise just swallow everything written to it. Its modem
+    /// control and interrupt enable registers are put back however the test ends, and bytes
+    /// already waiting in the receive FIFO are dropped.
+    pub fn self_test(&mut self) -> Result<(), SerialError> {
+        /// Puts the registers the test changes back when dropped.
+        struct Restore {
+            port: SerialPort,
+            modem_control: u8,
+            interrupts: u8,
+        }
+
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                unsafe {
+                    self.port.write(4, self.modem_control);
+                    self.port.write(1, self.interrupts);
+                }
+            }
+        }
+
+        const TEST_BYTE: u8 = 0xae;
+        const TIMEOUT_US: usize = 1000;
+
+        let _restore = unsafe {
+            let restore = Restore {
+                port: SerialPort { port: self.port },
+                modem_control: self.read(4),
+                interrupts: self.read(1),
+            };
+            self.write(1, 0);
+            self.write(4, MODEM_CONTROL_LOOPBACK);
+            restore
+        };
+
+        for _ in 0..16 {
+            if self.recv().is_err() {
+                break;
+            }
+        }
+
+        let mut sent = false;
+        for _ in 0..TIMEOUT_US {
+            if !sent {
+                sent = self.send(TEST_BYTE).is_ok();
+            } else if let Ok(received) = self.recv() {
+                return match received {
+                    TEST_BYTE => Ok(()),
+                    received => Err(SerialError::LoopbackMismatch {
+                        sent: TEST_BYTE,
+                        received,
+                    }),
+                };
+            }
+            unsafe { io_pause() };
+        }
+        Err(SerialError::LoopbackTimeout)
     }
 
-    pub unsafe fn com1() -> SerialPort {
-        let mut port = SerialPort { port: 0x3f8 };
-        port.init();
-        port
+    /// Initializes the port with its registers at `base`, at 115200 baud.
+    pub unsafe fn new(base: u16) -> SerialPort {
+        SerialPort::with_baud(base, BASE_BAUD, false).expect("the base rate is always valid")
+    }
+
+    /// Initializes COM1 and returns it, the first time this is called. Every later call returns
+    /// `None`, so the port is only ever programmed once and has a single owner.
+    ///
+    /// [`SerialPort::com1_uninit`] and [`emergency_writer`] still reach the port without
+    /// owning it.
+    pub fn take_com1() -> Option<SerialPort> {
+        if COM1_TAKEN.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+        Some(unsafe { SerialPort::new(COM1) })
+    }
+
+    pub unsafe fn com2() -> SerialPort {
+        SerialPort::new(COM2)
+    }
+
+    pub unsafe fn com3() -> SerialPort {
+        SerialPort::new(COM3)
+    }
+
+    pub unsafe fn com4() -> SerialPort {
+        SerialPort::new(COM4)
+    }
+
+    /// Returns COM1 without reprogramming it, for writing alongside an existing handle that
+    /// has already initialized the port.
+    pub unsafe fn com1_uninit() -> SerialPort {
+        SerialPort { port: COM1 }
     }
 
     pub fn send(&mut self, byte: u8) -> Result<(), SendError> {
@@ -71,6 +299,34 @@ impl SerialPort {
         }
     }
 
+    /// Returns the next received byte, without waiting for one.
+    ///
+    /// COM1's bytes come out of the buffer its interrupt handler fills, falling back to the
+    /// FIFO while the buffer is empty so that reads work before the interrupt is routed. Every
+    /// other port is polled directly.
+    pub fn try_read(&mut self) -> Option<u8> {
+        if self.port != COM1 {
+            return self.recv().ok();
+        }
+        RECEIVED.lock(|received| received.pop().or_else(|| self.recv().ok()))
+    }
+
+    /// Returns the highest priority interrupt the port has pending, if any.
+    fn interrupt_cause(&self) -> Option<InterruptCause> {
+        let id = unsafe { self.read(2) };
+        if id & 1 != 0 {
+            return None;
+        }
+        match (id >> 1) & 0b111 {
+            0b000 => Some(InterruptCause::ModemStatus),
+            0b001 => Some(InterruptCause::TransmitterEmpty),
+            0b010 => Some(InterruptCause::DataAvailable),
+            0b011 => Some(InterruptCause::LineStatus),
+            0b110 => Some(InterruptCause::CharacterTimeout),
+            _ => None,
+        }
+    }
+
     fn line_status(&self) -> LineStatus {
         unsafe { LineStatus::from_bits_retain(self.read(5)) }
     }
@@ -84,6 +340,27 @@ impl SerialPort {
     }
 }
 
+/// A baud rate the UART clock can't be divided down to exactly.
+#[derive(Debug)]
+pub struct InvalidBaudRate(pub u32);
+
+#[derive(Debug)]
+pub enum SerialError {
+    InvalidBaudRate(InvalidBaudRate),
+    /// The test byte never came back, which usually means there is no UART at the port.
+    LoopbackTimeout,
+    LoopbackMismatch {
+        sent: u8,
+        received: u8,
+    },
+}
+
+impl From<InvalidBaudRate> for SerialError {
+    fn from(value: InvalidBaudRate) -> Self {
+        SerialError::InvalidBaudRate(value)
+    }
+}
+
 #[derive(Debug)]
 pub enum SendError {
     Full,
@@ -94,6 +371,61 @@ pub enum RecvError {
     Empty,
 }
 
+/// Why a port raised an interrupt, as read from its interrupt identification register.
+#[derive(Debug, Clone, Copy)]
+enum InterruptCause {
+    ModemStatus,
+    TransmitterEmpty,
+    DataAvailable,
+    LineStatus,
+    /// Bytes have been sitting in the FIFO without reaching its trigger level.
+    CharacterTimeout,
+}
+
+/// A fixed size byte queue. Bytes arriving while it is full are dropped.
+struct RingBuffer {
+    bytes: [u8; 256],
+    head: usize,
+    len: usize,
+}
+
+impl RingBuffer {
+    const fn new() -> Self {
+        Self {
+            bytes: [0; 256],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.len == self.bytes.len() {
+            return;
+        }
+        self.bytes[(self.head + self.len) % self.bytes.len()] = byte;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.bytes[self.head];
+        self.head = (self.head + 1) % self.bytes.len();
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+/// The UART input clock divided by 16, which is the fastest rate it can run at.
+const BASE_BAUD: u32 = 115200;
+/// Loopback mode, with RTS, OUT1 and OUT2 set so that they loop back as well.
+const MODEM_CONTROL_LOOPBACK: u8 = 0x1e;
+const COM1: u16 = 0x3f8;
+const COM2: u16 = 0x2f8;
+const COM3: u16 = 0x3e8;
+const COM4: u16 = 0x2e8;
+
 bitflags! {
     #[derive(Debug, Clone, Copy)]
     struct InterruptFlags: u8 {
@@ -136,3 +468,54 @@ unsafe fn in8(port: u16) -> u8 {
     asm!("in al, dx", in("dx") port, out("al") value);
     value
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn ring_buffer_is_first_in_first_out() {
+        let mut buffer = RingBuffer::new();
+        assert_eq!(buffer.pop(), None);
+
+        buffer.push(1);
+        buffer.push(2);
+        assert_eq!(buffer.pop(), Some(1));
+        buffer.push(3);
+        assert_eq!(buffer.pop(), Some(2));
+        assert_eq!(buffer.pop(), Some(3));
+        assert_eq!(buffer.pop(), None);
+    }
+
+    #[test_case]
+    fn ring_buffer_wraps_around() {
+        let mut buffer = RingBuffer::new();
+        for byte in 0..200 {
+            buffer.push(byte);
+        }
+        for byte in 0..200 {
+            assert_eq!(buffer.pop(), Some(byte));
+        }
+
+        // The head is now near the end of the storage, so these cross its end.
+        for byte in 0..100 {
+            buffer.push(byte);
+        }
+        for byte in 0..100 {
+            assert_eq!(buffer.pop(), Some(byte));
+        }
+        assert_eq!(buffer.pop(), None);
+    }
+
+    #[test_case]
+    fn ring_buffer_drops_bytes_when_full() {
+        let mut buffer = RingBuffer::new();
+        for i in 0..300 {
+            buffer.push(i as u8);
+        }
+        for i in 0..256 {
+            assert_eq!(buffer.pop(), Some(i as u8));
+        }
+        assert_eq!(buffer.pop(), None);
+    }
+}
@@ -1,4 +1,9 @@
-use spin::mutex::SpinMutex;
+use core::ops::{Deref, DerefMut};
+
+use spin::{
+    mutex::{SpinMutex, SpinMutexGuard},
+    rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
 
 use crate::interrupts;
 
@@ -18,9 +23,216 @@ impl<T> Spinlock<T> {
     where
         F: FnOnce(&mut T) -> U,
     {
-        interrupts::without(|| {
-            let mut guard = self.mutex.lock();
-            f(&mut *guard)
+        f(&mut self.lock_guard())
+    }
+
+    /// Acquires the lock with interrupts disabled, keeping them disabled until the guard is
+    /// dropped.
+    pub fn lock_guard(&self) -> Guard<'_, T> {
+        let interrupts = InterruptsOff::new();
+        Guard {
+            guard: self.mutex.lock(),
+            _interrupts: interrupts,
+        }
+    }
+
+    /// Like [`Spinlock::lock_guard`], but returns `None` instead of spinning if the lock is held.
+    pub fn try_lock(&self) -> Option<Guard<'_, T>> {
+        let interrupts = InterruptsOff::new();
+        Some(Guard {
+            guard: self.mutex.try_lock()?,
+            _interrupts: interrupts,
         })
     }
 }
+
+/// Holds a [`Spinlock`]. Dropping it releases the lock, then turns interrupts back on if they
+/// were on when it was acquired, so guards nest like [`interrupts::without`].
+///
+/// Like scopes, guards have to be dropped newest first: the oldest one is the one that saw
+/// interrupts on, so dropping it early turns them on while newer guards are still held. The same
+/// goes for the guards of [`RwSpinlock`].
+#[derive(Debug)]
+pub struct Guard<'a, T> {
+    // Fields drop in order, so the lock is free before an interrupt handler can try to take it.
+    guard: SpinMutexGuard<'a, T>,
+    _interrupts: InterruptsOff,
+}
+
+impl<T> Deref for Guard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for Guard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+/// A reader-writer lock that, like [`Spinlock`], keeps interrupts disabled while it is held.
+///
+/// Any number of readers may hold it at once, which suits data that many interrupt handlers read
+/// but that is rarely written. A core that holds a read guard and then calls [`RwSpinlock::write`]
+/// deadlocks, since the writer waits for every reader, itself included.
+#[derive(Debug)]
+pub struct RwSpinlock<T> {
+    lock: RwLock<T>,
+}
+
+impl<T> RwSpinlock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            lock: RwLock::new(value),
+        }
+    }
+
+    pub fn read(&self) -> ReadGuard<'_, T> {
+        let interrupts = InterruptsOff::new();
+        ReadGuard {
+            guard: self.lock.read(),
+            _interrupts: interrupts,
+        }
+    }
+
+    pub fn write(&self) -> WriteGuard<'_, T> {
+        let interrupts = InterruptsOff::new();
+        WriteGuard {
+            guard: self.lock.write(),
+            _interrupts: interrupts,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ReadGuard<'a, T> {
+    guard: RwLockReadGuard<'a, T>,
+    _interrupts: InterruptsOff,
+}
+
+impl<T> Deref for ReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+#[derive(Debug)]
+pub struct WriteGuard<'a, T> {
+    guard: RwLockWriteGuard<'a, T>,
+    _interrupts: InterruptsOff,
+}
+
+impl<T> Deref for WriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for WriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+/// Disables interrupts, and turns them back on when dropped if they were on to begin with.
+#[derive(Debug)]
+struct InterruptsOff {
+    were_enabled: bool,
+}
+
+impl InterruptsOff {
+    fn new() -> Self {
+        let were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        Self { were_enabled }
+    }
+}
+
+impl Drop for InterruptsOff {
+    fn drop(&mut self) {
+        if self.were_enabled {
+            unsafe { interrupts::enable() };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn try_lock_fails_while_held() {
+        let lock = Spinlock::new(0);
+        {
+            let _guard = lock.lock_guard();
+            assert!(lock.try_lock().is_none());
+        }
+        let mut guard = lock
+            .try_lock()
+            .expect("lock still held after its guard dropped");
+        *guard += 1;
+        drop(guard);
+        assert_eq!(lock.lock(|value| *value), 1);
+    }
+
+    #[test_case]
+    fn guards_restore_interrupts_as_they_were() {
+        let lock = Spinlock::new(());
+        let other = Spinlock::new(());
+
+        unsafe { interrupts::enable() };
+        let outer = lock.lock_guard();
+        assert!(!interrupts::are_enabled());
+        // A nested guard found them off, so it leaves them off.
+        let inner = other.lock_guard();
+        drop(inner);
+        assert!(!interrupts::are_enabled());
+        // A failed attempt doesn't turn them back on either.
+        assert!(lock.try_lock().is_none());
+        assert!(!interrupts::are_enabled());
+        drop(outer);
+        let restored = interrupts::are_enabled();
+
+        interrupts::disable();
+        drop(lock.lock_guard());
+        let stayed_off = !interrupts::are_enabled();
+
+        assert!(restored, "dropping the guard left interrupts off");
+        assert!(stayed_off, "dropping the guard turned interrupts on");
+    }
+
+    #[test_case]
+    fn readers_share_and_writers_wait() {
+        let lock = RwSpinlock::new(1);
+
+        unsafe { interrupts::enable() };
+        let first = lock.read();
+        let second = lock.read();
+        let both_read = *first + *second;
+        let off_while_reading = !interrupts::are_enabled();
+        // Newest first, since the oldest guard is the one that turns interrupts back on.
+        drop(second);
+        let off_with_one_reader = !interrupts::are_enabled();
+        drop(first);
+        let on_after_reading = interrupts::are_enabled();
+
+        let mut writer = lock.write();
+        *writer += 1;
+        let off_while_writing = !interrupts::are_enabled();
+        drop(writer);
+        let on_after_writing = interrupts::are_enabled();
+        interrupts::disable();
+
+        assert_eq!(both_read, 2);
+        assert_eq!(*lock.read(), 2);
+        assert!(off_while_reading && off_with_one_reader && off_while_writing);
+        assert!(on_after_reading && on_after_writing);
+    }
+}
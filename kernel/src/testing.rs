@@ -0,0 +1,40 @@
+//! The in-kernel test runner. `cargo test` builds a kernel that runs every `#[test_case]`
+//! function once it has booted, then reports the result through QEMU's exit status.
+
+use core::any::type_name;
+
+use crate::qemu::{self, ExitCode};
+
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        log::info!("test {} ...", type_name::<T>());
+        self();
+        log::info!("test {} ok", type_name::<T>());
+    }
+}
+
+/// Runs every test in turn. A failing test panics, and the panic handler exits QEMU with
+/// [`ExitCode::Failure`].
+pub fn run(tests: &[&dyn Testable]) {
+    log::info!("running {} tests", tests.len());
+    for test in tests {
+        test.run();
+    }
+    log::info!("all {} tests passed", tests.len());
+    qemu::exit(ExitCode::Success);
+}
+
+#[test_case]
+fn harness_runs_tests() {
+    assert_eq!(core::hint::black_box(2) + 2, 4);
+}
+
+#[cfg(feature = "failing_test")]
+#[test_case]
+fn always_fails() {
+    panic!("this test fails on purpose");
+}
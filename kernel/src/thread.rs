@@ -0,0 +1,361 @@
+use alloc::{boxed::Box, collections::VecDeque, vec::Vec};
+use core::{
+    mem,
+    num::NonZeroUsize,
+    ptr::{self, NonNull},
+};
+
+use crate::{
+    address_space::{AddrSpace, AllocError, FrameOwner},
+    interrupts,
+    spinlock::Spinlock,
+    types::VirtAddr,
+    x86_64::{context_switch, gdt, percpu, TaskState},
+};
+
+/// The size of every thread's stack, in pages. A guard page sits below each one.
+const STACK_PAGES: usize = 16;
+
+/// How many timer ticks a thread runs for before it is preempted.
+const QUANTUM_TICKS: u32 = 5;
+
+static SCHEDULER: Spinlock<RunQueue> = Spinlock::new(RunQueue::new());
+
+/// A round-robin scheduler for kernel threads, which run until they yield or use up their
+/// quantum.
+#[derive(Debug)]
+pub struct Scheduler;
+
+impl Scheduler {
+    /// Creates a thread that runs `f` and exits once it returns. It is queued behind every thread
+    /// already waiting to run.
+    pub fn spawn(f: fn()) -> Result<(), AllocError> {
+        let thread = Thread::new(f)?;
+        SCHEDULER.lock(|queue| queue.ready.push_back(thread));
+        Ok(())
+    }
+
+    /// Switches to the next thread waiting to run, moving the current one to the back of the
+    /// queue. Returns straight away if no other thread is waiting.
+    ///
+    /// The context the kernel booted on becomes a thread of its own the first time it yields.
+    pub fn yield_now() {
+        interrupts::without(|| {
+            let Some((prev, next)) = SCHEDULER.lock(|queue| queue.rotate()) else {
+                return;
+            };
+            // The lock has to be released first, since the next thread may well take it before
+            // switching back here.
+            unsafe { switch(&mut *prev, &*next) };
+        });
+    }
+
+    /// Charges a timer tick to the current thread, and switches to the next thread once the
+    /// current one has used up its quantum.
+    ///
+    /// This runs from the timer interrupt, after the interrupt has been acknowledged. The
+    /// interrupted thread is suspended in two layers on its own stack: the interrupt entry saved
+    /// its scratch registers and the interrupt frame, and [`context_switch`] saves the
+    /// callee-saved registers above that. When the thread is next switched to, `context_switch`
+    /// returns into the interrupt handler, which restores the rest and returns from the interrupt
+    /// to where the thread was preempted.
+    ///
+    /// Interrupt handlers must therefore run on the thread's own stack, never on an IST stack.
+    pub fn tick() {
+        let due = SCHEDULER.lock(|queue| {
+            queue.ticks_left = queue.ticks_left.saturating_sub(1);
+            if queue.ticks_left == 0 {
+                queue.rotate()
+            } else {
+                None
+            }
+        });
+        if let Some((prev, next)) = due {
+            unsafe { switch(&mut *prev, &*next) };
+        }
+    }
+}
+
+struct RunQueue {
+    current: Option<Box<Thread>>,
+    ready: VecDeque<Box<Thread>>,
+    /// Threads that have exited. Their stacks are freed once another thread runs, since they
+    /// are still in use while switching away.
+    exited: Vec<Box<Thread>>,
+    /// Timer ticks left in the current thread's quantum.
+    ticks_left: u32,
+}
+
+impl RunQueue {
+    const fn new() -> Self {
+        Self {
+            current: None,
+            ready: VecDeque::new(),
+            exited: Vec::new(),
+            ticks_left: QUANTUM_TICKS,
+        }
+    }
+
+    /// Makes the next ready thread current, queueing the previous one, and returns both.
+    ///
+    /// The threads are boxed, so the pointers stay valid after the lock is released for as long
+    /// as the threads are queued or running.
+    fn rotate(&mut self) -> Option<(*mut Thread, *const Thread)> {
+        self.exited.clear();
+        self.ticks_left = QUANTUM_TICKS;
+        let next = self.ready.pop_front()?;
+        let next_ptr: *const Thread = &*next;
+
+        let mut prev = mem::replace(&mut self.current, Some(next)).unwrap_or_else(Thread::boot);
+        let prev_ptr: *mut Thread = &mut *prev;
+        self.ready.push_back(prev);
+        Some((prev_ptr, next_ptr))
+    }
+}
+
+/// A kernel thread, owned by the scheduler.
+pub struct Thread {
+    /// The stack pointer of the thread while it isn't running.
+    state: *const TaskState,
+    /// The thread's stack, or `None` for the boot context, which came with its own.
+    stack: Option<NonNull<u8>>,
+}
+
+unsafe impl Send for Thread {}
+
+impl Thread {
+    fn new(f: fn()) -> Result<Box<Thread>, AllocError> {
+        let pages = NonZeroUsize::new(STACK_PAGES).unwrap();
+        let stack = AddrSpace::kernel().allocate_with_guard(pages)?;
+        let top = stack.as_ptr() as usize + STACK_PAGES * 4096;
+
+        // `context_switch` returns into `thread_entry` once it has popped this state, and
+        // `thread_entry` expects the stack a call would leave: a return address on top, 16-byte
+        // aligned just above it. Its return address is null, as it never returns.
+        let return_address = (top - 8) as *mut usize;
+        let state = (top - 8 - mem::size_of::<TaskState>()) as *mut TaskState;
+        unsafe {
+            return_address.write(0);
+            state.write(TaskState {
+                rdi: f as usize,
+                rip: thread_entry as usize,
+                ..Default::default()
+            });
+        }
+
+        Ok(Box::new(Thread {
+            state,
+            stack: Some(stack),
+        }))
+    }
+
+    fn boot() -> Box<Thread> {
+        Box::new(Thread {
+            state: ptr::null(),
+            stack: None,
+        })
+    }
+}
+
+impl Drop for Thread {
+    fn drop(&mut self) {
+        if let Some(stack) = self.stack {
+            let pages = NonZeroUsize::new(STACK_PAGES).unwrap();
+            unsafe { AddrSpace::kernel().unmap(stack, pages, FrameOwner::AddrSpace) }
+                .expect("failed to free a thread stack");
+        }
+    }
+}
+
+/// Where every new thread starts, with interrupts disabled by the switch to it.
+extern "C" fn thread_entry(f: fn()) -> ! {
+    unsafe { interrupts::enable() };
+    f();
+    exit();
+}
+
+/// Ends the current thread and switches to the next one.
+fn exit() -> ! {
+    interrupts::disable();
+    let (prev, next) = SCHEDULER.lock(|queue| {
+        let next = queue.ready.pop_front().expect("the last thread exited");
+        let next_ptr: *const Thread = &*next;
+
+        let mut prev = mem::replace(&mut queue.current, Some(next)).expect("no thread is running");
+        queue.ticks_left = QUANTUM_TICKS;
+        let prev_ptr: *mut Thread = &mut *prev;
+        queue.exited.push(prev);
+        (prev_ptr, next_ptr)
+    });
+    unsafe { switch(&mut *prev, &*next) };
+    unreachable!("switched back to an exited thread");
+}
+
+/// Suspends the calling thread as `prev` and resumes `next`. Returns once some thread switches
+/// back to `prev`.
+///
+/// `prev` must be the thread that is running, and `next` one that is suspended, either by an
+/// earlier switch or because it has never run. Interrupts must be disabled, and no lock may be
+/// held that `next` could try to take.
+unsafe fn switch(prev: &mut Thread, next: &Thread) {
+    debug_assert!(
+        !interrupts::are_enabled(),
+        "thread switch with interrupts on"
+    );
+    debug_assert!(
+        !next.state.is_null(),
+        "switch to a thread with no saved state"
+    );
+    if let Some(stack) = next.stack {
+        gdt::set_kernel_stack(VirtAddr(stack.as_ptr() as usize + STACK_PAGES * 4096));
+    }
+    percpu::current().current_thread.set(next);
+    context_switch(&mut prev.state, next.state);
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use core::{
+        hint,
+        sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    };
+
+    use super::*;
+    use crate::time;
+
+    static SPINS: AtomicU64 = AtomicU64::new(0);
+    static STOP: AtomicBool = AtomicBool::new(false);
+    static STOPPED: AtomicBool = AtomicBool::new(false);
+
+    /// Busy-loops without ever yielding until told to stop.
+    fn spinner() {
+        while !STOP.load(Ordering::Relaxed) {
+            SPINS.fetch_add(1, Ordering::Relaxed);
+            hint::spin_loop();
+        }
+        STOPPED.store(true, Ordering::Relaxed);
+    }
+
+    /// Busy-waits, without yielding, until `done` returns true or a second has passed.
+    fn spin_until(done: impl Fn() -> bool) -> bool {
+        let start = time::uptime_ms();
+        while !done() {
+            if time::uptime_ms() - start > 1000 {
+                return false;
+            }
+            hint::spin_loop();
+        }
+        true
+    }
+
+    #[test_case]
+    fn busy_threads_are_preempted() {
+        SPINS.store(0, Ordering::Relaxed);
+        STOP.store(false, Ordering::Relaxed);
+        STOPPED.store(false, Ordering::Relaxed);
+        Scheduler::spawn(spinner).unwrap();
+
+        // Neither this thread nor the spinner yields, so each only makes progress if the timer
+        // takes the CPU away from the other.
+        unsafe { interrupts::enable() };
+        let spun = spin_until(|| SPINS.load(Ordering::Relaxed) > 0);
+        STOP.store(true, Ordering::Relaxed);
+        let stopped = spin_until(|| STOPPED.load(Ordering::Relaxed));
+        interrupts::disable();
+
+        assert!(spun, "the spinner never ran while this thread was busy");
+        assert!(stopped, "the spinner never ran again after being preempted");
+    }
+
+    const ROUNDS: usize = 100;
+
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    /// Which counting thread incremented last, and how many times that changed hands.
+    static LAST_COUNTER: AtomicU64 = AtomicU64::new(0);
+    static HANDOVERS: AtomicU64 = AtomicU64::new(0);
+    static COUNTERS_DONE: AtomicU64 = AtomicU64::new(0);
+
+    fn count_as(id: u64) {
+        for _ in 0..ROUNDS {
+            COUNTER.fetch_add(1, Ordering::Relaxed);
+            if LAST_COUNTER.swap(id, Ordering::Relaxed) != id {
+                HANDOVERS.fetch_add(1, Ordering::Relaxed);
+            }
+            Scheduler::yield_now();
+        }
+        COUNTERS_DONE.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[test_case]
+    fn yielding_threads_take_turns() {
+        COUNTER.store(0, Ordering::Relaxed);
+        LAST_COUNTER.store(0, Ordering::Relaxed);
+        HANDOVERS.store(0, Ordering::Relaxed);
+        COUNTERS_DONE.store(0, Ordering::Relaxed);
+        Scheduler::spawn(|| count_as(1)).unwrap();
+        Scheduler::spawn(|| count_as(2)).unwrap();
+
+        // The counting threads only run when this one yields to them.
+        for _ in 0..10 * ROUNDS {
+            if COUNTERS_DONE.load(Ordering::Relaxed) == 2 {
+                break;
+            }
+            Scheduler::yield_now();
+        }
+
+        assert_eq!(COUNTERS_DONE.load(Ordering::Relaxed), 2);
+        assert_eq!(COUNTER.load(Ordering::Relaxed), 2 * ROUNDS as u64);
+        // Run to completion one after the other, the count would change hands only once.
+        let handovers = HANDOVERS.load(Ordering::Relaxed);
+        assert!(handovers >= ROUNDS as u64, "only {} handovers", handovers);
+    }
+
+    /// The two sides of a bare context switch, outside the scheduler.
+    struct Contexts {
+        test: *const TaskState,
+        bounce: *const TaskState,
+        bounced: bool,
+    }
+
+    /// Runs on its own stack, switching straight back to the test.
+    extern "C" fn bounce(contexts: *mut Contexts) -> ! {
+        unsafe {
+            (*contexts).bounced = true;
+            context_switch(ptr::addr_of_mut!((*contexts).bounce), (*contexts).test);
+        }
+        unreachable!("switched back to a finished bounce");
+    }
+
+    #[test_case]
+    fn context_switch_round_trips() {
+        let mut stack = vec![0u64; 1024];
+        // The same layout as a new thread's stack: a null return address, 16-byte aligned just
+        // above it, with the state below.
+        let top = (stack.as_mut_ptr_range().end as usize) & !0xf;
+        let return_address = (top - 8) as *mut usize;
+        let state = (top - 8 - mem::size_of::<TaskState>()) as *mut TaskState;
+
+        let mut contexts = Contexts {
+            test: ptr::null(),
+            bounce: state,
+            bounced: false,
+        };
+        let contexts: *mut Contexts = &mut contexts;
+        let local = hint::black_box([0x1234_5678_9abc_def0_u64; 4]);
+        unsafe {
+            return_address.write(0);
+            state.write(TaskState {
+                rdi: contexts as usize,
+                rip: bounce as usize,
+                ..Default::default()
+            });
+            interrupts::without(|| {
+                context_switch(ptr::addr_of_mut!((*contexts).test), (*contexts).bounce)
+            });
+        }
+
+        assert!(unsafe { (*contexts).bounced });
+        assert_eq!(hint::black_box(local), [0x1234_5678_9abc_def0; 4]);
+    }
+}
@@ -0,0 +1,260 @@
+use core::{
+    hint,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::x86_64::{
+    interrupts::{self, Controller},
+    io_pause, pit, tsc,
+};
+
+/// The timer interrupt rate used unless the `tick_hz=` command line option overrides it.
+pub const DEFAULT_TICK_HZ: u32 = 100;
+
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+/// The TSC count when [`init`] started, which [`uptime`] counts from.
+static TSC_AT_INIT: AtomicU64 = AtomicU64::new(0);
+static TICK_HZ: AtomicU32 = AtomicU32::new(DEFAULT_TICK_HZ);
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug)]
+pub struct NoTimerError;
+
+/// A clock that can measure out a reference interval, for calibrating other timers against.
+pub trait TimeSource {
+    /// Busy-waits for `us` microseconds.
+    fn wait_us(&mut self, us: u64);
+}
+
+/// Waits using PIT channel 2, which runs at a fixed, known frequency.
+#[derive(Debug)]
+pub struct PitClock;
+
+impl TimeSource for PitClock {
+    fn wait_us(&mut self, us: u64) {
+        let mut ticks = u64::from(pit::FREQUENCY) * us / 1_000_000;
+        while ticks != 0 {
+            let chunk = ticks.min(u64::from(u16::MAX));
+            unsafe { pit::wait_channel2(chunk as u16) };
+            ticks -= chunk;
+        }
+    }
+}
+
+/// Waits using the TSC, which is only accurate once [`init`] has run.
+#[derive(Debug)]
+pub struct TscClock;
+
+impl TimeSource for TscClock {
+    fn wait_us(&mut self, us: u64) {
+        delay_us(us);
+    }
+}
+
+/// Determines the TSC frequency so the delay functions become cycle-accurate.
+///
+/// The frequency reported through CPUID is used when available, since it is exact and needs no
+/// calibration loop. Otherwise the TSC is measured against the PIT.
+pub unsafe fn init() {
+    TSC_AT_INIT.store(tsc::read(), Ordering::Relaxed);
+    let frequency = match tsc::frequency_from_cpuid() {
+        Some(frequency) => {
+            log::debug!("tsc frequency (cpuid): {} Hz", frequency);
+            frequency
+        }
+        None => {
+            let frequency = calibrate_tsc(&mut PitClock);
+            log::debug!("tsc frequency (pit): {} Hz", frequency);
+            frequency
+        }
+    };
+    TSC_FREQUENCY.store(frequency, Ordering::Relaxed);
+}
+
+fn calibrate_tsc(reference: &mut impl TimeSource) -> u64 {
+    const CALIBRATION_MS: u64 = 10;
+
+    let start = tsc::read();
+    reference.wait_us(CALIBRATION_MS * 1000);
+    let end = tsc::read();
+
+    (end - start) * 1000 / CALIBRATION_MS
+}
+
+/// Returns the TSC frequency in Hz, or `None` if it has not been calibrated yet.
+pub fn tsc_frequency() -> Option<u64> {
+    match TSC_FREQUENCY.load(Ordering::Relaxed) {
+        0 => None,
+        hz => Some(hz),
+    }
+}
+
+pub fn delay_us(us: u64) {
+    delay_ns(us.saturating_mul(1000));
+}
+
+/// Busy-waits for at least `ns` nanoseconds.
+///
+/// Before the TSC is calibrated this falls back to [io_pause], which only approximates a
+/// microsecond per iteration.
+pub fn delay_ns(ns: u64) {
+    let Some(frequency) = tsc_frequency() else {
+        for _ in 0..ns.div_ceil(1000) {
+            unsafe { io_pause() };
+        }
+        return;
+    };
+
+    let cycles = (u128::from(ns) * u128::from(frequency) / 1_000_000_000) as u64;
+    let start = tsc::read();
+    while tsc::read().wrapping_sub(start) < cycles {
+        hint::spin_loop();
+    }
+}
+
+/// Reprograms the periodic timer interrupt to fire `hz` times per second.
+///
+/// Only the local APIC timer can be programmed so far, so this fails while the legacy PIC is
+/// the interrupt controller.
+pub unsafe fn set_tick_hz(hz: u32) -> Result<(), NoTimerError> {
+    assert!(hz != 0, "tick rate must be nonzero");
+
+    interrupts::with_controller(|controller| match controller {
+        Controller::Apic(apic) => {
+            apic.local_apic().set_frequency(hz);
+            Ok(())
+        }
+        Controller::Pic(_) => Err(NoTimerError),
+    })
+    .unwrap_or(Err(NoTimerError))?;
+
+    TICK_HZ.store(hz, Ordering::Relaxed);
+    log::debug!("timer tick rate: {} Hz", hz);
+    Ok(())
+}
+
+/// The configured timer interrupt rate.
+pub fn tick_hz() -> u32 {
+    TICK_HZ.load(Ordering::Relaxed)
+}
+
+/// Records a timer interrupt. Called from the timer handler.
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Milliseconds since the timer was started, as counted by timer interrupts.
+///
+/// This assumes every tick so far happened at the current rate.
+pub fn uptime_ms() -> u64 {
+    TICKS.load(Ordering::Relaxed) * 1000 / u64::from(tick_hz())
+}
+
+/// Time since boot, from the most precise clock available.
+///
+/// Once [`init`] has calibrated the TSC this counts TSC cycles from the start of `init`, so the
+/// time spent in firmware and the bootloader is left out. Before that it falls back to the timer
+/// interrupt count, which stays zero until the timer is started after `init`, so the two never
+/// disagree about when boot was.
+pub fn uptime() -> Duration {
+    match tsc_frequency() {
+        Some(frequency) => {
+            let cycles = tsc::read().saturating_sub(TSC_AT_INIT.load(Ordering::Relaxed));
+            cycles_to_duration(cycles, frequency)
+        }
+        None => Duration::from_millis(uptime_ms()),
+    }
+}
+
+fn cycles_to_duration(cycles: u64, frequency: u64) -> Duration {
+    let nanos = u128::from(cycles % frequency) * 1_000_000_000 / u128::from(frequency);
+    Duration::new(cycles / frequency, nanos as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x86_64::hpet;
+
+    #[test_case]
+    fn delay_us_agrees_with_hpet() {
+        let Some(hpet) = hpet::global() else {
+            log::warn!("no hpet, skipping");
+            return;
+        };
+        let start = hpet.nanos();
+        delay_us(1000);
+        let elapsed = hpet.nanos() - start;
+        // It may overshoot, especially under emulation, but never undershoot.
+        assert!(
+            (1_000_000..2_000_000).contains(&elapsed),
+            "delay_us(1000) took {} ns",
+            elapsed
+        );
+    }
+
+    #[test_case]
+    fn tick_rate_agrees_with_hpet() {
+        const HZ: u32 = 250;
+        const MEASURE_NS: u64 = 200_000_000;
+
+        let Some(hpet) = hpet::global() else {
+            log::warn!("no hpet, skipping");
+            return;
+        };
+        let previous = tick_hz();
+        if unsafe { set_tick_hz(HZ) }.is_err() {
+            log::warn!("no programmable timer, skipping");
+            return;
+        }
+
+        let start_ticks = TICKS.load(Ordering::Relaxed);
+        let start = hpet.nanos();
+        unsafe { crate::interrupts::enable() };
+        while hpet.nanos() - start < MEASURE_NS {
+            hint::spin_loop();
+        }
+        crate::interrupts::disable();
+        let ticks = TICKS.load(Ordering::Relaxed) - start_ticks;
+        unsafe { set_tick_hz(previous) }.unwrap();
+
+        // 50 ticks are expected; allow for the first and last partial periods and some drift in
+        // the timer's calibration.
+        let expected = u64::from(HZ) * MEASURE_NS / 1_000_000_000;
+        assert!(
+            ticks.abs_diff(expected) <= expected / 10 + 1,
+            "{} ticks in {} ms at {} Hz",
+            ticks,
+            MEASURE_NS / 1_000_000,
+            HZ
+        );
+    }
+
+    #[test_case]
+    fn uptime_counts_from_init() {
+        let Some(frequency) = tsc_frequency() else {
+            log::warn!("tsc not calibrated, skipping");
+            return;
+        };
+        let before = uptime();
+        delay_us(1000);
+        let after = uptime();
+        let since_reset = cycles_to_duration(tsc::read(), frequency);
+
+        assert!(after - before >= Duration::from_millis(1));
+        // The firmware and bootloader ran before init, so the TSC was well past zero by then.
+        assert!(TSC_AT_INIT.load(Ordering::Relaxed) != 0);
+        assert!(after < since_reset, "{:?} is time since reset", after);
+    }
+
+    #[test_case]
+    fn cycles_convert_to_durations() {
+        assert_eq!(cycles_to_duration(0, 1000), Duration::ZERO);
+        assert_eq!(cycles_to_duration(2500, 1000), Duration::from_millis(2500));
+        assert_eq!(
+            cycles_to_duration(3_000_000_001, 2_000_000_000),
+            Duration::new(1, 500_000_000)
+        );
+    }
+}
@@ -0,0 +1,150 @@
+use core::{
+    fmt,
+    sync::atomic::{self, AtomicU64, Ordering},
+};
+
+use crate::x86_64::tsc;
+
+/// The number of most recent events kept for post-mortem inspection.
+const LEN: usize = 256;
+
+static RING: [Slot; LEN] = [Slot::EMPTY; LEN];
+static NEXT: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Tag {
+    Map = 1,
+    Unmap,
+    FrameAlloc,
+    FrameFree,
+    ContextSwitch,
+    InterruptEntry,
+    InterruptExit,
+}
+
+impl Tag {
+    fn from_raw(raw: u64) -> Option<Self> {
+        Some(match raw {
+            1 => Tag::Map,
+            2 => Tag::Unmap,
+            3 => Tag::FrameAlloc,
+            4 => Tag::FrameFree,
+            5 => Tag::ContextSwitch,
+            6 => Tag::InterruptEntry,
+            7 => Tag::InterruptExit,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Event {
+    pub tag: Tag,
+    pub tsc: u64,
+    pub args: [u64; 2],
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:>20} {:?} {:#x} {:#x}",
+            self.tsc, self.tag, self.args[0], self.args[1]
+        )
+    }
+}
+
+/// A ring entry. `seq` is one more than the index of the event stored in it, or zero while the
+/// slot is being written, so readers can skip entries that are torn or have been overwritten.
+struct Slot {
+    seq: AtomicU64,
+    tag: AtomicU64,
+    tsc: AtomicU64,
+    args: [AtomicU64; 2],
+}
+
+impl Slot {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: Slot = Slot {
+        seq: AtomicU64::new(0),
+        tag: AtomicU64::new(0),
+        tsc: AtomicU64::new(0),
+        args: [AtomicU64::new(0), AtomicU64::new(0)],
+    };
+}
+
+/// Records an event in the trace ring, overwriting the oldest one.
+///
+/// This takes no locks, so it is safe to call from interrupt handlers and the allocators.
+pub fn emit(tag: Tag, arg0: u64, arg1: u64) {
+    let index = NEXT.fetch_add(1, Ordering::Relaxed);
+    let slot = &RING[index as usize % LEN];
+
+    slot.seq.store(0, Ordering::Release);
+    slot.tag.store(tag as u64, Ordering::Relaxed);
+    slot.tsc.store(tsc::read(), Ordering::Relaxed);
+    slot.args[0].store(arg0, Ordering::Relaxed);
+    slot.args[1].store(arg1, Ordering::Relaxed);
+    slot.seq.store(index + 1, Ordering::Release);
+}
+
+/// Returns the recorded events, oldest first.
+pub fn events() -> impl Iterator<Item = Event> {
+    let end = NEXT.load(Ordering::Acquire);
+    let start = end.saturating_sub(LEN as u64);
+
+    (start..end).filter_map(|index| {
+        let slot = &RING[index as usize % LEN];
+        let seq = slot.seq.load(Ordering::Acquire);
+        let event = Event {
+            tag: Tag::from_raw(slot.tag.load(Ordering::Relaxed))?,
+            tsc: slot.tsc.load(Ordering::Relaxed),
+            args: [
+                slot.args[0].load(Ordering::Relaxed),
+                slot.args[1].load(Ordering::Relaxed),
+            ],
+        };
+        atomic::fence(Ordering::Acquire);
+        let unchanged = slot.seq.load(Ordering::Relaxed) == seq;
+        (seq == index + 1 && unchanged).then_some(event)
+    })
+}
+
+/// Writes every recorded event to `writer`, oldest first.
+pub fn dump(writer: &mut impl fmt::Write) -> fmt::Result {
+    for event in events() {
+        writeln!(writer, "{}", event)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    #[test_case]
+    fn events_come_back_in_order() {
+        const MARKER: u64 = 0x7e57_0000_0000;
+        let tags = [Tag::Map, Tag::FrameAlloc, Tag::Unmap, Tag::FrameFree];
+
+        // Interrupts would emit events of their own, which could push these out of the ring.
+        crate::interrupts::without(|| {
+            for (i, tag) in tags.into_iter().enumerate() {
+                emit(tag, MARKER | i as u64, i as u64);
+            }
+        });
+
+        let events: Vec<Event> = events()
+            .filter(|event| event.args[0] & !0xff == MARKER)
+            .collect();
+        assert_eq!(events.len(), tags.len());
+        for (i, (event, tag)) in events.iter().zip(tags).enumerate() {
+            assert_eq!(event.tag, tag);
+            assert_eq!(event.args, [MARKER | i as u64, i as u64]);
+        }
+        assert!(events.windows(2).all(|pair| pair[0].tsc <= pair[1].tsc));
+    }
+}
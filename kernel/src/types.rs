@@ -1,14 +1,83 @@
-use core::iter::Step;
+use core::{
+    iter::Step,
+    ops::{Add, Sub},
+};
 
 use bytemuck::{NoUninit, Zeroable};
 
+/// The size in bytes of a page or frame.
+pub const PAGE_SIZE: usize = 4096;
+
 #[repr(transparent)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, NoUninit)]
 pub struct PhysAddr(pub u64);
 
+impl PhysAddr {
+    pub fn checked_add(self, offset: u64) -> Option<Self> {
+        self.0.checked_add(offset).map(Self)
+    }
+
+    pub fn checked_sub(self, offset: u64) -> Option<Self> {
+        self.0.checked_sub(offset).map(Self)
+    }
+
+    /// Returns the distance in bytes from `origin` up to `self`.
+    ///
+    /// `origin` must not be greater than `self`.
+    pub fn offset_from(self, origin: PhysAddr) -> u64 {
+        debug_assert!(origin <= self, "physical address offset underflow");
+        self.0.wrapping_sub(origin.0)
+    }
+
+    /// Rounds down to a multiple of `align`, which must be a power of two.
+    pub fn align_down(self, align: u64) -> Self {
+        assert!(align.is_power_of_two(), "alignment must be a power of two");
+        Self(self.0 & !(align - 1))
+    }
+
+    /// Rounds up to a multiple of `align`, which must be a power of two.
+    pub fn align_up(self, align: u64) -> Self {
+        assert!(align.is_power_of_two(), "alignment must be a power of two");
+        Self(self.0.next_multiple_of(align))
+    }
+
+    /// Returns the offset of the address into its frame.
+    pub fn offset_in_page(self) -> u64 {
+        self.0 % PAGE_SIZE as u64
+    }
+}
+
+impl Add<u64> for PhysAddr {
+    type Output = PhysAddr;
+
+    fn add(self, rhs: u64) -> Self::Output {
+        debug_assert!(self.checked_add(rhs).is_some(), "physical address overflow");
+        Self(self.0.wrapping_add(rhs))
+    }
+}
+
+impl Sub<u64> for PhysAddr {
+    type Output = PhysAddr;
+
+    fn sub(self, rhs: u64) -> Self::Output {
+        debug_assert!(
+            self.checked_sub(rhs).is_some(),
+            "physical address underflow"
+        );
+        Self(self.0.wrapping_sub(rhs))
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Frame(pub PhysAddr);
 
+impl Frame {
+    /// Returns the frame `addr` falls in.
+    pub fn containing(addr: PhysAddr) -> Self {
+        Self(addr.align_down(PAGE_SIZE as u64))
+    }
+}
+
 impl Step for Frame {
     fn steps_between(start: &Self, end: &Self) -> Option<usize> {
         end.0
@@ -21,15 +90,15 @@ impl Step for Frame {
     fn forward_checked(start: Self, count: usize) -> Option<Self> {
         count
             .checked_mul(4096)
-            .and_then(|offset| start.0 .0.checked_add(offset as u64))
-            .map(|addr| Self(PhysAddr(addr)))
+            .and_then(|offset| start.0.checked_add(offset as u64))
+            .map(Self)
     }
 
     fn backward_checked(start: Self, count: usize) -> Option<Self> {
         count
             .checked_mul(4096)
-            .and_then(|offset| start.0 .0.checked_sub(offset as u64))
-            .map(|addr| Self(PhysAddr(addr)))
+            .and_then(|offset| start.0.checked_sub(offset as u64))
+            .map(Self)
     }
 }
 
@@ -42,6 +111,23 @@ impl VirtAddr {
         Self(0)
     }
 
+    /// Returns `addr` as a virtual address, or `None` if it isn't canonical.
+    pub fn new_canonical(addr: usize) -> Option<Self> {
+        let addr = Self(addr);
+        addr.is_canonical().then_some(addr)
+    }
+
+    /// Returns whether bits 48 through 63 are all copies of bit 47, as the processor requires of
+    /// every address it translates.
+    pub fn is_canonical(&self) -> bool {
+        ((self.0 as isize) << 16 >> 16) as usize == self.0
+    }
+
+    /// Returns whether this is a canonical address in the upper half, where the kernel lives.
+    pub fn is_higher_half(&self) -> bool {
+        self.is_canonical() && self.0 >> 63 == 1
+    }
+
     pub fn addr(&self) -> usize {
         self.0
     }
@@ -49,12 +135,77 @@ impl VirtAddr {
     pub fn as_ptr(&self) -> *mut () {
         self.0 as *mut ()
     }
+
+    pub fn checked_add(self, offset: usize) -> Option<Self> {
+        self.0.checked_add(offset).map(Self)
+    }
+
+    pub fn checked_sub(self, offset: usize) -> Option<Self> {
+        self.0.checked_sub(offset).map(Self)
+    }
+
+    /// Returns the distance in bytes from `origin` up to `self`.
+    ///
+    /// `origin` must not be greater than `self`.
+    pub fn offset_from(self, origin: VirtAddr) -> usize {
+        debug_assert!(origin <= self, "virtual address offset underflow");
+        self.0.wrapping_sub(origin.0)
+    }
+
+    /// Rounds down to a multiple of `align`, which must be a power of two.
+    pub fn align_down(self, align: usize) -> Self {
+        assert!(align.is_power_of_two(), "alignment must be a power of two");
+        Self(self.0 & !(align - 1))
+    }
+
+    /// Rounds up to a multiple of `align`, which must be a power of two.
+    pub fn align_up(self, align: usize) -> Self {
+        assert!(align.is_power_of_two(), "alignment must be a power of two");
+        Self(self.0.next_multiple_of(align))
+    }
+
+    /// Returns the offset of the address into its page.
+    pub fn offset_in_page(self) -> usize {
+        self.0 % PAGE_SIZE
+    }
+
+    /// Returns the index of the entry covering this address in the page table at `level`, from
+    /// 0 for the l1 up to 3 for the l4.
+    pub fn page_table_index(self, level: u8) -> usize {
+        assert!(level < 4, "no page table level {}", level);
+        (self.0 >> (12 + 9 * u32::from(level))) & 0x1ff
+    }
+}
+
+impl Add<usize> for VirtAddr {
+    type Output = VirtAddr;
+
+    fn add(self, rhs: usize) -> Self::Output {
+        debug_assert!(self.checked_add(rhs).is_some(), "virtual address overflow");
+        Self(self.0.wrapping_add(rhs))
+    }
+}
+
+impl Sub<usize> for VirtAddr {
+    type Output = VirtAddr;
+
+    fn sub(self, rhs: usize) -> Self::Output {
+        debug_assert!(self.checked_sub(rhs).is_some(), "virtual address underflow");
+        Self(self.0.wrapping_sub(rhs))
+    }
 }
 
 #[repr(transparent)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, NoUninit)]
 pub struct Page(pub VirtAddr);
 
+impl Page {
+    /// Returns the page `addr` falls in.
+    pub fn containing(addr: VirtAddr) -> Self {
+        Self(addr.align_down(PAGE_SIZE))
+    }
+}
+
 impl Step for Page {
     fn steps_between(start: &Self, end: &Self) -> Option<usize> {
         end.0.addr().checked_sub(start.0.addr()).map(|v| v / 4096)
@@ -63,14 +214,100 @@ impl Step for Page {
     fn forward_checked(start: Self, count: usize) -> Option<Self> {
         count
             .checked_mul(4096)
-            .and_then(|offset| start.0.addr().checked_add(offset))
-            .map(|addr| Self(VirtAddr(addr)))
+            .and_then(|offset| start.0.checked_add(offset))
+            .map(Self)
     }
 
     fn backward_checked(start: Self, count: usize) -> Option<Self> {
         count
             .checked_mul(4096)
-            .and_then(|offset| start.0.addr().checked_sub(offset))
-            .map(|addr| Self(VirtAddr(addr)))
+            .and_then(|offset| start.0.checked_sub(offset))
+            .map(Self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn phys_addr_arithmetic() {
+        let addr = PhysAddr(0x1000);
+        assert_eq!(addr + 0x234, PhysAddr(0x1234));
+        assert_eq!(addr - 0x1000, PhysAddr(0));
+        assert_eq!(PhysAddr(0x1234).offset_from(addr), 0x234);
+        assert_eq!(addr.offset_from(addr), 0);
+    }
+
+    #[test_case]
+    fn phys_addr_overflow() {
+        let top = PhysAddr(u64::MAX);
+        assert_eq!(top.checked_add(0), Some(top));
+        assert_eq!(top.checked_add(1), None);
+        assert_eq!(PhysAddr(0).checked_sub(1), None);
+        assert_eq!(PhysAddr(u64::MAX - 1) + 1, top);
+    }
+
+    #[test_case]
+    fn virt_addr_arithmetic() {
+        let addr = VirtAddr(0xffff_8000_0000_0000);
+        assert_eq!(addr + 0x10, VirtAddr(0xffff_8000_0000_0010));
+        assert_eq!(addr - 0x10, VirtAddr(0xffff_7fff_ffff_fff0));
+        assert_eq!(VirtAddr(0xffff_8000_0000_1000).offset_from(addr), 0x1000);
+    }
+
+    #[test_case]
+    fn virt_addr_overflow() {
+        let top = VirtAddr(usize::MAX);
+        assert_eq!(top.checked_add(1), None);
+        assert_eq!(VirtAddr(0).checked_sub(1), None);
+        assert_eq!(VirtAddr(usize::MAX - 0xfff) + 0xfff, top);
+    }
+
+    #[test_case]
+    fn canonical_boundaries() {
+        // The top of the lower half and the bottom of the upper half.
+        assert!(VirtAddr(0x0000_7fff_ffff_ffff).is_canonical());
+        assert!(VirtAddr(0xffff_8000_0000_0000).is_canonical());
+        assert!(!VirtAddr(0x0000_7fff_ffff_ffff).is_higher_half());
+        assert!(VirtAddr(0xffff_8000_0000_0000).is_higher_half());
+
+        // The first and last addresses of the hole between them.
+        assert!(!VirtAddr(0x0000_8000_0000_0000).is_canonical());
+        assert!(!VirtAddr(0xffff_7fff_ffff_ffff).is_canonical());
+        assert_eq!(VirtAddr::new_canonical(0x0000_8000_0000_0000), None);
+        assert!(!VirtAddr(0x0000_8000_0000_0000).is_higher_half());
+    }
+
+    #[test_case]
+    fn alignment_on_and_just_before_a_boundary() {
+        let on = VirtAddr(0xffff_8000_0000_2000);
+        let before = on - 1;
+        assert_eq!(on.align_down(PAGE_SIZE), on);
+        assert_eq!(on.align_up(PAGE_SIZE), on);
+        assert_eq!(before.align_down(PAGE_SIZE), on - PAGE_SIZE);
+        assert_eq!(before.align_up(PAGE_SIZE), on);
+        assert_eq!(Page::containing(on), Page(on));
+        assert_eq!(Page::containing(before), Page(on - PAGE_SIZE));
+
+        let on = PhysAddr(0x2000);
+        let before = on - 1;
+        assert_eq!(on.align_down(PAGE_SIZE as u64), on);
+        assert_eq!(on.align_up(PAGE_SIZE as u64), on);
+        assert_eq!(before.align_down(PAGE_SIZE as u64), PhysAddr(0x1000));
+        assert_eq!(before.align_up(PAGE_SIZE as u64), on);
+        assert_eq!(Frame::containing(on), Frame(on));
+        assert_eq!(Frame::containing(before), Frame(PhysAddr(0x1000)));
+    }
+
+    #[test_case]
+    fn page_table_indices_decompose_an_address() {
+        let addr = VirtAddr(0xffff_8000_dead_b000);
+        assert_eq!(addr.page_table_index(3), 256);
+        assert_eq!(addr.page_table_index(2), 3);
+        assert_eq!(addr.page_table_index(1), 0x0f5);
+        assert_eq!(addr.page_table_index(0), 0x0db);
+        assert_eq!(addr.offset_in_page(), 0);
+        assert_eq!((addr + 0xabc).offset_in_page(), 0xabc);
     }
 }
@@ -1,12 +1,15 @@
+use alloc::boxed::Box;
 use core::{cell::Cell, iter::Step, num::NonZeroUsize, ops::Range};
 
 use atomic::{Atomic, Ordering};
 
-use crate::types::Page;
+use crate::{spinlock::Spinlock, types::Page};
 
 #[derive(Debug)]
 pub enum VirtAllocError {
     VirtualAddressSpaceExhausted,
+    /// Part of a region that was asked for by address is, or may be, already in use.
+    RegionUnavailable,
 }
 
 pub unsafe trait VirtualRegionAllocator {
@@ -17,6 +20,14 @@ pub unsafe trait VirtualRegionDeallocator {
     unsafe fn deallocate_region(&self, region: Range<Page>);
 }
 
+/// An allocator that can hand out a region at an address chosen by the caller.
+///
+/// Parts of the region outside the range an allocator manages are never handed out by it, so
+/// they count as available.
+pub unsafe trait VirtualRegionReserver {
+    fn reserve_region(&self, region: Range<Page>) -> Result<(), VirtAllocError>;
+}
+
 #[derive(Debug)]
 pub struct BumpAllocator {
     full: Range<Page>,
@@ -30,6 +41,16 @@ impl BumpAllocator {
             pos: Cell::new(full.start),
         }
     }
+
+    /// The number of pages handed out so far.
+    pub fn used(&self) -> usize {
+        Step::steps_between(&self.full.start, &self.pos.get()).unwrap_or(0)
+    }
+
+    /// The number of pages that can still be allocated.
+    pub fn remaining(&self) -> usize {
+        Step::steps_between(&self.pos.get(), &self.full.end).unwrap_or(0)
+    }
 }
 
 unsafe impl VirtualRegionAllocator for BumpAllocator {
@@ -45,6 +66,31 @@ unsafe impl VirtualRegionAllocator for BumpAllocator {
     }
 }
 
+/// Only regions at or above the current position can be reserved, since the allocator doesn't
+/// track which pages below it are still in use. The pages skipped to get there are lost.
+unsafe impl VirtualRegionReserver for BumpAllocator {
+    fn reserve_region(&self, region: Range<Page>) -> Result<(), VirtAllocError> {
+        if region.end <= self.full.start || self.full.end <= region.start {
+            return Ok(());
+        }
+        if region.start < self.pos.get() {
+            return Err(VirtAllocError::RegionUnavailable);
+        }
+        self.pos.set(region.end.min(self.full.end));
+        Ok(())
+    }
+}
+
+/// Only the most recently allocated region can be given back, rewinding the allocator to its
+/// start. Freeing any other region is a no-op and leaves its pages consumed.
+unsafe impl VirtualRegionDeallocator for BumpAllocator {
+    unsafe fn deallocate_region(&self, region: Range<Page>) {
+        if region.end == self.pos.get() && self.full.start <= region.start {
+            self.pos.set(region.start);
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct SyncBumpAllocator {
     full: Range<Page>,
@@ -58,6 +104,18 @@ impl SyncBumpAllocator {
             pos: Atomic::new(full.start),
         }
     }
+
+    /// The number of pages handed out so far.
+    pub fn used(&self) -> usize {
+        let pos = self.pos.load(Ordering::Acquire);
+        Step::steps_between(&self.full.start, &pos).unwrap_or(0)
+    }
+
+    /// The number of pages that can still be allocated.
+    pub fn remaining(&self) -> usize {
+        let pos = self.pos.load(Ordering::Acquire);
+        Step::steps_between(&pos, &self.full.end).unwrap_or(0)
+    }
 }
 
 unsafe impl VirtualRegionAllocator for SyncBumpAllocator {
@@ -82,3 +140,330 @@ unsafe impl VirtualRegionAllocator for SyncBumpAllocator {
         }
     }
 }
+
+/// Like [`BumpAllocator`], this can only reserve regions at or above the current position.
+unsafe impl VirtualRegionReserver for SyncBumpAllocator {
+    fn reserve_region(&self, region: Range<Page>) -> Result<(), VirtAllocError> {
+        if region.end <= self.full.start || self.full.end <= region.start {
+            return Ok(());
+        }
+        let end = region.end.min(self.full.end);
+        self.pos
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |pos| {
+                (pos <= region.start).then_some(end)
+            })
+            .map(|_| ())
+            .map_err(|_| VirtAllocError::RegionUnavailable)
+    }
+}
+
+/// Like [`BumpAllocator`], this only reclaims the most recently allocated region.
+unsafe impl VirtualRegionDeallocator for SyncBumpAllocator {
+    unsafe fn deallocate_region(&self, region: Range<Page>) {
+        if region.start < self.full.start {
+            return;
+        }
+        // Fails harmlessly if another allocation has happened since.
+        _ = self.pos.compare_exchange(
+            region.end,
+            region.start,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        );
+    }
+}
+
+/// A first-fit allocator that keeps freed regions for reuse.
+///
+/// Free regions are kept in a linked list sorted by address, with adjacent regions merged as
+/// they are freed. The nodes are heap allocated, so this can only be used once the kernel heap
+/// is up.
+#[derive(Debug)]
+pub struct FreeListAllocator {
+    /// The number of pages in the whole managed range.
+    size: usize,
+    free: Spinlock<Option<Box<FreeRegion>>>,
+}
+
+#[derive(Debug)]
+struct FreeRegion {
+    region: Range<Page>,
+    next: Option<Box<FreeRegion>>,
+}
+
+impl FreeListAllocator {
+    pub fn new(full: Range<Page>) -> Self {
+        let size = Step::steps_between(&full.start, &full.end).unwrap_or(0);
+        let free = (full.start < full.end).then(|| {
+            Box::new(FreeRegion {
+                region: full,
+                next: None,
+            })
+        });
+        Self {
+            size,
+            free: Spinlock::new(free),
+        }
+    }
+
+    /// The number of pages currently handed out or reserved.
+    pub fn used(&self) -> usize {
+        self.size - self.remaining()
+    }
+
+    /// The number of free pages, which may be split across several regions.
+    pub fn remaining(&self) -> usize {
+        self.free.lock(|head| {
+            let mut pages = 0;
+            let mut node = head.as_deref();
+            while let Some(free) = node {
+                pages += Step::steps_between(&free.region.start, &free.region.end).unwrap_or(0);
+                node = free.next.as_deref();
+            }
+            pages
+        })
+    }
+
+    /// The number of separate free regions.
+    #[cfg(test)]
+    fn free_regions(&self) -> usize {
+        self.free.lock(|head| {
+            let mut count = 0;
+            let mut node = head.as_deref();
+            while let Some(free) = node {
+                count += 1;
+                node = free.next.as_deref();
+            }
+            count
+        })
+    }
+}
+
+unsafe impl VirtualRegionAllocator for FreeListAllocator {
+    fn allocate_region(&self, pages: NonZeroUsize) -> Result<Range<Page>, VirtAllocError> {
+        self.free.lock(|head| {
+            let mut link = head;
+            loop {
+                let node = link
+                    .as_mut()
+                    .ok_or(VirtAllocError::VirtualAddressSpaceExhausted)?;
+
+                let start = node.region.start;
+                match Step::forward_checked(start, pages.get()) {
+                    Some(end) if end < node.region.end => {
+                        node.region.start = end;
+                        return Ok(start..end);
+                    }
+                    Some(end) if end == node.region.end => {
+                        *link = node.next.take();
+                        return Ok(start..end);
+                    }
+                    _ => link = &mut link.as_mut().unwrap().next,
+                }
+            }
+        })
+    }
+}
+
+unsafe impl VirtualRegionDeallocator for FreeListAllocator {
+    unsafe fn deallocate_region(&self, region: Range<Page>) {
+        if region.start >= region.end {
+            return;
+        }
+
+        self.free.lock(|head| {
+            // Find the first free region that ends at or after the freed one starts.
+            let mut link = head;
+            while link
+                .as_ref()
+                .map_or(false, |node| node.region.end < region.start)
+            {
+                link = &mut link.as_mut().unwrap().next;
+            }
+
+            match link {
+                Some(node) if node.region.end == region.start => {
+                    node.region.end = region.end;
+                    if let Some(next) = node.next.take() {
+                        crate::kassert!(region.end <= next.region.start, "double free");
+                        if next.region.start == region.end {
+                            node.region.end = next.region.end;
+                            node.next = next.next;
+                        } else {
+                            node.next = Some(next);
+                        }
+                    }
+                }
+                Some(node) if node.region.start == region.end => {
+                    node.region.start = region.start;
+                }
+                _ => {
+                    if let Some(node) = link {
+                        crate::kassert!(region.end < node.region.start, "double free");
+                    }
+                    let next = link.take();
+                    *link = Some(Box::new(FreeRegion { region, next }));
+                }
+            }
+        });
+    }
+}
+
+unsafe impl VirtualRegionReserver for FreeListAllocator {
+    fn reserve_region(&self, region: Range<Page>) -> Result<(), VirtAllocError> {
+        if region.start >= region.end {
+            return Ok(());
+        }
+
+        self.free.lock(|head| {
+            let mut link = head;
+            while link
+                .as_ref()
+                .map_or(false, |node| node.region.end <= region.start)
+            {
+                link = &mut link.as_mut().unwrap().next;
+            }
+
+            let node = link.as_mut().ok_or(VirtAllocError::RegionUnavailable)?;
+            if region.start < node.region.start || node.region.end < region.end {
+                return Err(VirtAllocError::RegionUnavailable);
+            }
+
+            // Split the free region around the reserved one.
+            let after = region.end..node.region.end;
+            node.region.end = region.start;
+            if after.start < after.end {
+                let next = node.next.take();
+                node.next = Some(Box::new(FreeRegion {
+                    region: after,
+                    next,
+                }));
+            }
+            if node.region.start == node.region.end {
+                *link = node.next.take();
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::VirtAddr;
+
+    const BASE: usize = 0xffff_c000_0000_0000;
+
+    fn region(start: usize, pages: usize) -> Range<Page> {
+        let start = Page(VirtAddr(BASE + start * 4096));
+        start..Step::forward(start, pages)
+    }
+
+    fn count(pages: usize) -> NonZeroUsize {
+        NonZeroUsize::new(pages).unwrap()
+    }
+
+    #[test_case]
+    fn bump_remaining_drops_by_each_allocation() {
+        let allocator = BumpAllocator::new(region(0, 64));
+        assert_eq!((allocator.used(), allocator.remaining()), (0, 64));
+
+        let mut used = 0;
+        for pages in [1, 7, 16, 40] {
+            let allocated = allocator.allocate_region(count(pages)).unwrap();
+            assert_eq!(allocated, region(used, pages));
+            used += pages;
+            assert_eq!(allocator.used(), used);
+            assert_eq!(allocator.remaining(), 64 - used);
+        }
+        assert!(allocator.allocate_region(count(1)).is_err());
+        assert_eq!(allocator.remaining(), 0);
+    }
+
+    #[test_case]
+    fn sync_bump_remaining_drops_by_each_allocation() {
+        let allocator = SyncBumpAllocator::new(region(0, 64));
+        assert_eq!((allocator.used(), allocator.remaining()), (0, 64));
+
+        let mut used = 0;
+        for pages in [1, 7, 16, 40] {
+            let allocated = allocator.allocate_region(count(pages)).unwrap();
+            assert_eq!(allocated, region(used, pages));
+            used += pages;
+            assert_eq!(allocator.used(), used);
+            assert_eq!(allocator.remaining(), 64 - used);
+        }
+        assert!(allocator.allocate_region(count(1)).is_err());
+        assert_eq!(allocator.remaining(), 0);
+    }
+
+    #[test_case]
+    fn bump_rewinds_only_on_lifo_free() {
+        let allocator = BumpAllocator::new(region(0, 64));
+        let first = allocator.allocate_region(count(4)).unwrap();
+        let second = allocator.allocate_region(count(8)).unwrap();
+
+        // Not at the tip, so its pages stay consumed.
+        unsafe { allocator.deallocate_region(first) };
+        assert_eq!(allocator.used(), 12);
+
+        unsafe { allocator.deallocate_region(second) };
+        assert_eq!(allocator.used(), 4);
+        assert_eq!(allocator.allocate_region(count(2)).unwrap(), region(4, 2));
+    }
+
+    #[test_case]
+    fn sync_bump_rewinds_only_on_lifo_free() {
+        let allocator = SyncBumpAllocator::new(region(0, 64));
+        let first = allocator.allocate_region(count(4)).unwrap();
+        let second = allocator.allocate_region(count(8)).unwrap();
+
+        unsafe { allocator.deallocate_region(first) };
+        assert_eq!(allocator.used(), 12);
+
+        unsafe { allocator.deallocate_region(second) };
+        assert_eq!(allocator.used(), 4);
+        assert_eq!(allocator.allocate_region(count(2)).unwrap(), region(4, 2));
+    }
+
+    #[test_case]
+    fn free_list_reuses_freed_regions() {
+        let allocator = FreeListAllocator::new(region(0, 64));
+        let first = allocator.allocate_region(count(8)).unwrap();
+        let second = allocator.allocate_region(count(8)).unwrap();
+        assert_eq!((first.clone(), second), (region(0, 8), region(8, 8)));
+        assert_eq!((allocator.used(), allocator.remaining()), (16, 48));
+
+        unsafe { allocator.deallocate_region(first) };
+        assert_eq!((allocator.used(), allocator.remaining()), (8, 56));
+
+        // First fit, so the hole left by the first region is handed out again.
+        assert_eq!(allocator.allocate_region(count(4)).unwrap(), region(0, 4));
+        assert_eq!(allocator.allocate_region(count(4)).unwrap(), region(4, 4));
+        // The hole is used up, so this comes from past the second region.
+        assert_eq!(allocator.allocate_region(count(2)).unwrap(), region(16, 2));
+        assert_eq!((allocator.used(), allocator.remaining()), (18, 46));
+    }
+
+    #[test_case]
+    fn free_list_coalesces_adjacent_frees() {
+        let allocator = FreeListAllocator::new(region(0, 32));
+        let a = allocator.allocate_region(count(4)).unwrap();
+        let b = allocator.allocate_region(count(4)).unwrap();
+        let c = allocator.allocate_region(count(4)).unwrap();
+        assert_eq!(allocator.free_regions(), 1);
+
+        unsafe { allocator.deallocate_region(a) };
+        assert_eq!(allocator.free_regions(), 2);
+        // Merges into the first hole, with `c` still separating it from the rest.
+        unsafe { allocator.deallocate_region(b) };
+        assert_eq!(allocator.free_regions(), 2);
+        // Closes the gap, merging everything back into one region.
+        unsafe { allocator.deallocate_region(c) };
+        assert_eq!(allocator.free_regions(), 1);
+        assert_eq!(allocator.remaining(), 32);
+
+        assert_eq!(allocator.allocate_region(count(32)).unwrap(), region(0, 32));
+        assert_eq!(allocator.remaining(), 0);
+    }
+}
@@ -3,13 +3,20 @@ use core::arch::asm;
 use bitflags::bitflags;
 
 pub mod apic;
+pub mod cpu;
+pub mod fpu;
+pub mod gdt;
 pub mod hpet;
 pub mod idt;
 pub mod interrupts;
 pub mod local_apic;
+pub mod percpu;
 pub mod pic;
 pub mod pit;
 pub mod segment;
+pub mod smp;
+pub mod syscall;
+pub mod tsc;
 
 #[inline]
 pub unsafe fn out8(port: u16, value: u8) {
@@ -21,6 +28,11 @@ pub unsafe fn out16(port: u16, value: u16) {
     asm!("out dx, ax", in("dx") port, in("ax") value);
 }
 
+#[inline]
+pub unsafe fn out32(port: u16, value: u32) {
+    asm!("out dx, eax", in("dx") port, in("eax") value);
+}
+
 #[inline]
 pub unsafe fn in8(port: u16) -> u8 {
     let value;
@@ -35,6 +47,14 @@ pub unsafe fn in16(port: u16) -> u16 {
     value
 }
 
+/// Waits roughly a microsecond by writing to the unused POST diagnostic port.
+///
+/// This needs no timer calibration, so it stays usable during early boot.
+#[inline]
+pub unsafe fn io_pause() {
+    out8(0x80, 0);
+}
+
 bitflags! {
     #[derive(Debug, Clone, Copy)]
     pub struct RFlags: u64 {
@@ -60,7 +80,67 @@ pub mod cr3 {
     pub fn read() -> Frame {
         let bits: u64;
         unsafe { asm!("mov {}, cr3", out(reg) bits, options(nomem, nostack, preserves_flags)) };
-        Frame(PhysAddr(bits & !0xfff))
+        Frame::containing(PhysAddr(bits))
+    }
+
+    /// Switches to the page tables whose l4 is `frame`.
+    ///
+    /// This flushes every TLB entry except global ones.
+    pub unsafe fn write(frame: Frame) {
+        asm!("mov cr3, {}", in(reg) frame.0 .0, options(nostack, preserves_flags));
+    }
+}
+
+pub mod cr0 {
+    use core::arch::asm;
+
+    use bitflags::bitflags;
+
+    bitflags! {
+        #[derive(Debug, Clone, Copy)]
+        pub struct Cr0: u64 {
+            const MONITOR_COPROCESSOR = 1 << 1;
+            const EMULATE_COPROCESSOR = 1 << 2;
+            const TASK_SWITCHED = 1 << 3;
+            const NUMERIC_ERROR = 1 << 5;
+            /// Makes read-only pages read-only to the kernel too, not just to user code.
+            const WRITE_PROTECT = 1 << 16;
+        }
+    }
+
+    pub fn read() -> Cr0 {
+        let bits: u64;
+        unsafe { asm!("mov {}, cr0", out(reg) bits, options(nomem, nostack, preserves_flags)) };
+        Cr0::from_bits_retain(bits)
+    }
+
+    pub unsafe fn write(cr0: Cr0) {
+        asm!("mov cr0, {}", in(reg) cr0.bits(), options(nostack, preserves_flags));
+    }
+}
+
+pub mod cr4 {
+    use core::arch::asm;
+
+    use bitflags::bitflags;
+
+    bitflags! {
+        #[derive(Debug, Clone, Copy)]
+        pub struct Cr4: u64 {
+            const PAGE_GLOBAL_ENABLE = 1 << 7;
+            const OS_FXSR = 1 << 9;
+            const OS_XMM_EXCEPTIONS = 1 << 10;
+        }
+    }
+
+    pub fn read() -> Cr4 {
+        let bits: u64;
+        unsafe { asm!("mov {}, cr4", out(reg) bits, options(nomem, nostack, preserves_flags)) };
+        Cr4::from_bits_retain(bits)
+    }
+
+    pub unsafe fn write(cr4: Cr4) {
+        asm!("mov cr4, {}", in(reg) cr4.bits(), options(nostack, preserves_flags));
     }
 }
 
@@ -76,6 +156,54 @@ pub mod cr2 {
     }
 }
 
+pub mod efer {
+    use bitflags::bitflags;
+
+    use super::{rdmsr, wrmsr};
+
+    bitflags! {
+        #[derive(Debug, Clone, Copy)]
+        pub struct Efer: u64 {
+            const SYSCALL_ENABLE = 1;
+            const LONG_MODE_ENABLE = 1 << 8;
+            const LONG_MODE_ACTIVE = 1 << 10;
+            const NO_EXECUTE_ENABLE = 1 << 11;
+        }
+    }
+
+    pub fn read() -> Efer {
+        Efer::from_bits_retain(unsafe { rdmsr(IA32_EFER) })
+    }
+
+    pub unsafe fn write(efer: Efer) {
+        wrmsr(IA32_EFER, efer.bits());
+    }
+
+    const IA32_EFER: u32 = 0xc0000080;
+}
+
+/// Turns on EFER.NXE, making bit 63 of page table entries the no-execute bit.
+///
+/// Returns false, leaving EFER untouched, if the processor doesn't support no-execute pages.
+pub unsafe fn enable_nxe() -> bool {
+    let supported = cpu::features().nx;
+    if supported {
+        efer::write(efer::read() | efer::Efer::NO_EXECUTE_ENABLE);
+    }
+    supported
+}
+
+/// Turns on CR4.PGE, so that global page table entries survive CR3 reloads.
+///
+/// Returns false, leaving CR4 untouched, if the processor doesn't support global pages.
+pub unsafe fn enable_global_pages() -> bool {
+    let supported = cpu::features().pge;
+    if supported {
+        cr4::write(cr4::read() | cr4::Cr4::PAGE_GLOBAL_ENABLE);
+    }
+    supported
+}
+
 pub unsafe fn rdmsr(msr: u32) -> u64 {
     let high: u64;
     let low: u64;
@@ -90,19 +218,35 @@ pub unsafe fn wrmsr(msr: u32, value: u64) {
     asm!("wrmsr", in("ecx") msr, in("eax") low, in("edx") high);
 }
 
+/// The state [`context_switch`] leaves at the top of a suspended thread's stack, lowest address
+/// first. The stack pointer saved for the thread points at it.
 #[repr(C)]
 #[derive(Debug, Default)]
 pub struct TaskState {
-    pub rbx: usize,
-    pub rbp: usize,
-    pub rdi: usize,
-    pub rsi: usize,
-    pub r12: usize,
-    pub r13: usize,
-    pub r14: usize,
     pub r15: usize,
+    pub r14: usize,
+    pub r13: usize,
+    pub r12: usize,
+    pub rsi: usize,
+    pub rdi: usize,
+    pub rbp: usize,
+    pub rbx: usize,
+    /// Where the thread resumes.
+    pub rip: usize,
 }
 
+/// Saves the current thread's state on its stack and stores the stack pointer to `from`, then
+/// resumes the thread whose saved state is at `to`.
+///
+/// This is a naked function so that the stack layout is exactly [`TaskState`]. It pushes the
+/// callee-saved registers, along with `rdi` and `rsi` so that a fresh thread can be handed an
+/// argument, switches stacks, and pops the same registers for the other thread. The final `ret`
+/// then uses the return address on the new stack: for a thread that switched away earlier, that
+/// is the instruction after its own call to `context_switch`, so from its point of view the call
+/// simply returns later. The scratch registers are clobbered as by any `extern "C"` call.
+///
+/// The x87 and SSE control words aren't saved, so threads must not change them.
+#[naked]
 pub unsafe extern "C" fn context_switch(from: *mut *const TaskState, to: *const TaskState) {
     asm!(
         "push rbx",
@@ -113,8 +257,8 @@ pub unsafe extern "C" fn context_switch(from: *mut *const TaskState, to: *const
         "push r13",
         "push r14",
         "push r15",
-        "mov [{from}], rsp",
-        "mov rsp, {to}",
+        "mov [rdi], rsp",
+        "mov rsp, rsi",
         "pop r15",
         "pop r14",
         "pop r13",
@@ -123,7 +267,7 @@ pub unsafe extern "C" fn context_switch(from: *mut *const TaskState, to: *const
         "pop rdi",
         "pop rbp",
         "pop rbx",
-        from = in(reg) from,
-        to = in(reg) to,
+        "ret",
+        options(noreturn)
     );
 }
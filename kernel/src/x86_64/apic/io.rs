@@ -0,0 +1,130 @@
+use core::ptr::NonNull;
+
+use super::local::LocalApicId;
+use crate::{
+    acpi::{self, madt, AcpiError},
+    types::PhysAddr,
+};
+
+/// Why the IO APIC couldn't be found.
+#[derive(Debug)]
+pub enum FindIoApicError {
+    AcpiError(AcpiError),
+    NoMadt,
+    /// The MADT lists no IO APIC whose inputs start at global system interrupt 0, which is where
+    /// the legacy ISA IRQs are.
+    NoIoApic,
+}
+
+impl From<AcpiError> for FindIoApicError {
+    fn from(value: AcpiError) -> Self {
+        Self::AcpiError(value)
+    }
+}
+
+/// Where an IO APIC delivers one interrupt line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RedirectionEntry {
+    pub vector: u8,
+    /// The local APIC the interrupt is delivered to, in physical destination mode.
+    pub dest: LocalApicId,
+    pub masked: bool,
+}
+
+/// An IO APIC, which routes external interrupt lines to local APIC vectors.
+#[derive(Debug)]
+pub struct IoApic {
+    base: NonNull<u32>,
+}
+
+unsafe impl Send for IoApic {}
+
+impl IoApic {
+    /// Returns the address of the IO APIC that the legacy ISA IRQs are wired to, as listed in
+    /// the MADT.
+    pub fn physical_address() -> Result<PhysAddr, FindIoApicError> {
+        let madt = acpi::find_table(madt::SIGNATURE)?.ok_or(FindIoApicError::NoMadt)?;
+        madt::io_apics(&madt)
+            .find(|io_apic| io_apic.gsi_base == 0)
+            .map(|io_apic| io_apic.address)
+            .ok_or(FindIoApicError::NoIoApic)
+    }
+
+    pub unsafe fn with_address(addr: NonNull<()>) -> Self {
+        Self { base: addr.cast() }
+    }
+
+    /// The number of interrupt lines this IO APIC has redirection entries for.
+    pub fn max_redirection_entries(&self) -> u8 {
+        let version = unsafe { self.read(VERSION_REGISTER) };
+        (version.wrapping_shr(16) & 0xff) as u8 + 1
+    }
+
+    pub fn read_redirection(&self, irq: u8) -> RedirectionEntry {
+        let register = redirection_register(irq);
+        let (low, high) = unsafe { (self.read(register), self.read(register + 1)) };
+        RedirectionEntry {
+            vector: low as u8,
+            dest: LocalApicId(high.wrapping_shr(24)),
+            masked: low & REDIRECTION_MASKED != 0,
+        }
+    }
+
+    /// Routes `irq` to `vector` on the local APIC `dest`, with fixed delivery and the default
+    /// edge-triggered, active-high polarity.
+    pub unsafe fn write_redirection(
+        &mut self,
+        irq: u8,
+        vector: u8,
+        dest: LocalApicId,
+        masked: bool,
+    ) {
+        let register = redirection_register(irq);
+        let mut low = u32::from(vector);
+        if masked {
+            low |= REDIRECTION_MASKED;
+        }
+
+        // Mask the line while the halves disagree, so it can't fire with a half-written entry.
+        self.write(register, REDIRECTION_MASKED);
+        self.write(register + 1, dest.0.wrapping_shl(24));
+        self.write(register, low);
+    }
+
+    pub unsafe fn set_masked(&mut self, irq: u8, masked: bool) {
+        let register = redirection_register(irq);
+        let low = self.read(register);
+        let low = if masked {
+            low | REDIRECTION_MASKED
+        } else {
+            low & !REDIRECTION_MASKED
+        };
+        self.write(register, low);
+    }
+
+    pub unsafe fn set_vector(&mut self, irq: u8, vector: u8) {
+        let register = redirection_register(irq);
+        let low = self.read(register);
+        self.write(register, (low & !0xff) | u32::from(vector));
+    }
+
+    unsafe fn read(&self, register: u32) -> u32 {
+        self.base.as_ptr().write_volatile(register);
+        self.base.as_ptr().add(DATA_OFFSET).read_volatile()
+    }
+
+    unsafe fn write(&self, register: u32, value: u32) {
+        self.base.as_ptr().write_volatile(register);
+        self.base.as_ptr().add(DATA_OFFSET).write_volatile(value);
+    }
+}
+
+/// Returns the register holding the low half of the redirection entry for `irq`.
+fn redirection_register(irq: u8) -> u32 {
+    0x10 + 2 * u32::from(irq)
+}
+
+/// Offset of the data window from the register select window, in units of `u32`.
+const DATA_OFFSET: usize = 0x10 / 4;
+const VERSION_REGISTER: u32 = 0x01;
+const REDIRECTION_MASKED: u32 = 1 << 16;
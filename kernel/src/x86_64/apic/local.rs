@@ -1,30 +1,177 @@
-use core::{arch::x86_64::__cpuid, ptr::NonNull};
+use core::ptr::NonNull;
 
 use bitflags::bitflags;
 
 use crate::{
+    address_space::{AddrSpace, AllocError, MapOptions},
     hhdm::Hhdm,
+    spinlock::Spinlock,
+    time::TimeSource,
     types::{PhysAddr, VirtAddr},
-    x86_64::{rdmsr, wrmsr},
+    x86_64::{cpu, rdmsr, wrmsr},
 };
 
+/// Where the xAPIC registers are mapped. Every processor's local APIC answers at the same
+/// physical address, so the first to enable one maps it and the rest share the mapping.
+static XAPIC_REGISTERS: Spinlock<Option<VirtAddr>> = Spinlock::new(None);
+
 #[derive(Debug)]
 pub enum LocalApicP {
     XApic(LocalApic<XApic>),
     X2Apic(LocalApic<X2Apic>),
 }
 
+impl LocalApicP {
+    /// Enables the local APIC in x2APIC mode if the processor supports it, or else in xAPIC mode
+    /// with its registers mapped into the kernel address space.
+    pub unsafe fn detect() -> Result<LocalApicP, ApicEnableError> {
+        if cpu::features().x2apic {
+            log::debug!("using the local apic in x2apic mode");
+            return Ok(LocalApicP::X2Apic(LocalApic::enable(X2Apic)?));
+        }
+
+        let address = XAPIC_REGISTERS.lock(|registers| -> Result<VirtAddr, AllocError> {
+            if let Some(address) = *registers {
+                return Ok(address);
+            }
+            let map_options = MapOptions {
+                writable: true,
+                ..Default::default()
+            };
+            let address =
+                AddrSpace::kernel().map_mmio(XApic::physical_address(), 4096, map_options)?;
+            let address = VirtAddr(address.as_ptr() as usize);
+            *registers = Some(address);
+            Ok(address)
+        })?;
+        log::debug!("using the local apic in xapic mode");
+        let address = NonNull::new(address.as_ptr()).expect("the xapic is mapped at null");
+        Ok(LocalApicP::XApic(LocalApic::enable(XApic::with_address(
+            address,
+        ))?))
+    }
+
+    pub fn id(&self) -> LocalApicId {
+        match self {
+            LocalApicP::XApic(lapic) => lapic.id(),
+            LocalApicP::X2Apic(lapic) => lapic.id(),
+        }
+    }
+
+    pub unsafe fn end_of_interrupt(&mut self) {
+        match self {
+            LocalApicP::XApic(lapic) => lapic.end_of_interrupt(),
+            LocalApicP::X2Apic(lapic) => lapic.end_of_interrupt(),
+        }
+    }
+
+    pub unsafe fn calibrate(&mut self, reference: &mut impl TimeSource) {
+        match self {
+            LocalApicP::XApic(lapic) => lapic.calibrate(reference),
+            LocalApicP::X2Apic(lapic) => lapic.calibrate(reference),
+        }
+    }
+
+    pub unsafe fn set_frequency(&mut self, hz: u32) {
+        match self {
+            LocalApicP::XApic(lapic) => lapic.set_frequency(hz),
+            LocalApicP::X2Apic(lapic) => lapic.set_frequency(hz),
+        }
+    }
+
+    pub unsafe fn set_lint(
+        &mut self,
+        index: LintIndex,
+        vector: u8,
+        mode: DeliveryMode,
+        masked: bool,
+    ) {
+        match self {
+            LocalApicP::XApic(lapic) => lapic.set_lint(index, vector, mode, masked),
+            LocalApicP::X2Apic(lapic) => lapic.set_lint(index, vector, mode, masked),
+        }
+    }
+
+    pub fn lint_masked(&self, index: LintIndex) -> bool {
+        match self {
+            LocalApicP::XApic(lapic) => lapic.lint_masked(index),
+            LocalApicP::X2Apic(lapic) => lapic.lint_masked(index),
+        }
+    }
+
+    pub unsafe fn error_status(&mut self) -> ApicErrors {
+        match self {
+            LocalApicP::XApic(lapic) => lapic.error_status(),
+            LocalApicP::X2Apic(lapic) => lapic.error_status(),
+        }
+    }
+
+    pub unsafe fn send_ipi(&mut self, dest: LocalApicId, vector: u8) {
+        match self {
+            LocalApicP::XApic(lapic) => lapic.send_ipi(dest, vector),
+            LocalApicP::X2Apic(lapic) => lapic.send_ipi(dest, vector),
+        }
+    }
+
+    pub unsafe fn send_init(&mut self, dest: LocalApicId) {
+        match self {
+            LocalApicP::XApic(lapic) => lapic.send_init(dest),
+            LocalApicP::X2Apic(lapic) => lapic.send_init(dest),
+        }
+    }
+
+    pub unsafe fn send_startup(&mut self, dest: LocalApicId, vector: u8) {
+        match self {
+            LocalApicP::XApic(lapic) => lapic.send_startup(dest, vector),
+            LocalApicP::X2Apic(lapic) => lapic.send_startup(dest, vector),
+        }
+    }
+}
+
+/// The vector the local APIC raises spurious interrupts on.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// The vector APIC error interrupts are delivered on.
+pub const ERROR_VECTOR: u8 = 0xfe;
+
+/// The vector the timer interrupt is delivered on.
+pub const TIMER_VECTOR: u8 = 32;
+
 #[derive(Debug)]
 pub struct UnsupportedError;
 
+bitflags! {
+    /// The contents of the error status register.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ApicErrors: u32 {
+        const SEND_CHECKSUM = 1;
+        const RECEIVE_CHECKSUM = 1 << 1;
+        const SEND_ACCEPT = 1 << 2;
+        const RECEIVE_ACCEPT = 1 << 3;
+        const REDIRECTABLE_IPI = 1 << 4;
+        const SEND_ILLEGAL_VECTOR = 1 << 5;
+        const RECEIVE_ILLEGAL_VECTOR = 1 << 6;
+        const ILLEGAL_REGISTER_ADDRESS = 1 << 7;
+    }
+}
+
 #[derive(Debug)]
 pub enum ApicEnableError {
     Unsupported,
+    AllocError(AllocError),
+}
+
+impl From<AllocError> for ApicEnableError {
+    fn from(value: AllocError) -> Self {
+        Self::AllocError(value)
+    }
 }
 
 #[derive(Debug)]
 pub struct LocalApic<A> {
     address_space: A,
+    /// Timer ticks per millisecond at divide-by-16, or zero before calibration.
+    timer_ticks_per_ms: u32,
 }
 
 impl<A> LocalApic<A>
@@ -32,17 +179,23 @@ where
     A: ApicAddressSpace,
 {
     pub unsafe fn enable(address_space: A) -> Result<Self, ApicEnableError> {
-        let spurious_interrupt_vector = 0xff;
         let spurious_interrupt_vector_register = 0xf;
 
         address_space.enable()?;
 
+        // Set the spurious vector and the software enable bit.
         address_space.write(
             spurious_interrupt_vector_register,
-            address_space.read(spurious_interrupt_vector_register) | 0x1ff,
+            address_space.read(spurious_interrupt_vector_register)
+                | u32::from(SPURIOUS_VECTOR)
+                | 0x100,
         );
+        address_space.write(0x37, u32::from(ERROR_VECTOR));
 
-        Ok(Self { address_space })
+        Ok(Self {
+            address_space,
+            timer_ticks_per_ms: 0,
+        })
     }
 
     pub fn id(&self) -> LocalApicId {
@@ -53,13 +206,107 @@ where
         unsafe { self.address_space.read(0x3) as u8 }
     }
 
-    pub fn enable_timer(&mut self) {
-        let entry_bits = pack_timer_lvt_entry(32, TimerMode::Periodic, TriggerMode::Edge, false);
-        unsafe {
-            self.address_space.write(0x38, 0x1);
-            self.address_space.write(0x3e, 0x3);
-            self.address_space.write(0x32, entry_bits);
-        };
+    pub unsafe fn end_of_interrupt(&mut self) {
+        self.address_space.write(0xb, 0);
+    }
+
+    /// Programs the local vector table entry for one of the LINT pins.
+    ///
+    /// `vector` is ignored by the NMI, SMI and ExtINT delivery modes.
+    pub unsafe fn set_lint(
+        &mut self,
+        index: LintIndex,
+        vector: u8,
+        mode: DeliveryMode,
+        masked: bool,
+    ) {
+        let mut bits = u32::from(vector) | ((mode as u32) << 8);
+        if masked {
+            bits |= LVT_MASKED;
+        }
+        self.address_space.write(index as u32, bits);
+    }
+
+    /// Returns whether the LINT pin's local vector table entry is masked.
+    pub fn lint_masked(&self, index: LintIndex) -> bool {
+        unsafe { self.address_space.read(index as u32) & LVT_MASKED != 0 }
+    }
+
+    /// Returns the errors the APIC has detected since the last call.
+    pub unsafe fn error_status(&mut self) -> ApicErrors {
+        // The register only reflects new errors after a write.
+        self.address_space.write(0x28, 0);
+        ApicErrors::from_bits_retain(self.address_space.read(0x28))
+    }
+
+    /// Programs the timer. Writing the initial count starts it, so a count of zero leaves it
+    /// stopped.
+    pub unsafe fn configure_timer(&mut self, config: TimerConfig) {
+        let entry_bits =
+            pack_timer_lvt_entry(config.vector, config.mode, TriggerMode::Edge, config.masked);
+        self.address_space.write(0x3e, config.divider as u32);
+        self.address_space.write(0x32, entry_bits);
+        self.address_space.write(0x38, config.initial_count);
+    }
+
+    /// Measures the timer's rate against `reference`.
+    ///
+    /// The timer is left stopped.
+    pub unsafe fn calibrate(&mut self, reference: &mut impl TimeSource) {
+        const CALIBRATION_MS: u32 = 10;
+
+        self.configure_timer(TimerConfig {
+            vector: TIMER_VECTOR,
+            mode: TimerMode::OneShot,
+            divider: TimerDivider::By16,
+            initial_count: u32::MAX,
+            masked: true,
+        });
+
+        reference.wait_us(u64::from(CALIBRATION_MS) * 1000);
+
+        let elapsed = u32::MAX - self.address_space.read(0x39);
+        self.address_space.write(0x38, 0);
+
+        self.timer_ticks_per_ms = elapsed / CALIBRATION_MS;
+        log::debug!(
+            "apic timer: {} ticks/ms at divide-by-16",
+            self.timer_ticks_per_ms
+        );
+    }
+
+    /// Starts the timer firing periodically on [`TIMER_VECTOR`] at `hz` interrupts per second.
+    pub unsafe fn set_frequency(&mut self, hz: u32) {
+        assert!(self.timer_ticks_per_ms != 0, "apic timer not calibrated");
+
+        let count = (u64::from(self.timer_ticks_per_ms) * 1000 / u64::from(hz)).max(1);
+        self.configure_timer(TimerConfig {
+            vector: TIMER_VECTOR,
+            mode: TimerMode::Periodic,
+            divider: TimerDivider::By16,
+            initial_count: count.try_into().unwrap_or(u32::MAX),
+            masked: false,
+        });
+    }
+
+    /// Sends a fixed interrupt on `vector` to the local APIC `dest`.
+    pub unsafe fn send_ipi(&mut self, dest: LocalApicId, vector: u8) {
+        self.send_command(dest, u32::from(vector) | ICR_FIXED);
+    }
+
+    /// Sends an INIT, which resets the processor `dest` to wait for a startup IPI.
+    pub unsafe fn send_init(&mut self, dest: LocalApicId) {
+        self.send_command(dest, ICR_INIT);
+    }
+
+    /// Sends a startup IPI, which starts the processor `dest` in real mode at `vector * 0x1000`.
+    pub unsafe fn send_startup(&mut self, dest: LocalApicId, vector: u8) {
+        self.send_command(dest, u32::from(vector) | ICR_STARTUP);
+    }
+
+    unsafe fn send_command(&mut self, dest: LocalApicId, command: u32) {
+        let value = (u64::from(dest.0) << 32) | u64::from(command | ICR_LEVEL_ASSERT);
+        self.address_space.write_icr(value);
     }
 }
 
@@ -89,21 +336,66 @@ enum TriggerMode {
     Level,
 }
 
+/// A local interrupt pin, as the register index of its local vector table entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LintIndex {
+    Lint0 = 0x35,
+    Lint1 = 0x36,
+}
+
+/// How an interrupt from a local vector table entry is delivered, in its bits 8-10 encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryMode {
+    Fixed = 0b000,
+    Smi = 0b010,
+    Nmi = 0b100,
+    ExtInt = 0b111,
+}
+
 #[derive(Debug, Clone, Copy)]
-enum TimerMode {
+pub struct TimerConfig {
+    pub vector: u8,
+    pub mode: TimerMode,
+    pub divider: TimerDivider,
+    /// The count the timer starts from, at the rate of the bus clock over `divider`.
+    pub initial_count: u32,
+    pub masked: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
     OneShot,
     Periodic,
+    /// Fires when the TSC reaches the value written to the IA32_TSC_DEADLINE MSR; the initial
+    /// count is ignored.
     TscDeadline,
 }
 
-#[derive(Debug, Clone, Copy)]
-pub struct LocalApicId(u32);
+/// The divide configuration register encodings, which skip bit 2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerDivider {
+    By1 = 0b1011,
+    By2 = 0b0000,
+    By4 = 0b0001,
+    By8 = 0b0010,
+    By16 = 0b0011,
+    By32 = 0b1000,
+    By64 = 0b1001,
+    By128 = 0b1010,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalApicId(pub u32);
 
 pub unsafe trait ApicAddressSpace {
     unsafe fn id(&self) -> LocalApicId;
     unsafe fn enable(&self) -> Result<(), ApicEnableError>;
     unsafe fn read(&self, register_index: u32) -> u32;
     unsafe fn write(&self, register_index: u32, value: u32);
+
+    /// Writes the interrupt command register, sending an IPI. The destination APIC ID is taken
+    /// from the upper 32 bits of `value`.
+    unsafe fn write_icr(&self, value: u64);
 }
 
 #[derive(Debug)]
@@ -111,6 +403,8 @@ pub struct XApic {
     base: NonNull<Register>,
 }
 
+unsafe impl Send for XApic {}
+
 impl XApic {
     pub fn physical_address() -> PhysAddr {
         XAPIC_BASE_ADDRESS
@@ -134,8 +428,7 @@ unsafe impl ApicAddressSpace for XApic {
     }
 
     unsafe fn enable(&self) -> Result<(), ApicEnableError> {
-        let supported = unsafe { __cpuid(1).edx & (1 << 9) != 0 };
-        if !supported {
+        if !cpu::features().apic {
             return Err(ApicEnableError::Unsupported);
         }
 
@@ -159,6 +452,16 @@ unsafe impl ApicAddressSpace for XApic {
             .add(register_index as usize)
             .write_volatile(Register(value));
     }
+
+    unsafe fn write_icr(&self, value: u64) {
+        // Writing the low half sends the IPI, so the destination must be in place first.
+        self.write(0x31, (value.wrapping_shr(32) as u32).wrapping_shl(24));
+        self.write(0x30, value as u32);
+
+        while self.read(0x30) & ICR_SEND_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -171,8 +474,7 @@ unsafe impl ApicAddressSpace for X2Apic {
     }
 
     unsafe fn enable(&self) -> Result<(), ApicEnableError> {
-        let supported = unsafe { __cpuid(1).ecx & (1 << 21) != 0 };
-        if !supported {
+        if !cpu::features().x2apic {
             return Err(ApicEnableError::Unsupported);
         }
 
@@ -187,12 +489,24 @@ unsafe impl ApicAddressSpace for X2Apic {
     unsafe fn write(&self, register_index: u32, value: u32) {
         wrmsr(X2APIC_MSR_BASE + register_index, value.into());
     }
+
+    unsafe fn write_icr(&self, value: u64) {
+        wrmsr(X2APIC_MSR_BASE + 0x30, value);
+    }
 }
 
 #[repr(C, align(16))]
 #[derive(Debug, Clone, Copy)]
 struct Register(u32);
 
+const LVT_MASKED: u32 = 1 << 16;
+
+const ICR_FIXED: u32 = 0;
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_SEND_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+
 const X2APIC_MSR_BASE: u32 = 0x800;
 const IA32_APIC_BASE: u32 = 0x1b;
 const XAPIC_BASE_ADDRESS: PhysAddr = PhysAddr(0xfee00000);
@@ -246,3 +560,95 @@ const XAPIC_BASE_ADDRESS: PhysAddr = PhysAddr(0xfee00000);
 //         unsafe { self.0.as_ptr().read_volatile() }
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+    use crate::{
+        interrupts,
+        x86_64::interrupts::{mark_routed, with_controller, Controller},
+    };
+
+    /// Runs `f` with this CPU's local APIC, or does nothing on the PIC fallback.
+    fn with_local_apic(f: impl FnOnce(&mut LocalApicP)) {
+        with_controller(|controller| match controller {
+            Controller::Apic(apic) => f(apic.local_apic()),
+            Controller::Pic(_) => {}
+        });
+    }
+
+    static IPI_RECEIVED: AtomicBool = AtomicBool::new(false);
+
+    #[test_case]
+    fn self_ipi_runs_the_handler() {
+        const VECTOR: u8 = 0xf2;
+
+        let mut sent = false;
+        IPI_RECEIVED.store(false, Ordering::Relaxed);
+        interrupts::register(VECTOR, |_| IPI_RECEIVED.store(true, Ordering::Relaxed));
+        // The local APIC delivers the IPI, so it has to be acknowledged.
+        mark_routed(VECTOR);
+        with_local_apic(|lapic| {
+            let id = lapic.id();
+            unsafe { lapic.send_ipi(id, VECTOR) };
+            sent = true;
+        });
+        if !sent {
+            return;
+        }
+
+        // The IPI stays pending until interrupts are enabled.
+        unsafe { interrupts::enable() };
+        for _ in 0..1_000_000 {
+            if IPI_RECEIVED.load(Ordering::Relaxed) {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        interrupts::disable();
+        assert!(
+            IPI_RECEIVED.load(Ordering::Relaxed),
+            "self-ipi never arrived"
+        );
+    }
+
+    #[test_case]
+    fn healthy_apic_reports_no_errors() {
+        with_local_apic(|lapic| {
+            let errors = unsafe { lapic.error_status() };
+            assert_eq!(errors, ApicErrors::empty());
+        });
+    }
+
+    /// Reads or writes a raw register, for saving and restoring what a test changes.
+    unsafe fn register(lapic: &LocalApicP, index: u32, value: Option<u32>) -> u32 {
+        let space: &dyn ApicAddressSpace = match lapic {
+            LocalApicP::XApic(lapic) => &lapic.address_space,
+            LocalApicP::X2Apic(lapic) => &lapic.address_space,
+        };
+        if let Some(value) = value {
+            space.write(index, value);
+        }
+        space.read(index)
+    }
+
+    #[test_case]
+    fn lint0_mask_reads_back() {
+        with_local_apic(|lapic| unsafe {
+            let index = LintIndex::Lint0;
+            let saved = register(lapic, index as u32, None);
+
+            // Only ever masked, since an unmasked LINT0 could let the 8259 deliver on the vector.
+            lapic.set_lint(index, 0xf3, DeliveryMode::Fixed, true);
+            let masked = lapic.lint_masked(index);
+            let value = register(lapic, index as u32, None);
+            register(lapic, index as u32, Some(saved));
+
+            assert!(masked);
+            assert_eq!(value & 0x7ff, 0xf3, "vector or delivery mode lost");
+            assert_eq!(lapic.lint_masked(index), saved & LVT_MASKED != 0);
+        });
+    }
+}
@@ -0,0 +1,94 @@
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+
+use spin::Lazy;
+
+static FEATURES: Lazy<Features> = Lazy::new(|| {
+    let features = unsafe { Features::detect() };
+    log::debug!("cpu features: {:?}", features);
+    features
+});
+
+/// The processor features the kernel cares about, as reported by CPUID.
+#[derive(Debug, Clone, Copy)]
+pub struct Features {
+    pub apic: bool,
+    pub x2apic: bool,
+    pub tsc_deadline: bool,
+    pub nx: bool,
+    pub pge: bool,
+    pub pcid: bool,
+    pub fxsr: bool,
+    pub sse: bool,
+    pub sse2: bool,
+    pub fsgsbase: bool,
+    pub rdrand: bool,
+}
+
+impl Features {
+    unsafe fn detect() -> Self {
+        let leaf1 = __cpuid(1);
+        let max_leaf = __cpuid(0).eax;
+        let leaf7_ebx = if max_leaf >= 7 { __cpuid(7).ebx } else { 0 };
+        let max_extended_leaf = __cpuid(0x8000_0000).eax;
+        let extended1_edx = if max_extended_leaf >= 0x8000_0001 {
+            __cpuid(0x8000_0001).edx
+        } else {
+            0
+        };
+
+        let bit = |register: u32, bit: u32| register & (1 << bit) != 0;
+        Self {
+            apic: bit(leaf1.edx, 9),
+            x2apic: bit(leaf1.ecx, 21),
+            tsc_deadline: bit(leaf1.ecx, 24),
+            nx: bit(extended1_edx, 20),
+            pge: bit(leaf1.edx, 13),
+            pcid: bit(leaf1.ecx, 17),
+            fxsr: bit(leaf1.edx, 24),
+            sse: bit(leaf1.edx, 25),
+            sse2: bit(leaf1.edx, 26),
+            fsgsbase: bit(leaf7_ebx, 0),
+            rdrand: bit(leaf1.ecx, 30),
+        }
+    }
+}
+
+/// Returns the features of the processor, detected on first use.
+///
+/// Only the boot processor is asked, on the assumption that every processor is the same.
+pub fn features() -> &'static Features {
+    &FEATURES
+}
+
+/// Returns the calling processor's local APIC ID.
+///
+/// CPUID leaf 0xb reports the full 32-bit x2APIC ID. Leaf 1 only has the low 8 bits, which is
+/// all an xAPIC ID has, so it is the fallback for processors without leaf 0xb.
+pub fn apic_id() -> u32 {
+    unsafe {
+        if __cpuid(0).eax >= 0xb {
+            let topology = __cpuid_count(0xb, 0);
+            // A leaf with no topology levels is reported as all zeroes.
+            if topology.ebx != 0 {
+                return topology.edx;
+            }
+        }
+        __cpuid(1).ebx >> 24
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn features_are_detected() {
+        let features = features();
+        // Printed for comparing against the `-cpu` flags QEMU was started with.
+        log::info!("cpu features: {:#?}", features);
+
+        // Every x86_64 processor has these.
+        assert!(features.fxsr && features.sse && features.sse2);
+        assert!(core::ptr::eq(features, super::features()), "not cached");
+    }
+}
@@ -0,0 +1,72 @@
+use core::arch::asm;
+
+use super::{
+    cpu,
+    cr0::{self, Cr0},
+    cr4::{self, Cr4},
+};
+
+/// Turns on the x87 FPU and SSE, and resets the FPU.
+///
+/// Afterwards x87 and SSE instructions execute natively, and their floating point errors raise
+/// #MF and #XM instead of the legacy external interrupt. The kernel's target is soft-float, so
+/// the compiler doesn't emit these instructions on its own, but this must still run before any
+/// code that uses them explicitly, or before the first switch to user code.
+pub unsafe fn init() {
+    assert!(
+        cpu::features().fxsr && cpu::features().sse,
+        "sse is unsupported"
+    );
+
+    let cr0 = cr0::read() - Cr0::EMULATE_COPROCESSOR - Cr0::TASK_SWITCHED;
+    cr0::write(cr0 | Cr0::MONITOR_COPROCESSOR | Cr0::NUMERIC_ERROR);
+    cr4::write(cr4::read() | Cr4::OS_FXSR | Cr4::OS_XMM_EXCEPTIONS);
+
+    asm!("fninit", options(nomem, nostack));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The target is soft-float, so the compiler never keeps anything in the x87 or XMM registers
+    // these clobber, and plain `f64` arithmetic wouldn't touch the FPU at all.
+
+    #[test_case]
+    fn sse_computes() {
+        let (a, b) = (2.25f64, 4.0f64);
+        let mut result = 0f64;
+        unsafe {
+            asm!(
+                "movsd xmm0, [{a}]",
+                "mulsd xmm0, [{b}]",
+                "sqrtsd xmm0, xmm0",
+                "movsd [{result}], xmm0",
+                a = in(reg) &a,
+                b = in(reg) &b,
+                result = in(reg) &mut result,
+                options(nostack, preserves_flags)
+            )
+        };
+        assert_eq!(result, 3.0);
+    }
+
+    #[test_case]
+    fn x87_computes() {
+        let (a, b) = (6.25f64, 16.0f64);
+        let mut result = 0f64;
+        unsafe {
+            asm!(
+                "fld qword ptr [{a}]",
+                "fmul qword ptr [{b}]",
+                "fsqrt",
+                "fstp qword ptr [{result}]",
+                a = in(reg) &a,
+                b = in(reg) &b,
+                result = in(reg) &mut result,
+                options(nostack)
+            )
+        };
+        assert_eq!(result, 10.0);
+    }
+}
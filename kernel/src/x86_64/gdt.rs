@@ -0,0 +1,297 @@
+use alloc::boxed::Box;
+use core::{
+    arch::asm,
+    mem,
+    num::NonZeroUsize,
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+use crate::{
+    address_space::{AddrSpace, AllocError},
+    types::VirtAddr,
+    x86_64::{
+        percpu,
+        segment::{code, Selector},
+    },
+};
+
+pub const KERNEL_CODE: Selector = Selector(0x08);
+pub const KERNEL_DATA: Selector = Selector(0x10);
+/// User data comes right before user code, the order `sysret` expects them in.
+pub const USER_DATA: Selector = Selector(0x18 | 3);
+pub const USER_CODE: Selector = Selector(0x20 | 3);
+pub const TSS: Selector = Selector(0x28);
+
+/// Interrupt stack used by the double fault handler, which can't trust the interrupted stack.
+/// Index 0 is the TSS's IST1.
+pub const DOUBLE_FAULT_IST: u16 = 0;
+/// Interrupt stack used by the NMI handler, which can fire at any instruction.
+pub const NMI_IST: u16 = 1;
+/// Interrupt stack used by the machine check handler, which can fire at any instruction.
+pub const MACHINE_CHECK_IST: u16 = 2;
+
+const IST_COUNT: usize = 3;
+const IST_PAGES: usize = 4;
+/// The size of the stack each CPU enters the kernel on from ring 3, until a thread's own kernel
+/// stack is set with [`set_kernel_stack`].
+const KERNEL_STACK_PAGES: usize = 16;
+/// The most CPUs that can have tables set up, and so the most the kernel can run on.
+pub const MAX_CPUS: usize = 64;
+
+static CPUS: [AtomicPtr<CpuTables>; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NONE: AtomicPtr<CpuTables> = AtomicPtr::new(ptr::null_mut());
+    [NONE; MAX_CPUS]
+};
+
+/// The 64-bit task state segment.
+#[repr(C, packed(4))]
+#[derive(Debug, Clone, Copy)]
+pub struct TaskStateSegment {
+    _reserved0: u32,
+    pub privilege_stacks: [u64; 3],
+    _reserved1: u64,
+    pub interrupt_stacks: [u64; 7],
+    _reserved2: u64,
+    _reserved3: u16,
+    pub iomap_base: u16,
+}
+
+const _: () = assert!(mem::size_of::<TaskStateSegment>() == 104);
+
+impl TaskStateSegment {
+    pub const fn new() -> Self {
+        Self {
+            _reserved0: 0,
+            privilege_stacks: [0; 3],
+            _reserved1: 0,
+            interrupt_stacks: [0; 7],
+            _reserved2: 0,
+            _reserved3: 0,
+            // No IO permission bitmap.
+            iomap_base: mem::size_of::<TaskStateSegment>() as u16,
+        }
+    }
+}
+
+impl Default for TaskStateSegment {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A CPU's GDT and the TSS it refers to, which must live as long as the CPU uses them.
+#[repr(C, align(16))]
+#[derive(Debug)]
+pub(super) struct CpuTables {
+    gdt: [u64; 7],
+    tss: TaskStateSegment,
+}
+
+/// Builds and loads a GDT and TSS for the calling CPU, with freshly allocated interrupt stacks
+/// and a kernel stack for entries from ring 3.
+///
+/// Each stack has an unmapped guard page below it, so overflowing one faults rather
+/// than silently corrupting memory. The selectors are the same on every CPU, so a single IDT
+/// can be shared while every CPU still switches to its own stacks.
+///
+/// This must run once per CPU, before that CPU loads the IDT, since the gates take their code
+/// selector from the active CS.
+pub unsafe fn setup_cpu(cpu_index: usize) -> Result<(), AllocError> {
+    assert!(cpu_index < MAX_CPUS, "cpu index {} out of range", cpu_index);
+
+    let mut tss = TaskStateSegment::new();
+    let mut interrupt_stacks = tss.interrupt_stacks;
+    for stack in &mut interrupt_stacks[..IST_COUNT] {
+        let pages = NonZeroUsize::new(IST_PAGES).unwrap();
+        let bottom = AddrSpace::kernel().allocate_with_guard(pages)?;
+        *stack = bottom.as_ptr().add(IST_PAGES * 4096) as u64;
+    }
+    tss.interrupt_stacks = interrupt_stacks;
+
+    let pages = NonZeroUsize::new(KERNEL_STACK_PAGES).unwrap();
+    let bottom = AddrSpace::kernel().allocate_with_guard(pages)?;
+    tss.privilege_stacks = [bottom.as_ptr().add(KERNEL_STACK_PAGES * 4096) as u64, 0, 0];
+
+    let tables = Box::leak(Box::new(CpuTables { gdt: [0; 7], tss }));
+    let [tss_low, tss_high] = tss_descriptor(&tables.tss);
+    tables.gdt = [
+        0,
+        // 64-bit, present, ring 0, executable, readable.
+        0x00af_9a00_0000_ffff,
+        // Present, ring 0, writable.
+        0x00cf_9200_0000_ffff,
+        // Present, ring 3, writable.
+        0x00cf_f200_0000_ffff,
+        // 64-bit, present, ring 3, executable, readable.
+        0x00af_fa00_0000_ffff,
+        tss_low,
+        tss_high,
+    ];
+
+    let previous = CPUS[cpu_index].swap(tables, Ordering::AcqRel);
+    assert!(previous.is_null(), "cpu {} set up twice", cpu_index);
+
+    load(&tables.gdt);
+    reload_segments();
+    debug_assert_eq!(code::read(), KERNEL_CODE);
+    asm!("ltr {:x}", in(reg) TSS.0, options(nostack, preserves_flags));
+
+    log::debug!("cpu {}: loaded gdt and tss", cpu_index);
+    Ok(())
+}
+
+/// Sets the stack the calling CPU switches to when an interrupt, exception or system call
+/// arrives from ring 3.
+///
+/// The scheduler points this at the kernel stack of each thread it switches to.
+pub unsafe fn set_kernel_stack(rsp: VirtAddr) {
+    let tables = current_tables();
+    let mut stacks = (*tables).tss.privilege_stacks;
+    stacks[0] = rsp.addr() as u64;
+    (*tables).tss.privilege_stacks = stacks;
+    percpu::current().kernel_stack.set(rsp.addr());
+}
+
+/// Returns the stack the calling CPU enters the kernel on from ring 3.
+pub fn kernel_stack() -> VirtAddr {
+    unsafe { (*current_tables()).kernel_stack() }
+}
+
+/// Returns the tables [`setup_cpu`] built for `cpu_index`.
+pub(super) fn cpu_tables(cpu_index: usize) -> *mut CpuTables {
+    let tables = CPUS[cpu_index].load(Ordering::Acquire);
+    assert!(!tables.is_null(), "cpu {} has no gdt", cpu_index);
+    tables
+}
+
+/// Returns the tables of the calling CPU, which its per-CPU block keeps.
+fn current_tables() -> *mut CpuTables {
+    percpu::current().tables
+}
+
+impl CpuTables {
+    pub(super) fn kernel_stack(&self) -> VirtAddr {
+        let stacks = self.tss.privilege_stacks;
+        VirtAddr(stacks[0] as usize)
+    }
+}
+
+fn tss_descriptor(tss: &TaskStateSegment) -> [u64; 2] {
+    let base = tss as *const TaskStateSegment as u64;
+    let limit = mem::size_of::<TaskStateSegment>() as u64 - 1;
+
+    let low = (limit & 0xffff)
+        | ((base & 0xff_ffff) << 16)
+        // Present, available 64-bit TSS.
+        | (0x89 << 40)
+        | (((limit >> 16) & 0xf) << 48)
+        | (((base >> 24) & 0xff) << 56);
+    [low, base >> 32]
+}
+
+unsafe fn load(gdt: &[u64; 7]) {
+    #[repr(C, packed(2))]
+    #[derive(Debug)]
+    struct GdtPtr {
+        limit: u16,
+        base: u64,
+    }
+
+    let gdt_ptr = GdtPtr {
+        base: gdt.as_ptr() as u64,
+        limit: mem::size_of_val(gdt) as u16 - 1,
+    };
+
+    asm!("lgdt [{}]", in(reg) &gdt_ptr, options(nostack, preserves_flags));
+}
+
+/// Switches CS to the kernel code selector with a far return, and the data segments to the
+/// kernel data selector. FS and GS are left alone, since loading them would clear their bases.
+unsafe fn reload_segments() {
+    asm!(
+        "push {code}",
+        "lea {tmp}, [rip + 2f]",
+        "push {tmp}",
+        "retfq",
+        "2:",
+        "mov ds, {data:x}",
+        "mov es, {data:x}",
+        "mov ss, {data:x}",
+        code = in(reg) KERNEL_CODE.0 as u64,
+        data = in(reg) KERNEL_DATA.0,
+        tmp = out(reg) _,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::AtomicUsize;
+
+    use super::*;
+    use crate::{
+        interrupts::x86_64::StackFrame,
+        x86_64::idt::{Idt, RawGate},
+    };
+
+    #[test_case]
+    fn per_cpu_tables_are_the_loaded_gdt() {
+        let mut gdt_ptr = [0u16; 5];
+        unsafe { asm!("sgdt [{}]", in(reg) &mut gdt_ptr, options(nostack, preserves_flags)) };
+        let [_, base @ ..] = gdt_ptr;
+        let base = bytemuck::cast::<_, u64>(base);
+
+        // The GDT is the first field of the tables.
+        assert_eq!(current_tables() as u64, base);
+    }
+
+    #[test_case]
+    fn tss_is_marked_busy() {
+        // Loading the task register marks its descriptor busy.
+        let descriptor = unsafe { (*current_tables()).gdt[5] };
+        assert_eq!((descriptor >> 40) & 0xf, 0xb, "tss not marked busy");
+    }
+
+    static HANDLER_RSP: AtomicUsize = AtomicUsize::new(0);
+
+    extern "x86-interrupt" fn record_stack(_frame: StackFrame) {
+        let rsp: usize;
+        unsafe { asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags)) };
+        HANDLER_RSP.store(rsp, Ordering::Relaxed);
+    }
+
+    #[test_case]
+    fn ist_gates_run_on_this_cpus_stacks() {
+        const VECTOR: u8 = 0xf0;
+
+        let tops = unsafe { (*current_tables()).tss.interrupt_stacks };
+        for index in [DOUBLE_FAULT_IST, NMI_IST, MACHINE_CHECK_IST] {
+            // A throwaway IDT, since the real one's IST gates all belong to handlers that panic.
+            let mut idt = Box::new(Idt::empty());
+            let gate = &mut idt.gates[usize::from(VECTOR) - 32];
+            *gate = RawGate::with_addr(record_stack as usize);
+            unsafe { gate.set_stack_index(index) };
+
+            HANDLER_RSP.store(0, Ordering::Relaxed);
+            crate::interrupts::without(|| unsafe {
+                let mut saved = [0u16; 5];
+                asm!("sidt [{}]", in(reg) &mut saved, options(nostack, preserves_flags));
+                idt.load();
+                asm!("int {}", const VECTOR);
+                asm!("lidt [{}]", in(reg) &saved, options(nostack, preserves_flags));
+            });
+
+            let rsp = HANDLER_RSP.load(Ordering::Relaxed);
+            let top = tops[usize::from(index)] as usize;
+            assert!(
+                top - IST_PAGES * 4096 <= rsp && rsp < top,
+                "ist {}: handler ran at {:#x}, outside {:#x}..{:#x}",
+                index,
+                rsp,
+                top - IST_PAGES * 4096,
+                top
+            );
+        }
+    }
+}
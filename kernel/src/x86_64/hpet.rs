@@ -2,23 +2,118 @@ use core::ptr::NonNull;
 
 use bitflags::bitflags;
 use bytemuck::{Pod, Zeroable};
+use spin::Lazy;
 
+use crate::{
+    acpi,
+    address_space::{AddrSpace, MapOptions},
+    types::PhysAddr,
+};
+
+/// The HPET the ACPI tables describe, mapped and running, on first use.
+static HPET: Lazy<Option<Hpet>> = Lazy::new(|| unsafe { Hpet::from_acpi() });
+
+/// Returns the system's HPET, or `None` if there is none or it couldn't be mapped.
+pub fn global() -> Option<&'static Hpet> {
+    HPET.as_ref()
+}
+
+/// A high precision event timer, used as a monotonic clock.
 pub struct Hpet {
     base: NonNull<u64>,
 }
 
+unsafe impl Send for Hpet {}
+// Shared handles only read the registers.
+unsafe impl Sync for Hpet {}
+
+impl Hpet {
+    /// `base` must point to the timer's registers, mapped with caching disabled as
+    /// [`AddrSpace::map_mmio`](crate::address_space::AddrSpace::map_mmio) does.
+    pub unsafe fn new(base: NonNull<u64>) -> Self {
+        Self { base }
+    }
+
+    /// Maps and enables the HPET found through the ACPI "HPET" table.
+    unsafe fn from_acpi() -> Option<Hpet> {
+        let table = acpi::find_table(*b"HPET").ok()??;
+        // The base address is the address field of the generic address structure that follows
+        // the event timer block id.
+        let address = table.data().get(8..16)?;
+        let address = PhysAddr(u64::from_le_bytes(address.try_into().ok()?));
+
+        let map_options = MapOptions {
+            writable: true,
+            ..Default::default()
+        };
+        let base = AddrSpace::kernel()
+            .map_mmio(address, 0x400, map_options)
+            .ok()?;
+        let mut hpet = Hpet::new(base.cast());
+        hpet.enable();
+        Some(hpet)
+    }
+
+    /// Starts the main counter.
+    pub fn enable(&mut self) {
+        unsafe {
+            let config = GeneralConfiguration::from_bits_retain(self.read(CONFIGURATION));
+            self.write(
+                CONFIGURATION,
+                (config | GeneralConfiguration::ENABLE).bits(),
+            );
+        }
+    }
+
+    /// How long one tick of the main counter lasts, in femtoseconds.
+    pub fn period_femtoseconds(&self) -> u32 {
+        self.capabilities().counter_clock_period
+    }
+
+    pub fn timer_count(&self) -> u8 {
+        self.capabilities().timer_count()
+    }
+
+    /// Reads the main counter, which only advances once the timer is enabled.
+    pub fn counter(&self) -> u64 {
+        unsafe { self.read(MAIN_COUNTER) }
+    }
+
+    /// Returns the main counter converted to nanoseconds.
+    pub fn nanos(&self) -> u64 {
+        let femtos = u128::from(self.counter()) * u128::from(self.period_femtoseconds());
+        (femtos / 1_000_000) as u64
+    }
+
+    fn capabilities(&self) -> GeneralCapabilities {
+        bytemuck::cast(unsafe { self.read(CAPABILITIES) })
+    }
+
+    unsafe fn read(&self, offset: usize) -> u64 {
+        self.base.as_ptr().add(offset / 8).read_volatile()
+    }
+
+    unsafe fn write(&mut self, offset: usize, value: u64) {
+        self.base.as_ptr().add(offset / 8).write_volatile(value)
+    }
+}
+
+const CAPABILITIES: usize = 0x0;
+const CONFIGURATION: usize = 0x10;
+const MAIN_COUNTER: usize = 0xf0;
+
 #[repr(C, align(8))]
 #[derive(Debug, Clone, Copy, Zeroable, Pod)]
 struct GeneralCapabilities {
-    counter_clock_period: u32,
-    vendor_id: u16,
-    flags: u8,
     revision_id: u8,
+    flags: u8,
+    vendor_id: u16,
+    counter_clock_period: u32,
 }
 
 impl GeneralCapabilities {
     pub fn timer_count(&self) -> u8 {
-        self.flags + 1
+        (self.flags & 0x1f) + 1
     }
 }
 
@@ -35,3 +130,23 @@ struct GeneralInterruptStatus {
     _reserved: u32,
     timer_interrupt_active_bitset: u32,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time;
+
+    #[test_case]
+    fn counter_advances() {
+        let Some(hpet) = global() else {
+            log::warn!("no hpet, skipping");
+            return;
+        };
+        // The period is at most 100 ns, so a microsecond is several ticks.
+        assert!(hpet.period_femtoseconds() <= 100_000_000);
+        let first = hpet.counter();
+        time::delay_us(1);
+        let second = hpet.counter();
+        assert!(second > first, "counter stuck at {}", first);
+    }
+}
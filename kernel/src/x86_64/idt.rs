@@ -127,10 +127,36 @@ impl RawGate {
         self.offset_high = addr.wrapping_shr(32) as u32;
         self.options.set_present(true);
     }
+
+    /// Builds a trap gate for `addr` that ring 3 code can invoke with `int`.
+    ///
+    /// Being a trap gate, it leaves interrupts enabled while the handler runs.
+    pub fn with_syscall_handler(addr: usize) -> Self {
+        let mut gate = Self::with_addr(addr);
+        gate.options
+            .set_privilege_level(3)
+            .disable_interrupts(false);
+        gate
+    }
+
+    pub fn options(&self) -> GateOptions {
+        self.options
+    }
+
+    pub fn options_mut(&mut self) -> &mut GateOptions {
+        &mut self.options
+    }
+
+    /// Makes the gate switch to interrupt stack `index` (0-based) of the current CPU's TSS.
+    ///
+    /// Every CPU that can take this interrupt must have that stack set up.
+    pub unsafe fn set_stack_index(&mut self, index: u16) {
+        self.options.set_stack_index(index);
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
-struct GateOptions(u16);
+pub struct GateOptions(u16);
 
 impl GateOptions {
     fn new() -> Self {
@@ -142,16 +168,29 @@ impl GateOptions {
         self
     }
 
-    fn disable_interrupts(&mut self, disable: bool) -> &mut Self {
+    /// Chooses between an interrupt gate, which clears IF on entry, and a trap gate, which
+    /// leaves it alone.
+    pub fn disable_interrupts(&mut self, disable: bool) -> &mut Self {
         self.0 = u16_with_bit(8, self.0, !disable);
         self
     }
 
-    fn set_privilege_level(&mut self, dpl: u16) -> &mut Self {
+    pub fn is_trap_gate(&self) -> bool {
+        self.0 & (1 << 8) != 0
+    }
+
+    /// Sets the least privileged ring allowed to invoke the gate with `int`. Hardware
+    /// interrupts and exceptions ignore it.
+    pub fn set_privilege_level(&mut self, dpl: u16) -> &mut Self {
+        assert!(dpl <= 3, "invalid privilege level {}", dpl);
         self.0 = u16_with_value(13, 14, self.0, dpl);
         self
     }
 
+    pub fn privilege_level(&self) -> u16 {
+        (self.0 >> 13) & 0b11
+    }
+
     unsafe fn set_stack_index(&mut self, index: u16) -> &mut Self {
         self.0 = u16_with_value(0, 2, self.0, index + 1);
         self
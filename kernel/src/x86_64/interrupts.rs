@@ -1,17 +1,252 @@
-use super::pic;
+use super::{
+    apic::{io::IoApic, local::LocalApicP},
+    pic,
+};
+use core::sync::atomic::{AtomicBool, Ordering};
 
+use crate::spinlock::Spinlock;
+
+static CONTROLLER: Spinlock<Option<Controller>> = Spinlock::new(None);
+
+/// Whether a hardware interrupt is routed to each vector, so that it has to be acknowledged.
+static ROUTED: [AtomicBool; 256] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NO: AtomicBool = AtomicBool::new(false);
+    [NO; 256]
+};
+
+/// Records that the controller delivers interrupts on `vector`, so they must be acknowledged with
+/// an end of interrupt.
+///
+/// [`InterruptController::set_vector`] does this for the IRQ lines it routes. Local APIC
+/// sources, such as its timer and IPIs, have to be marked with this.
+pub fn mark_routed(vector: u8) {
+    ROUTED[usize::from(vector)].store(true, Ordering::Relaxed);
+}
+
+/// Returns whether the controller delivers interrupts on `vector`. Vectors that only software
+/// `int` instructions raise aren't routed, and must not be acknowledged.
+pub fn is_routed(vector: u8) -> bool {
+    ROUTED[usize::from(vector)].load(Ordering::Relaxed)
+}
+
+/// Makes `controller` the one used to program IRQs from now on.
+pub fn install(controller: Controller) {
+    CONTROLLER.lock(|slot| *slot = Some(controller));
+}
+
+/// Runs `f` with the installed interrupt controller, or returns `None` if there is none yet.
+pub fn with_controller<F, T>(f: F) -> Option<T>
+where
+    F: FnOnce(&mut Controller) -> T,
+{
+    CONTROLLER.lock(|slot| slot.as_mut().map(f))
+}
+
+/// A backend that routes hardware interrupt lines (IRQs) to interrupt vectors.
+pub trait InterruptController {
+    unsafe fn mask(&mut self, irq: u8);
+    unsafe fn unmask(&mut self, irq: u8);
+    unsafe fn end_of_interrupt(&mut self, vector: u8);
+    unsafe fn set_vector(&mut self, irq: u8, vector: u8);
+}
+
+/// The legacy pair of cascaded 8259 PICs.
+#[derive(Debug)]
+pub struct Pic {
+    offsets: [u8; 2],
+}
+
+impl Pic {
+    /// Wraps PICs that were already remapped to the given vector offsets with [pic::init].
+    pub unsafe fn with_offsets(pic1_offset: u8, pic2_offset: u8) -> Self {
+        Self {
+            offsets: [pic1_offset, pic2_offset],
+        }
+    }
+}
+
+impl InterruptController for Pic {
+    unsafe fn mask(&mut self, irq: u8) {
+        let mut masks = pic::read_masks();
+        masks[usize::from(irq / 8)] |= 1 << (irq % 8);
+        pic::write_masks(masks);
+    }
+
+    unsafe fn unmask(&mut self, irq: u8) {
+        let mut masks = pic::read_masks();
+        masks[usize::from(irq / 8)] &= !(1 << (irq % 8));
+        if irq >= 8 {
+            // Lines on the secondary PIC only arrive through the cascade line.
+            masks[0] &= !(1 << 2);
+        }
+        pic::write_masks(masks);
+    }
+
+    unsafe fn end_of_interrupt(&mut self, vector: u8) {
+        pic::end_of_interrupt(vector, self.offsets[0], self.offsets[1]);
+    }
+
+    /// The PIC can only place its eight lines at consecutive vectors, so this moves every line
+    /// of the PIC that `irq` belongs to.
+    unsafe fn set_vector(&mut self, irq: u8, vector: u8) {
+        assert_eq!(vector % 8, irq % 8, "pic vectors must be 8-aligned");
+        self.offsets[usize::from(irq / 8)] = vector - irq % 8;
+        pic::init(self.offsets[0], self.offsets[1]);
+    }
+}
+
+/// An IO APIC routing IRQs to the local APIC of the current processor.
+#[derive(Debug)]
+pub struct Apic {
+    io_apic: IoApic,
+    local_apic: LocalApicP,
+}
+
+impl Apic {
+    pub fn new(io_apic: IoApic, local_apic: LocalApicP) -> Self {
+        Self {
+            io_apic,
+            local_apic,
+        }
+    }
+
+    pub fn local_apic(&mut self) -> &mut LocalApicP {
+        &mut self.local_apic
+    }
+}
+
+impl InterruptController for Apic {
+    unsafe fn mask(&mut self, irq: u8) {
+        self.io_apic.set_masked(irq, true);
+    }
+
+    unsafe fn unmask(&mut self, irq: u8) {
+        self.io_apic.set_masked(irq, false);
+    }
+
+    unsafe fn end_of_interrupt(&mut self, _vector: u8) {
+        self.local_apic.end_of_interrupt();
+    }
+
+    unsafe fn set_vector(&mut self, irq: u8, vector: u8) {
+        self.io_apic.set_vector(irq, vector);
+    }
+}
+
+/// The interrupt controller chosen at boot.
 #[derive(Debug)]
-pub enum InterruptController {
-    Pic,
+pub enum Controller {
+    Pic(Pic),
+    Apic(Apic),
 }
 
-impl InterruptController {
-    pub unsafe fn end_of_interrupt(&self, vector: u8) {
+impl InterruptController for Controller {
+    unsafe fn mask(&mut self, irq: u8) {
+        match self {
+            Controller::Pic(pic) => pic.mask(irq),
+            Controller::Apic(apic) => apic.mask(irq),
+        }
+    }
+
+    unsafe fn unmask(&mut self, irq: u8) {
+        match self {
+            Controller::Pic(pic) => pic.unmask(irq),
+            Controller::Apic(apic) => apic.unmask(irq),
+        }
+    }
+
+    unsafe fn end_of_interrupt(&mut self, vector: u8) {
         match self {
-            InterruptController::Pic => pic::end_of_interrupt(vector, PIC1_OFFSET, PIC2_OFFSET),
+            Controller::Pic(pic) => pic.end_of_interrupt(vector),
+            Controller::Apic(apic) => apic.end_of_interrupt(vector),
+        }
+    }
+
+    unsafe fn set_vector(&mut self, irq: u8, vector: u8) {
+        mark_routed(vector);
+        match self {
+            Controller::Pic(pic) => pic.set_vector(irq, vector),
+            Controller::Apic(apic) => apic.set_vector(irq, vector),
         }
     }
 }
 
-const PIC1_OFFSET: u8 = 32;
-const PIC2_OFFSET: u8 = 40;
+#[cfg(test)]
+mod tests {
+    use super::{super::apic::io::RedirectionEntry, *};
+
+    const IRQ: u8 = 1;
+
+    #[test_case]
+    fn pic_mask_sets_the_line_bit() {
+        // The PICs are there even when the IO APIC is in use, and masking only touches the mask
+        // registers, so this works whichever controller is installed.
+        crate::interrupts::without(|| unsafe {
+            let saved = pic::read_masks();
+            let mut pic = Pic::with_offsets(40, 48);
+
+            pic.unmask(IRQ);
+            let unmasked = pic::read_masks();
+            pic.mask(IRQ);
+            let masked = pic::read_masks();
+            pic::write_masks(saved);
+
+            assert_eq!(unmasked[0] & 1 << IRQ, 0);
+            assert_eq!(masked[0] & 1 << IRQ, 1 << IRQ);
+            // Nothing else on either PIC may change.
+            assert_eq!(unmasked[0] | 1 << IRQ, masked[0]);
+            assert_eq!(unmasked[1], masked[1]);
+        });
+    }
+
+    #[test_case]
+    fn io_apic_mask_sets_the_entry_bit() {
+        with_controller(|controller| {
+            let Controller::Apic(apic) = controller else {
+                return;
+            };
+            let saved = apic.io_apic.read_redirection(IRQ);
+
+            unsafe { apic.mask(IRQ) };
+            let masked = apic.io_apic.read_redirection(IRQ);
+            unsafe { apic.unmask(IRQ) };
+            let unmasked = apic.io_apic.read_redirection(IRQ);
+            unsafe { apic.io_apic.set_masked(IRQ, saved.masked) };
+
+            assert!(masked.masked);
+            assert!(!unmasked.masked);
+            // Masking leaves the routing alone.
+            assert_eq!((masked.vector, masked.dest), (saved.vector, saved.dest));
+            assert_eq!((unmasked.vector, unmasked.dest), (saved.vector, saved.dest));
+        });
+    }
+
+    #[test_case]
+    fn io_apic_redirection_round_trips() {
+        with_controller(|controller| {
+            let Controller::Apic(apic) = controller else {
+                return;
+            };
+            let saved = apic.io_apic.read_redirection(IRQ);
+
+            // Masked, so nothing arrives on the made-up vector while it's in place.
+            let written = RedirectionEntry {
+                vector: 0xee,
+                dest: saved.dest,
+                masked: true,
+            };
+            let read = unsafe {
+                apic.io_apic
+                    .write_redirection(IRQ, written.vector, written.dest, written.masked);
+                let read = apic.io_apic.read_redirection(IRQ);
+                apic.io_apic
+                    .write_redirection(IRQ, saved.vector, saved.dest, saved.masked);
+                read
+            };
+
+            assert_eq!(read, written);
+            assert_eq!(apic.io_apic.read_redirection(IRQ), saved);
+        });
+    }
+}
@@ -1,10 +1,10 @@
-use core::{arch::x86_64::__cpuid, fmt::Debug, ptr::NonNull};
+use core::{fmt::Debug, ptr::NonNull};
 
 use bitflags::bitflags;
 
 use crate::{
     types::PhysAddr,
-    x86_64::{rdmsr, wrmsr},
+    x86_64::{cpu, rdmsr, wrmsr},
 };
 
 #[derive(Debug)]
@@ -133,8 +133,7 @@ impl Debug for LocalApic {
 struct PaddedRegister(u32);
 
 fn is_apic_present() -> bool {
-    let result = unsafe { __cpuid(1) };
-    result.edx & (1 << 9) != 0
+    cpu::features().apic
 }
 
 const IA32_APIC_BASE: u32 = 0x1b;
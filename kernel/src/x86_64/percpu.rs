@@ -0,0 +1,128 @@
+use alloc::boxed::Box;
+use core::{arch::asm, cell::Cell, ptr};
+
+use super::{apic::local::LocalApicId, cpu, gdt, wrmsr};
+use crate::{address_space::AddrSpace, thread::Thread};
+
+const IA32_GS_BASE: u32 = 0xc000_0101;
+
+/// Data private to one CPU, found through its GS base.
+///
+/// While in the kernel, the GS base always points at the CPU's block. Interrupts from ring 3
+/// `swapgs` to get it back, leaving user code's GS base in `IA32_KERNEL_GS_BASE` meanwhile.
+///
+/// The system call entry reaches the first three fields by their offsets, so they must stay
+/// where they are.
+#[repr(C)]
+#[derive(Debug)]
+pub struct PerCpu {
+    /// Points back at the block itself, so that a single `gs:` load finds it. At offset 0.
+    #[allow(dead_code)] // Only read through `gs:`.
+    this: *const PerCpu,
+    /// The stack system calls run on, kept in step with the TSS by
+    /// [`gdt::set_kernel_stack`](super::gdt::set_kernel_stack). At offset 8.
+    pub(super) kernel_stack: Cell<usize>,
+    /// Where the system call entry keeps the user stack pointer while it switches stacks. At
+    /// offset 16.
+    #[allow(dead_code)] // Only used through `gs:`.
+    user_stack: Cell<usize>,
+    pub cpu_id: usize,
+    pub apic_id: LocalApicId,
+    /// The thread running on this CPU, or null until the scheduler first switches threads.
+    pub current_thread: Cell<*const Thread>,
+    /// The address space this CPU last switched to with [`AddrSpace::activate`], or null until
+    /// it first does, while it runs on the kernel's.
+    pub active_addr_space: Cell<*const AddrSpace>,
+    /// The CPU's GDT and TSS, as built by [`gdt::setup_cpu`].
+    pub(super) tables: *mut gdt::CpuTables,
+}
+
+/// Allocates the calling CPU's block and points its GS base at it.
+///
+/// The block is never freed, and this must run once per CPU before [`current`] is used on it,
+/// after the CPU's GDT is set up.
+pub unsafe fn init(cpu_id: usize) {
+    let apic_id = LocalApicId(cpu::apic_id());
+    let tables = gdt::cpu_tables(cpu_id);
+    let block = Box::leak(Box::new(PerCpu {
+        this: ptr::null(),
+        kernel_stack: Cell::new((*tables).kernel_stack().addr()),
+        user_stack: Cell::new(0),
+        cpu_id,
+        apic_id,
+        current_thread: Cell::new(ptr::null()),
+        active_addr_space: Cell::new(ptr::null()),
+        tables,
+    }));
+    block.this = block;
+    wrmsr(IA32_GS_BASE, block as *const PerCpu as u64);
+}
+
+/// Returns the calling CPU's block. It faults if [`init`] hasn't run on this CPU.
+///
+/// The caller must not migrate to another CPU while using the block, which holds as long as
+/// threads stay on the CPU they started on.
+pub fn current() -> &'static PerCpu {
+    let block: *const PerCpu;
+    unsafe {
+        asm!(
+            "mov {}, gs:[0]",
+            out(reg) block,
+            options(nostack, readonly, preserves_flags)
+        )
+    };
+    unsafe { &*block }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x86_64::interrupts::{self, Controller};
+
+    #[test_case]
+    fn apic_id_matches_the_local_apic() {
+        let id = interrupts::with_controller(|controller| match controller {
+            Controller::Apic(apic) => Some(apic.local_apic().id()),
+            Controller::Pic(_) => None,
+        });
+        let Some(Some(id)) = id else {
+            log::warn!("no local apic, skipping");
+            return;
+        };
+        assert_eq!(current().apic_id, id);
+    }
+
+    #[test_case]
+    fn block_is_what_gs_points_at() {
+        const SENTINEL: usize = 0x5e47_1ee1;
+
+        let block = current();
+        let saved = block.user_stack.get();
+
+        // Written through the block, read through `gs`. The user stack slot is at offset 16, and
+        // only the system call entry uses it.
+        block.user_stack.set(SENTINEL);
+        let through_gs: usize;
+        unsafe {
+            asm!(
+                "mov {}, gs:[16]",
+                out(reg) through_gs,
+                options(nostack, readonly, preserves_flags)
+            )
+        };
+
+        // And the other way around.
+        unsafe {
+            asm!(
+                "mov gs:[16], {}",
+                in(reg) !SENTINEL,
+                options(nostack, preserves_flags)
+            )
+        };
+        let through_block = block.user_stack.get();
+        block.user_stack.set(saved);
+
+        assert_eq!(through_gs, SENTINEL);
+        assert_eq!(through_block, !SENTINEL);
+    }
+}
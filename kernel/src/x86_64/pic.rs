@@ -1,36 +1,39 @@
-use crate::x86_64::{in8, out8};
+use crate::{
+    time,
+    x86_64::{in8, out8},
+};
 
 pub unsafe fn init(pic1_offset: u8, pic2_offset: u8) {
     let masks = read_masks();
 
     out8(PIC1_COMMAND, ICW1_INIT | ICW1_ICW4);
-    io_pause();
+    time::delay_us(1);
     out8(PIC2_COMMAND, ICW1_INIT | ICW1_ICW4);
-    io_pause();
+    time::delay_us(1);
     out8(PIC1_DATA, pic1_offset);
-    io_pause();
+    time::delay_us(1);
     out8(PIC2_DATA, pic2_offset);
-    io_pause();
+    time::delay_us(1);
     out8(PIC1_DATA, 4);
-    io_pause();
+    time::delay_us(1);
     out8(PIC2_DATA, 2);
-    io_pause();
+    time::delay_us(1);
 
     out8(PIC1_DATA, ICW4_8086);
-    io_pause();
+    time::delay_us(1);
     out8(PIC2_DATA, ICW4_8086);
-    io_pause();
+    time::delay_us(1);
 
     write_masks(masks);
 }
 
+/// Acknowledges the interrupt on `vector`. Vectors outside both PICs' ranges didn't come from
+/// them and are ignored.
 pub unsafe fn end_of_interrupt(vector: u8, pic1_offset: u8, pic2_offset: u8) {
     if (pic1_offset..pic1_offset + 8).contains(&vector) {
         out8(PIC1_COMMAND, PIC_EOI);
     } else if (pic2_offset..pic2_offset + 8).contains(&vector) {
         out8(PIC2_COMMAND, PIC_EOI);
-    } else {
-        panic!("invalid irq vector for pic");
     }
 }
 
@@ -58,8 +61,3 @@ const ICW4_BUF_SLAVE: u8 = 0x08; /* Buffered mode/slave */
 const ICW4_BUF_MASTER: u8 = 0x0c; /* Buffered mode/master */
 const ICW4_SFNM: u8 = 0x10; /* Special fully nested (not) */
 const PIC_EOI: u8 = 0x20;
-
-#[inline]
-unsafe fn io_pause() {
-    out8(0x80, 0);
-}
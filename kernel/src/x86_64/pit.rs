@@ -1,41 +1,180 @@
-use super::{in16, out16};
+use core::hint;
 
-pub struct Pit(());
+use super::{in8, out8};
+
+pub const FREQUENCY: u32 = 1_193_182;
+
+/// The programmable interval timer's three channels.
+pub struct Pit {
+    /// How each channel's counter was last set to be accessed.
+    access_modes: [AccessMode; 3],
+}
 
 impl Pit {
-    pub fn current_count(&self) -> u16 {
-        unsafe { in16(0x40) }
+    /// Takes control of the PIT, which must not be programmed by anything else while this exists.
+    pub unsafe fn new() -> Pit {
+        Pit {
+            access_modes: [AccessMode::LowHighByte; 3],
+        }
+    }
+
+    /// Latches the count of `channel` and reads it back, so the two bytes are consistent.
+    pub fn current_count(&self, channel: Channel) -> u16 {
+        let port = channel.data_port();
+        unsafe {
+            out8(COMMAND, (channel as u8) << 6);
+            match self.access_modes[channel as usize] {
+                AccessMode::LowByteOnly => u16::from(in8(port)),
+                AccessMode::HighByteOnly => u16::from(in8(port)) << 8,
+                AccessMode::LatchCountValue | AccessMode::LowHighByte => {
+                    let low = in8(port);
+                    let high = in8(port);
+                    u16::from_le_bytes([low, high])
+                }
+            }
+        }
     }
 
-    pub fn set_reload_value(&mut self, value: u16) {
-        unsafe { out16(0x40, value) };
+    /// Sets the value `channel` counts down from, writing only the bytes its access mode takes.
+    pub fn set_reload_value(&mut self, channel: Channel, value: u16) {
+        let port = channel.data_port();
+        let [low, high] = value.to_le_bytes();
+        unsafe {
+            match self.access_modes[channel as usize] {
+                AccessMode::LowByteOnly => out8(port, low),
+                AccessMode::HighByteOnly => out8(port, high),
+                AccessMode::LatchCountValue | AccessMode::LowHighByte => {
+                    out8(port, low);
+                    out8(port, high);
+                }
+            }
+        }
     }
 
+    /// Returns whether `channel` has been given a reload value that it hasn't loaded into its
+    /// counter yet. Until it has, [`Pit::current_count`] reads garbage.
+    pub fn reload_pending(&self, channel: Channel) -> bool {
+        // A read-back command latching only the status of `channel`.
+        let command = 0b1110_0000 | 1 << (channel as u8 + 1);
+        unsafe {
+            out8(COMMAND, command);
+            in8(channel.data_port()) & STATUS_NULL_COUNT != 0
+        }
+    }
+
+    /// Programs `channel` with an access and operating mode. The channel then waits for a reload
+    /// value before it starts counting.
+    ///
+    /// [`AccessMode::LatchCountValue`] isn't a mode of its own: it latches the current count for
+    /// reading and leaves the channel as it was, so `operating_mode` is ignored.
     pub fn write_command(
         &mut self,
         channel: Channel,
         access_mode: AccessMode,
         operating_mode: OperatingMode,
     ) {
+        let command = (channel as u8) << 6 | (access_mode as u8) << 4 | (operating_mode as u8) << 1;
+        unsafe { out8(COMMAND, command) };
+
+        if !matches!(access_mode, AccessMode::LatchCountValue) {
+            self.access_modes[channel as usize] = access_mode;
+        }
     }
 }
-pub fn sleep() {}
+/// Busy-waits for `ms` milliseconds by polling channel 0, without needing interrupts.
+///
+/// A single count lasts at most about 55 ms, so longer sleeps run the channel several times over.
+/// Channel 0 drives IRQ 0, which should stay masked while this runs.
+pub unsafe fn sleep(ms: u32) {
+    let mut pit = Pit::new();
+    // Rounded up, so the sleep is never shorter than asked for.
+    let mut ticks = (u64::from(FREQUENCY) * u64::from(ms)).div_ceil(1000);
+
+    while ticks != 0 {
+        let chunk = ticks.min(u64::from(u16::MAX)) as u16;
+        pit.write_command(
+            Channel::Channel0,
+            AccessMode::LowHighByte,
+            OperatingMode::IrqOnTerminalCount,
+        );
+        pit.set_reload_value(Channel::Channel0, chunk);
+        while pit.reload_pending(Channel::Channel0) {
+            hint::spin_loop();
+        }
+
+        // In this mode the counter keeps going past zero, wrapping around to 0xffff.
+        let mut last = chunk;
+        loop {
+            let count = pit.current_count(Channel::Channel0);
+            if count > last {
+                break;
+            }
+            last = count;
+            hint::spin_loop();
+        }
+        ticks -= u64::from(chunk);
+    }
+}
+
+/// Busy-waits for `ticks` PIT ticks using channel 2, with the speaker output disconnected.
+///
+/// Channel 2 is the only channel whose output can be polled (through port 0x61), which makes it
+/// usable for calibrating other time sources before interrupts are set up.
+pub unsafe fn wait_channel2(ticks: u16) {
+    let gate = in8(PORT_B) & !PORT_B_SPEAKER;
+
+    // Channel 2, low/high byte access, interrupt on terminal count.
+    out8(COMMAND, 0b1011_0000);
+    out8(CHANNEL2_DATA, ticks as u8);
+    out8(CHANNEL2_DATA, (ticks >> 8) as u8);
+
+    // A rising edge on the gate (re)starts the count.
+    out8(PORT_B, gate & !PORT_B_GATE);
+    out8(PORT_B, gate | PORT_B_GATE);
+
+    while in8(PORT_B) & PORT_B_OUTPUT == 0 {
+        hint::spin_loop();
+    }
+
+    out8(PORT_B, gate & !PORT_B_GATE);
+}
+
+const CHANNEL0_DATA: u16 = 0x40;
+const CHANNEL1_DATA: u16 = 0x41;
+const CHANNEL2_DATA: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+const STATUS_NULL_COUNT: u8 = 1 << 6;
+const PORT_B: u16 = 0x61;
+const PORT_B_GATE: u8 = 1;
+const PORT_B_SPEAKER: u8 = 1 << 1;
+const PORT_B_OUTPUT: u8 = 1 << 5;
 
 #[derive(Debug, Clone, Copy)]
-enum Channel {
+pub enum Channel {
     Channel0,
     Channel1,
     Channel2,
 }
+
+impl Channel {
+    fn data_port(self) -> u16 {
+        match self {
+            Channel::Channel0 => CHANNEL0_DATA,
+            Channel::Channel1 => CHANNEL1_DATA,
+            Channel::Channel2 => CHANNEL2_DATA,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
-enum AccessMode {
+pub enum AccessMode {
     LatchCountValue,
     LowByteOnly,
     HighByteOnly,
     LowHighByte,
 }
 #[derive(Debug, Clone, Copy)]
-enum OperatingMode {
+pub enum OperatingMode {
     IrqOnTerminalCount,
     HardwareRetriggerableOneShot,
     RateGenerator,
@@ -43,3 +182,56 @@ enum OperatingMode {
     SoftwareTriggeredStrobe,
     HardwareTriggeredStrobe,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{time, x86_64::tsc};
+
+    #[test_case]
+    fn rate_generator_counts_down() {
+        let mut pit = unsafe { Pit::new() };
+        pit.write_command(
+            Channel::Channel0,
+            AccessMode::LowHighByte,
+            OperatingMode::RateGenerator,
+        );
+        pit.set_reload_value(Channel::Channel0, u16::MAX);
+        while pit.reload_pending(Channel::Channel0) {
+            hint::spin_loop();
+        }
+
+        // A full count takes about 55 ms, so it can reload between two samples at most once.
+        let samples = [(); 3].map(|_| {
+            for _ in 0..1000 {
+                hint::spin_loop();
+            }
+            pit.current_count(Channel::Channel0)
+        });
+        assert!(
+            samples.windows(2).any(|pair| pair[1] < pair[0]),
+            "count never went down: {:?}",
+            samples
+        );
+    }
+
+    #[test_case]
+    fn sleep_lasts_at_least_as_long_as_asked() {
+        const MS: u32 = 50;
+
+        let Some(frequency) = time::tsc_frequency() else {
+            log::warn!("tsc not calibrated, skipping");
+            return;
+        };
+        let start = tsc::read();
+        unsafe { sleep(MS) };
+        let elapsed = tsc::read() - start;
+
+        let expected = frequency * u64::from(MS) / 1000;
+        assert!(
+            elapsed >= expected,
+            "slept {} us",
+            elapsed * 1_000_000 / frequency
+        );
+    }
+}
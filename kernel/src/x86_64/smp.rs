@@ -0,0 +1,396 @@
+//! Starting the application processors (APs), every processor but the one limine started us on.
+//!
+//! An AP comes out of INIT in real mode, so it starts in a trampoline copied below 1 MiB. The
+//! trampoline switches straight to long mode on a small set of page tables that identity map its
+//! own page and share the kernel's higher half, then jumps to [`ap_entry`] on the AP's own stack.
+
+use core::{
+    arch::global_asm,
+    num::NonZeroUsize,
+    ptr,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use super::{
+    apic::local::{LocalApicId, LocalApicP},
+    cr0::{self, Cr0},
+    cr3, efer, enable_global_pages, fpu, gdt,
+    interrupts::{self as controller, Controller},
+    percpu, syscall,
+};
+use crate::{
+    acpi::{self, madt, AcpiError},
+    address_space::{AddrSpace, AllocError},
+    hhdm::Hhdm,
+    interrupts, pmm, time,
+    types::{Frame, PhysAddr},
+};
+
+/// The trampoline's code and data, then its PML4, PDPT and page directory.
+const LOW_PAGES: usize = 4;
+const AP_STACK_PAGES: usize = 16;
+
+/// How long an AP gets to reach [`ap_entry`], and then to finish setting itself up.
+const START_TIMEOUT_US: u64 = 100_000;
+const ONLINE_TIMEOUT_US: u64 = 1_000_000;
+
+/// The kernel's PML4, which each AP switches to as soon as it's in long mode.
+static KERNEL_L4: AtomicU64 = AtomicU64::new(0);
+/// How many APs have reached [`ap_entry`], and how many of those have finished setting up.
+static STARTED: AtomicUsize = AtomicUsize::new(0);
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug)]
+pub enum SmpError {
+    Acpi(AcpiError),
+    /// There is no MADT, so the other processors can't be found.
+    NoMadt,
+    /// No usable memory below 1 MiB was free for the trampoline.
+    NoLowMemory,
+    /// IPIs can only be sent through the local APIC, and the legacy PIC is in use.
+    NoApic,
+    Alloc(AllocError),
+}
+
+impl From<AcpiError> for SmpError {
+    fn from(value: AcpiError) -> Self {
+        SmpError::Acpi(value)
+    }
+}
+
+impl From<AllocError> for SmpError {
+    fn from(value: AllocError) -> Self {
+        SmpError::Alloc(value)
+    }
+}
+
+/// How many of the APs the MADT lists came online.
+#[derive(Debug, Clone, Copy)]
+pub struct BootedAps {
+    pub online: usize,
+    pub found: usize,
+}
+
+/// Starts every enabled AP in the MADT, one at a time, and leaves each parked once it has its own
+/// GDT, IDT, per-CPU block and local APIC.
+///
+/// If an AP doesn't start in time the rest aren't tried, since it might still start later and
+/// read the trampoline while it is being rewritten for the next one.
+///
+/// # Safety
+/// This must run once, on the bootstrap processor, after its own per-CPU block and interrupt
+/// controller are set up.
+pub unsafe fn boot_all() -> Result<BootedAps, SmpError> {
+    let madt = acpi::find_table(madt::SIGNATURE)?.ok_or(SmpError::NoMadt)?;
+    let base = pmm::low_memory(LOW_PAGES).ok_or(SmpError::NoLowMemory)?;
+    let bsp = percpu::current().apic_id;
+
+    let kernel_l4 = cr3::read();
+    KERNEL_L4.store(kernel_l4.0 .0, Ordering::Relaxed);
+    let trampoline = Trampoline::install(base.0, kernel_l4);
+
+    let mut booted = BootedAps {
+        online: 0,
+        found: 0,
+    };
+    let aps = madt::local_apics(&madt).filter(|entry| entry.enabled && entry.apic_id != bsp.0);
+    for entry in aps {
+        booted.found += 1;
+        let cpu_id = booted.online + 1;
+        if cpu_id >= gdt::MAX_CPUS {
+            continue;
+        }
+
+        let stack =
+            AddrSpace::kernel().allocate_with_guard(NonZeroUsize::new(AP_STACK_PAGES).unwrap())?;
+        trampoline.write(
+            Field::Stack,
+            stack.as_ptr() as u64 + AP_STACK_PAGES as u64 * 4096,
+        );
+        trampoline.write(Field::Entry, ap_entry as usize as u64);
+        trampoline.write(Field::CpuId, cpu_id as u64);
+
+        let apic_id = LocalApicId(entry.apic_id);
+        let started = STARTED.load(Ordering::Acquire);
+        with_local_apic(|lapic| lapic.send_init(apic_id))?;
+        time::delay_us(10_000);
+        for _ in 0..2 {
+            with_local_apic(|lapic| lapic.send_startup(apic_id, trampoline.vector()))?;
+            if wait_for(&STARTED, started + 1, 200) {
+                break;
+            }
+        }
+
+        if !wait_for(&STARTED, started + 1, START_TIMEOUT_US) {
+            log::warn!("cpu with apic id {} didn't start", entry.apic_id);
+            break;
+        }
+        if !wait_for(&ONLINE, booted.online + 1, ONLINE_TIMEOUT_US) {
+            log::warn!(
+                "cpu with apic id {} started but didn't come online",
+                entry.apic_id
+            );
+            break;
+        }
+        booted.online += 1;
+    }
+    Ok(booted)
+}
+
+/// Where each AP starts, once for every AP it is rewritten for.
+struct Trampoline {
+    hhdm: Hhdm,
+    base: PhysAddr,
+}
+
+#[derive(Clone, Copy)]
+enum Field {
+    Stack,
+    Entry,
+    CpuId,
+}
+
+impl Trampoline {
+    /// Copies the trampoline to `base` and fills in everything but the per-AP fields.
+    unsafe fn install(base: PhysAddr, kernel_l4: Frame) -> Trampoline {
+        let hhdm = Hhdm::with_limine();
+        let code = hhdm.to_virtual::<u8>(base).as_ptr();
+        ptr::write_bytes(code, 0, LOW_PAGES * 4096);
+        ptr::copy_nonoverlapping(
+            ptr::addr_of!(smp_trampoline_start),
+            code,
+            label(ptr::addr_of!(smp_trampoline_end)),
+        );
+
+        let trampoline = Trampoline { hhdm, base };
+        let pml4 = base + 0x1000;
+        let pdpt = base + 0x2000;
+        let pd = base + 0x3000;
+
+        // The low 2 MiB stay identity mapped so the trampoline survives turning paging on, and
+        // the higher half is the kernel's so the AP can reach its stack and `ap_entry`.
+        let l4 = trampoline.table(pml4);
+        *l4 = pdpt.0 | 0x3;
+        let kernel = trampoline.table(kernel_l4.0);
+        ptr::copy_nonoverlapping(kernel.add(256), l4.add(256), 256);
+        *trampoline.table(pdpt) = pd.0 | 0x3;
+        *trampoline.table(pd) = 0x83;
+
+        let gdtr_base = trampoline
+            .label_at(ptr::addr_of!(smp_trampoline_gdtr))
+            .add(2);
+        gdtr_base
+            .cast::<u32>()
+            .write_unaligned((base.0 + label(ptr::addr_of!(smp_trampoline_gdt)) as u64) as u32);
+        let far_jump = trampoline.label_at(ptr::addr_of!(smp_trampoline_far_jump));
+        far_jump.cast::<u32>().write_unaligned(
+            (base.0 + label(ptr::addr_of!(smp_trampoline_long_mode)) as u64) as u32,
+        );
+        let efer = efer::Efer::LONG_MODE_ENABLE | (efer::read() & efer::Efer::NO_EXECUTE_ENABLE);
+        trampoline
+            .label_at(ptr::addr_of!(smp_trampoline_pml4))
+            .cast::<u32>()
+            .write_unaligned(pml4.0 as u32);
+        trampoline
+            .label_at(ptr::addr_of!(smp_trampoline_efer))
+            .cast::<u32>()
+            .write_unaligned(efer.bits() as u32);
+        trampoline
+    }
+
+    /// The startup IPI vector that starts an AP at the trampoline.
+    fn vector(&self) -> u8 {
+        (self.base.0 >> 12) as u8
+    }
+
+    fn write(&self, field: Field, value: u64) {
+        let label = match field {
+            Field::Stack => unsafe { ptr::addr_of!(smp_trampoline_stack) },
+            Field::Entry => unsafe { ptr::addr_of!(smp_trampoline_entry) },
+            Field::CpuId => unsafe { ptr::addr_of!(smp_trampoline_cpu_id) },
+        };
+        unsafe { self.label_at(label).cast::<u64>().write_volatile(value) };
+    }
+
+    /// Returns where a trampoline label ended up in the copy.
+    fn label_at(&self, label_addr: *const u8) -> *mut u8 {
+        let offset = label(label_addr) as u64;
+        self.hhdm.to_virtual::<u8>(self.base + offset).as_ptr()
+    }
+
+    fn table(&self, frame: PhysAddr) -> *mut u64 {
+        self.hhdm.to_virtual::<u64>(frame).as_ptr()
+    }
+}
+
+/// Returns the offset of a trampoline label from its start.
+fn label(label_addr: *const u8) -> usize {
+    label_addr as usize - unsafe { ptr::addr_of!(smp_trampoline_start) } as usize
+}
+
+fn with_local_apic<F>(f: F) -> Result<(), SmpError>
+where
+    F: FnOnce(&mut LocalApicP),
+{
+    controller::with_controller(|controller| match controller {
+        Controller::Apic(apic) => {
+            f(apic.local_apic());
+            true
+        }
+        Controller::Pic(_) => false,
+    })
+    .filter(|&sent| sent)
+    .map(|_| ())
+    .ok_or(SmpError::NoApic)
+}
+
+/// Waits up to `timeout_us` microseconds for `counter` to reach `value`.
+fn wait_for(counter: &AtomicUsize, value: usize, timeout_us: u64) -> bool {
+    const STEP_US: u64 = 10;
+    for _ in 0..timeout_us.div_ceil(STEP_US) {
+        if counter.load(Ordering::Acquire) >= value {
+            return true;
+        }
+        time::delay_us(STEP_US);
+    }
+    counter.load(Ordering::Acquire) >= value
+}
+
+/// Where the trampoline leaves each AP, on its own stack but still on the trampoline's page
+/// tables.
+extern "C" fn ap_entry(cpu_id: usize) -> ! {
+    unsafe {
+        cr3::write(Frame::containing(PhysAddr(
+            KERNEL_L4.load(Ordering::Relaxed),
+        )));
+        STARTED.fetch_add(1, Ordering::Release);
+        debug_assert!(cr0::read().contains(Cr0::WRITE_PROTECT));
+
+        fpu::init();
+        enable_global_pages();
+        if let Err(err) = gdt::setup_cpu(cpu_id) {
+            log::error!("cpu {}: gdt setup failed: {:?}", cpu_id, err);
+            park();
+        }
+        percpu::init(cpu_id);
+        syscall::init();
+        interrupts::init_ap();
+        if let Err(err) = LocalApicP::detect() {
+            log::warn!("cpu {}: local apic unavailable: {:?}", cpu_id, err);
+        }
+    }
+    log::debug!("cpu {} online", cpu_id);
+    ONLINE.fetch_add(1, Ordering::Release);
+    park()
+}
+
+/// The scheduler only runs on the bootstrap processor, so APs sleep with interrupts off.
+fn park() -> ! {
+    loop {
+        interrupts::x86_64::disable_and_wait();
+    }
+}
+
+extern "C" {
+    static smp_trampoline_start: u8;
+    static smp_trampoline_long_mode: u8;
+    static smp_trampoline_gdt: u8;
+    static smp_trampoline_gdtr: u8;
+    static smp_trampoline_far_jump: u8;
+    static smp_trampoline_pml4: u8;
+    static smp_trampoline_efer: u8;
+    static smp_trampoline_stack: u8;
+    static smp_trampoline_entry: u8;
+    static smp_trampoline_cpu_id: u8;
+    static smp_trampoline_end: u8;
+}
+
+// Assembled into the kernel image but only ever run from its copy in low memory, so every address
+// in it is either relative or patched in by `Trampoline::install`.
+global_asm!(
+    ".section .rodata.smp_trampoline, \"a\"",
+    ".global smp_trampoline_start",
+    ".global smp_trampoline_long_mode",
+    ".global smp_trampoline_gdt",
+    ".global smp_trampoline_gdtr",
+    ".global smp_trampoline_far_jump",
+    ".global smp_trampoline_pml4",
+    ".global smp_trampoline_efer",
+    ".global smp_trampoline_stack",
+    ".global smp_trampoline_entry",
+    ".global smp_trampoline_cpu_id",
+    ".global smp_trampoline_end",
+    ".code16",
+    "smp_trampoline_start:",
+    "    cli",
+    "    cld",
+    "    mov %cs, %ax",
+    "    mov %ax, %ds",
+    "    lgdtl smp_trampoline_gdtr - smp_trampoline_start",
+    // PAE, then our page tables, then EFER.LME (and NXE if the bootstrap processor uses it).
+    "    mov %cr4, %eax",
+    "    or $(1 << 5), %eax",
+    "    mov %eax, %cr4",
+    "    mov smp_trampoline_pml4 - smp_trampoline_start, %eax",
+    "    mov %eax, %cr3",
+    "    mov $0xc0000080, %ecx",
+    "    mov smp_trampoline_efer - smp_trampoline_start, %eax",
+    "    xor %edx, %edx",
+    "    wrmsr",
+    // Protection and paging together, which with LME set puts us in long mode. Write protection
+    // too, as the bootloader left it on the bootstrap processor, so that read-only pages are
+    // read-only to the kernel here as well.
+    "    mov %cr0, %eax",
+    "    or $0x80010001, %eax",
+    "    mov %eax, %cr0",
+    "    ljmpl *(smp_trampoline_far_jump - smp_trampoline_start)",
+    ".code64",
+    "smp_trampoline_long_mode:",
+    "    mov $0x10, %ax",
+    "    mov %ax, %ds",
+    "    mov %ax, %es",
+    "    mov %ax, %ss",
+    "    mov smp_trampoline_stack(%rip), %rsp",
+    "    mov smp_trampoline_cpu_id(%rip), %rdi",
+    "    mov smp_trampoline_entry(%rip), %rax",
+    "    xor %ebp, %ebp",
+    // A null return address ends backtraces here.
+    "    push $0",
+    "    jmp *%rax",
+    ".balign 8",
+    "smp_trampoline_gdt:",
+    "    .quad 0",
+    "    .quad 0x00af9a000000ffff",
+    "    .quad 0x00cf92000000ffff",
+    "smp_trampoline_gdtr:",
+    "    .word smp_trampoline_gdtr - smp_trampoline_gdt - 1",
+    "    .long 0",
+    "smp_trampoline_far_jump:",
+    "    .long 0",
+    "    .word 0x08",
+    "smp_trampoline_pml4:",
+    "    .long 0",
+    "smp_trampoline_efer:",
+    "    .long 0",
+    ".balign 8",
+    "smp_trampoline_stack:",
+    "    .quad 0",
+    "smp_trampoline_entry:",
+    "    .quad 0",
+    "smp_trampoline_cpu_id:",
+    "    .quad 0",
+    "smp_trampoline_end:",
+    ".previous",
+    options(att_syntax)
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn bootstrap_processor_has_write_protect() {
+        // The trampoline turns it on for the APs, and `ap_entry` checks that it did.
+        assert!(cr0::read().contains(Cr0::WRITE_PROTECT));
+    }
+}
@@ -0,0 +1,107 @@
+use core::arch::asm;
+
+use super::{efer, gdt, wrmsr};
+
+const IA32_STAR: u32 = 0xc000_0081;
+const IA32_LSTAR: u32 = 0xc000_0082;
+const IA32_FMASK: u32 = 0xc000_0084;
+
+/// Returns [`ABI_VERSION`].
+pub const SYS_VERSION: u64 = 0;
+
+/// The version of the system call interface, for user code to check against.
+pub const ABI_VERSION: u64 = 1;
+
+/// Returned for system call numbers that don't exist.
+pub const ENOSYS: u64 = u64::MAX;
+
+/// The registers a system call saves on the kernel stack, lowest address first.
+///
+/// The number is passed in `rax` and the arguments in `rdi`, `rsi`, `rdx`, `r10`, `r8` and `r9`,
+/// as on Linux. `rcx` and `r11` hold the user `rip` and `rflags`, which `syscall` overwrites.
+#[repr(C)]
+#[derive(Debug)]
+pub struct SyscallFrame {
+    pub r9: u64,
+    pub r8: u64,
+    pub r10: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rax: u64,
+    pub rcx: u64,
+    pub r11: u64,
+    pub rsp: u64,
+}
+
+/// Enables the `syscall` instruction on the calling CPU, entering the kernel at
+/// [`syscall_entry`].
+///
+/// The per-CPU data must be set up first, since the entry finds its stack there.
+pub unsafe fn init() {
+    efer::write(efer::read() | efer::Efer::SYSCALL_ENABLE);
+
+    // `syscall` loads CS from bits 32..48 and SS from 8 past it. `sysret` loads CS from 16 past
+    // bits 48..64 and SS from 8 past them, with the RPL forced to 3.
+    let user_base = u64::from(gdt::USER_DATA.0 & !3) - 8;
+    let star = user_base << 48 | u64::from(gdt::KERNEL_CODE.0) << 32;
+    wrmsr(IA32_STAR, star);
+    wrmsr(IA32_LSTAR, syscall_entry as usize as u64);
+
+    // Interrupts, single stepping and the direction flag are off in the kernel.
+    wrmsr(IA32_FMASK, RFLAGS_TF | RFLAGS_IF | RFLAGS_DF);
+}
+
+const RFLAGS_TF: u64 = 1 << 8;
+const RFLAGS_IF: u64 = 1 << 9;
+const RFLAGS_DF: u64 = 1 << 10;
+
+/// Where `syscall` enters the kernel.
+///
+/// It switches to the kernel stack recorded in the per-CPU data, builds a [`SyscallFrame`] there
+/// and calls [`dispatch`] on it, with interrupts still disabled. Everything but `rax`, which
+/// carries the result, is restored before `sysret`.
+#[naked]
+unsafe extern "C" fn syscall_entry() {
+    asm!(
+        "swapgs",
+        "mov gs:[16], rsp",
+        "mov rsp, gs:[8]",
+        "push qword ptr gs:[16]",
+        "push r11",
+        "push rcx",
+        "push rax",
+        "push rdi",
+        "push rsi",
+        "push rdx",
+        "push r10",
+        "push r8",
+        "push r9",
+        "mov rdi, rsp",
+        "call {dispatch}",
+        "pop r9",
+        "pop r8",
+        "pop r10",
+        "pop rdx",
+        "pop rsi",
+        "pop rdi",
+        "add rsp, 8",
+        "pop rcx",
+        "pop r11",
+        "pop rsp",
+        "swapgs",
+        "sysretq",
+        dispatch = sym dispatch,
+        options(noreturn)
+    );
+}
+
+extern "C" fn dispatch(frame: &mut SyscallFrame) -> u64 {
+    match frame.rax {
+        SYS_VERSION => ABI_VERSION,
+        number => {
+            log::debug!("unknown syscall {} from {:#x}", number, frame.rcx);
+            ENOSYS
+        }
+    }
+}
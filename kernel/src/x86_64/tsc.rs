@@ -0,0 +1,80 @@
+use core::arch::x86_64::{__cpuid, _rdtsc, CpuidResult};
+
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Reads the TSC frequency in Hz from CPUID leaves 0x15 and 0x16, if the processor reports it.
+pub fn frequency_from_cpuid() -> Option<u64> {
+    let max_leaf = unsafe { __cpuid(0).eax };
+    if max_leaf < 0x15 {
+        return None;
+    }
+
+    let tsc_info = unsafe { __cpuid(0x15) };
+    let frequency_info = (max_leaf >= 0x16).then(|| unsafe { __cpuid(0x16) });
+    decode_frequency(tsc_info, frequency_info)
+}
+
+/// Computes the TSC frequency from the raw contents of leaf 0x15 (TSC/crystal clock ratio) and,
+/// when the crystal frequency isn't enumerated there, leaf 0x16 (processor base frequency).
+fn decode_frequency(tsc_info: CpuidResult, frequency_info: Option<CpuidResult>) -> Option<u64> {
+    let denominator = u64::from(tsc_info.eax);
+    let numerator = u64::from(tsc_info.ebx);
+    let crystal_hz = u64::from(tsc_info.ecx);
+
+    if denominator == 0 || numerator == 0 {
+        return None;
+    }
+    if crystal_hz != 0 {
+        return Some(crystal_hz * numerator / denominator);
+    }
+
+    // Without an enumerated crystal clock, the base frequency is the TSC frequency.
+    let base_mhz = u64::from(frequency_info?.eax & 0xffff);
+    (base_mhz != 0).then_some(base_mhz * 1_000_000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(eax: u32, ebx: u32, ecx: u32) -> CpuidResult {
+        CpuidResult {
+            eax,
+            ebx,
+            ecx,
+            edx: 0,
+        }
+    }
+
+    #[test_case]
+    fn crystal_frequency_scales_by_the_ratio() {
+        // A 24 MHz crystal with a TSC/crystal ratio of 250/2.
+        let tsc_info = leaf(2, 250, 24_000_000);
+        assert_eq!(decode_frequency(tsc_info, None), Some(3_000_000_000));
+        // Leaf 0x16 is only a fallback, so it must not override the crystal.
+        assert_eq!(
+            decode_frequency(tsc_info, Some(leaf(2_600, 0, 0))),
+            Some(3_000_000_000)
+        );
+    }
+
+    #[test_case]
+    fn missing_crystal_falls_back_to_the_base_frequency() {
+        let tsc_info = leaf(2, 250, 0);
+        assert_eq!(
+            decode_frequency(tsc_info, Some(leaf(2_600, 3_400, 100))),
+            Some(2_600_000_000)
+        );
+        assert_eq!(decode_frequency(tsc_info, Some(leaf(0, 0, 0))), None);
+        assert_eq!(decode_frequency(tsc_info, None), None);
+    }
+
+    #[test_case]
+    fn zero_ratio_is_unknown() {
+        let base = Some(leaf(2_600, 0, 0));
+        assert_eq!(decode_frequency(leaf(0, 250, 24_000_000), base), None);
+        assert_eq!(decode_frequency(leaf(2, 0, 24_000_000), base), None);
+    }
+}
@@ -1,8 +1,8 @@
 use std::{
-    env::args,
-    fs::{self, File},
+    env::{self, args},
+    fs::{self, File, OpenOptions},
     path::{Path, PathBuf},
-    process::Command,
+    process::{self, Command},
 };
 
 use color_eyre::{eyre::bail, Result};
@@ -29,6 +29,48 @@ fn main() -> Result<()> {
             let iso = build()?;
             run(&iso)?;
         }
+        "gdb" => {
+            let iso = build()?;
+            gdb(&iso)?;
+        }
+        "clean" => {
+            let flags: Vec<String> = args().skip(2).collect();
+            for flag in &flags {
+                if flag != "--all" && flag != "--keep-limine" {
+                    bail!("invalid flag '{}' for clean", flag);
+                }
+            }
+            clean(
+                flags.iter().any(|flag| flag == "--all"),
+                flags.iter().any(|flag| flag == "--keep-limine"),
+            )?;
+        }
+        "test" => {
+            let flags: Vec<String> = args().skip(2).collect();
+            for flag in &flags {
+                if flag != "--check-harness" {
+                    bail!("invalid flag '{}' for test", flag);
+                }
+            }
+
+            let iso = build_test(&[])?;
+            if !test(&iso)? {
+                process::exit(1);
+            }
+
+            // A harness that can't report failure would pass anything, so build the suite again
+            // with a test that always fails, and make sure it does.
+            if !flags.is_empty() {
+                let iso = build_test(&["failing_test"])?;
+                if test(&iso)? {
+                    bail!("the test kernel passed despite a failing test");
+                }
+                println!(
+                    "{:>12} a failing test fails the suite",
+                    "Finished".bold().green()
+                );
+            }
+        }
         _ => {
             bail!("invalid subcommand '{}'", command)
         }
@@ -40,18 +82,131 @@ fn main() -> Result<()> {
 fn run(iso: &Path) -> Result<()> {
     println!("{:>12} `kernel.iso`", "Running".bold().green());
 
-    _ = File::create("qemu.log");
+    qemu(iso)?.args(["-serial", "stdio"]).spawn()?.wait()?;
 
-    Command::new("qemu-system-x86_64")
-        .args(["-M", "q35", "-m", "2G", "-serial", "stdio", "-cdrom"])
-        .arg(iso)
-        .args(["-boot", "d", "-d", "int,guest_errors", "-D", "qemu.log"])
+    Ok(())
+}
+
+/// Boots the ISO halted, with QEMU's gdbstub listening on `GDB_PORT` (1234 by default).
+///
+/// If gdb is on the path it is started and connected, and the kernel's serial output goes to
+/// `serial.log` so it doesn't fight gdb for the terminal. Otherwise the command to connect with
+/// is printed.
+fn gdb(iso: &Path) -> Result<()> {
+    let port = env::var("GDB_PORT").unwrap_or_else(|_| "1234".to_string());
+    let kernel_elf = Path::new("build/iso_root/kernel.elf").canonicalize()?;
+    let target = format!("target remote :{}", port);
+    let symbols = format!("symbol-file {}", kernel_elf.display());
+
+    let mut qemu = qemu(iso)?;
+    qemu.args(["-gdb", &format!("tcp::{}", port), "-S"]);
+
+    if !is_on_path("gdb") {
+        println!(
+            "{:>12} `kernel.iso`, waiting for gdb on :{}",
+            "Running".bold().green(),
+            port
+        );
+        println!("connect with: gdb -ex '{}' -ex '{}'", symbols, target);
+        qemu.args(["-serial", "stdio"]).spawn()?.wait()?;
+        return Ok(());
+    }
+
+    println!(
+        "{:>12} `kernel.iso` under gdb, serial output in `serial.log`",
+        "Running".bold().green()
+    );
+    let mut qemu = qemu.args(["-serial", "file:serial.log"]).spawn()?;
+    Command::new("gdb")
+        .args(["-ex", &symbols, "-ex", &target])
         .spawn()?
         .wait()?;
+    qemu.kill()?;
 
     Ok(())
 }
 
+/// Boots a test kernel with the `isa-debug-exit` device, and returns whether all tests passed.
+///
+/// The kernel writes `TEST_SUCCESS` or `TEST_FAILURE` to the device once it is done, which
+/// QEMU turns into the exit status `(code << 1) | 1`.
+fn test(iso: &Path) -> Result<bool> {
+    const TEST_SUCCESS: i32 = 0x10;
+    const TEST_FAILURE: i32 = 0x11;
+
+    println!("{:>12} `kernel.iso`", "Testing".bold().green());
+
+    let status = qemu(iso)?
+        .args(["-device", "isa-debug-exit,iobase=0xf4,iosize=0x04"])
+        .args(["-serial", "stdio", "-display", "none", "-no-reboot"])
+        .spawn()?
+        .wait()?;
+
+    match status.code() {
+        Some(code) if code == (TEST_SUCCESS << 1) | 1 => {
+            println!("{:>12} all tests passed", "Finished".bold().green());
+            Ok(true)
+        }
+        Some(code) if code == (TEST_FAILURE << 1) | 1 => {
+            println!("{:>12} a test failed", "Failed".bold().red());
+            Ok(false)
+        }
+        _ => {
+            println!(
+                "{:>12} qemu exited without a result: {}",
+                "Failed".bold().red(),
+                status
+            );
+            Ok(false)
+        }
+    }
+}
+
+/// Returns a QEMU command that boots `iso`, with interrupts and guest errors logged to
+/// `qemu.log`. Where serial output goes is left to the caller.
+///
+/// The machine can be configured through environment variables:
+/// - `QEMU_MEMORY`: the memory size, as accepted by `-m`. Defaults to `2G`.
+/// - `QEMU_CPUS`: the number of processors. Defaults to 1.
+/// - `QEMU_KVM`: `1` to use KVM, `0` for TCG. Defaults to KVM if `/dev/kvm` can be opened.
+fn qemu(iso: &Path) -> Result<Command> {
+    _ = File::create("qemu.log");
+
+    let memory = env::var("QEMU_MEMORY").unwrap_or_else(|_| "2G".to_string());
+    let cpus = env::var("QEMU_CPUS").unwrap_or_else(|_| "1".to_string());
+    let kvm = match env::var("QEMU_KVM").as_deref() {
+        Ok("1") => true,
+        Ok("0") => false,
+        Ok(value) => bail!("QEMU_KVM must be 0 or 1, not '{}'", value),
+        Err(_) => kvm_available(),
+    };
+
+    let mut command = Command::new("qemu-system-x86_64");
+    command
+        .args(["-M", "q35", "-m", &memory, "-smp", &cpus, "-cdrom"])
+        .arg(iso)
+        .args(["-boot", "d", "-d", "int,guest_errors", "-D", "qemu.log"]);
+    if kvm {
+        // QEMU can't trace interrupts under KVM, so only guest errors end up in the log.
+        command.args(["-enable-kvm", "-cpu", "host"]);
+    }
+    Ok(command)
+}
+
+fn kvm_available() -> bool {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/kvm")
+        .is_ok()
+}
+
+fn is_on_path(program: &str) -> bool {
+    env::var_os("PATH")
+        .map(|path| env::split_paths(&path).any(|dir| dir.join(program).is_file()))
+        .unwrap_or(false)
+}
+
 fn build() -> Result<PathBuf> {
     let limine = fetch_limine()?;
 
@@ -63,6 +218,76 @@ fn build() -> Result<PathBuf> {
     Ok(iso_path)
 }
 
+/// Removes everything under `build/`, and with `all` the kernel's cargo target directory too.
+/// With `keep_limine` the limine clone survives, so the next build doesn't fetch it again.
+fn clean(all: bool, keep_limine: bool) -> Result<()> {
+    println!("{:>12} `build`", "Cleaning".bold().green());
+
+    let build = Path::new("build");
+    if build.exists() {
+        for entry in fs::read_dir(build)? {
+            let path = entry?.path();
+            if keep_limine && path.file_name() == Some("limine".as_ref()) {
+                continue;
+            }
+            if path.is_dir() {
+                fs::remove_dir_all(&path)?;
+            } else {
+                fs::remove_file(&path)?;
+            }
+        }
+    }
+
+    if all {
+        println!("{:>12} `kernel`", "Cleaning".bold().green());
+        let status = Command::new("cargo")
+            .arg("clean")
+            .current_dir("kernel")
+            .status()?;
+        if !status.success() {
+            bail!("cargo clean failed: {}", status);
+        }
+    }
+
+    Ok(())
+}
+
+/// Builds an ISO around the kernel's test binary, which runs every `#[test_case]` after boot.
+fn build_test(features: &[&str]) -> Result<PathBuf> {
+    let limine = fetch_limine()?;
+
+    let kernel_elf = compile_test_kernel(features)?;
+
+    println!("{:>12} `kernel.iso`", "Building".bold().green());
+    build_iso(&kernel_elf, &limine)
+}
+
+fn compile_test_kernel(features: &[&str]) -> Result<PathBuf> {
+    let mut cargo = Command::new("cargo");
+    cargo.args(["test", "--no-run"]);
+    if !features.is_empty() {
+        cargo.args(["--features", &features.join(",")]);
+    }
+    let output = cargo.current_dir("kernel").output()?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    eprint!("{}", stderr);
+    if !output.status.success() {
+        bail!("failed to build the test kernel");
+    }
+
+    // Cargo doesn't name test binaries predictably, but reports them as
+    // `Executable unittests src/main.rs (target/.../kernel-<hash>)`.
+    let Some(path) = stderr
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("Executable "))
+        .find_map(|line| line.split_once('(')?.1.strip_suffix(')'))
+    else {
+        bail!("cargo didn't report where the test kernel is");
+    };
+
+    Ok(Path::new("kernel").join(path).canonicalize()?)
+}
+
 fn compile_kernel() -> Result<PathBuf> {
     Command::new("cargo")
         .args(["build"])